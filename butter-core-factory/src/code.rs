@@ -0,0 +1,129 @@
+use crate::types::CodeVersion;
+use crate::*;
+use near_sdk::json_types::Base64VecU8;
+
+#[near_bindgen]
+impl Factory {
+    /// Append a chunk to the core code being uploaded for `version`, so that a wasm larger than
+    /// the arguments limit of the tools can be uploaded in several transactions.
+    pub fn upload_core_code(&mut self, version: String, chunk: Base64VecU8) {
+        self.assert_owner();
+        parse_version(&version);
+        assert!(
+            self.code_versions.get(&version).is_none(),
+            "version {} already exists",
+            version
+        );
+
+        let mut code = self.uploads.get(&version).unwrap_or_default();
+        code.extend_from_slice(&chunk.0);
+        self.uploads.insert(&version, &code);
+    }
+
+    /// Store the code uploaded by `upload_core_code` as a new core version.
+    pub fn add_core_version(&mut self, version: String) -> CodeVersion {
+        self.assert_owner();
+        let code = self
+            .uploads
+            .remove(&version)
+            .unwrap_or_else(|| env::panic_str("no code uploaded for the version"));
+        let code_hash: CryptoHash = env::sha256(&code).try_into().unwrap();
+        assert!(
+            self.codes.get(&code_hash).is_none(),
+            "the code is already stored"
+        );
+
+        let code_version = CodeVersion {
+            version: version.clone(),
+            code_hash: Base58CryptoHash::from(code_hash),
+            size: U64(code.len() as u64),
+            uploaded_at: U64(env::block_height()),
+        };
+        self.codes.insert(&code_hash, &code);
+        self.code_versions.insert(&version, &code_version);
+        let is_latest = match &self.latest_version {
+            Some(latest_version) => parse_version(&version) > parse_version(latest_version),
+            None => true,
+        };
+        if is_latest {
+            self.latest_version = Some(version);
+        }
+        code_version
+    }
+
+    /// Remove a core version and its code, deployed cores are not affected.
+    pub fn remove_core_version(&mut self, version: String) {
+        self.assert_owner();
+        let code_version = self
+            .code_versions
+            .remove(&version)
+            .unwrap_or_else(|| env::panic_str("unknown version"));
+        self.codes.remove(&code_version.code_hash.into());
+        if self.latest_version.as_ref() == Some(&version) {
+            self.latest_version = self
+                .code_versions
+                .keys()
+                .max_by_key(|version| parse_version(version));
+        }
+    }
+
+    /// Drop the code uploaded for `version` which is not added yet.
+    pub fn cancel_core_upload(&mut self, version: String) {
+        self.assert_owner();
+        self.uploads.remove(&version);
+    }
+
+    pub fn get_versions(&self, from_index: u64, limit: u64) -> Vec<CodeVersion> {
+        self.code_versions
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_core_version(&self, version: String) -> Option<CodeVersion> {
+        self.code_versions.get(&version)
+    }
+
+    /// The stored code of `code_hash`, cores pull it when upgraded by the factory.
+    pub fn get_code_by_hash(&self, code_hash: Base58CryptoHash) -> Base64VecU8 {
+        self.codes
+            .get(&code_hash.into())
+            .unwrap_or_else(|| env::panic_str("unknown code hash"))
+            .into()
+    }
+
+    pub fn get_latest_version(&self) -> Option<CodeVersion> {
+        self.latest_version
+            .as_ref()
+            .and_then(|version| self.code_versions.get(version))
+    }
+}
+
+impl Factory {
+    /// The stored version and its code, the latest version if `version` is none.
+    pub(crate) fn get_code(&self, version: Option<String>) -> (CodeVersion, Vec<u8>) {
+        let version = version
+            .or_else(|| self.latest_version.clone())
+            .unwrap_or_else(|| env::panic_str("no core version is stored"));
+        let code_version = self
+            .code_versions
+            .get(&version)
+            .unwrap_or_else(|| env::panic_str("unknown version"));
+        let code = self.codes.get(&code_version.code_hash.into()).unwrap();
+        (code_version, code)
+    }
+}
+
+/// Parse a semantic version like "1.2.3".
+fn parse_version(version: &str) -> (u64, u64, u64) {
+    let parts = version
+        .split('.')
+        .map(|part| part.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .unwrap_or_default();
+    match parts[..] {
+        [major, minor, patch] => (major, minor, patch),
+        _ => env::panic_str("version should be MAJOR.MINOR.PATCH"),
+    }
+}
@@ -1,19 +1,116 @@
+mod code;
+mod types;
+mod upgrade;
+
+use crate::types::{CodeVersion, CoreInfo, CoreInitArgs, CoreUpgrade};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{Base58CryptoHash, U128, U64};
 use near_sdk::serde_json::json;
-use near_sdk::{env, near_bindgen, AccountId, Gas, Promise};
-
-const BUTTER_CORE_BINARY: &'static [u8] =
-    include_bytes!("../../target/wasm32-unknown-unknown/release/butter_core.wasm");
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Balance, BorshStorageKey, CryptoHash, Gas,
+    PanicOnDefault, Promise, PromiseResult,
+};
+use std::convert::TryInto;
 
 /// This gas spent on the call & account creation, the rest goes to the `new` call.
 const CREATE_CALL_GAS: Gas = Gas(200_000_000_000_000);
+/// Gas to call callback_create_butter_core method.
+const CALLBACK_CREATE_BUTTER_CORE_GAS: Gas = Gas(10_000_000_000_000);
+/// Storage used by the core account besides the code and the staged upgrade: the account itself,
+/// its access keys and the initial state of the core contract.
+const CORE_EXTRA_STORAGE_BYTES: u64 = 10_000;
+
+#[ext_contract(ext_butter_core)]
+pub trait ExtButterCore {
+    fn upgrade_from_factory(&mut self, code_hash: Base58CryptoHash) -> Base58CryptoHash;
+    fn get_code_hash(&self) -> Option<Base58CryptoHash>;
+}
+
+#[derive(BorshStorageKey, BorshSerialize)]
+enum StorageKey {
+    Cores,
+    CoresByController,
+    ControllerCores { controller_hash: CryptoHash },
+    Deployers,
+    CodeVersions,
+    Codes,
+    Uploads,
+    CoreUpgrades,
+}
 
 #[near_bindgen]
-#[derive(BorshSerialize, BorshDeserialize, Default)]
-pub struct Factory {}
+#[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
+pub struct Factory {
+    owner: AccountId,
+    /// Accounts allowed to create cores besides the owner.
+    deployers: UnorderedSet<AccountId>,
+    /// Deployed cores by name.
+    cores: UnorderedMap<String, CoreInfo>,
+    /// Names of the deployed cores by controller.
+    cores_by_controller: LookupMap<AccountId, UnorderedSet<String>>,
+    /// Stored core versions by semantic version.
+    code_versions: UnorderedMap<String, CodeVersion>,
+    /// Stored core wasm by sha256 hash.
+    codes: LookupMap<CryptoHash, Vec<u8>>,
+    /// Core wasm being uploaded by version.
+    uploads: LookupMap<String, Vec<u8>>,
+    latest_version: Option<String>,
+    /// The last upgrade of each core by name.
+    core_upgrades: LookupMap<String, CoreUpgrade>,
+}
 
 #[near_bindgen]
 impl Factory {
+    #[init]
+    pub fn new(owner: AccountId) -> Self {
+        Self {
+            owner,
+            deployers: UnorderedSet::new(StorageKey::Deployers),
+            cores: UnorderedMap::new(StorageKey::Cores),
+            cores_by_controller: LookupMap::new(StorageKey::CoresByController),
+            code_versions: UnorderedMap::new(StorageKey::CodeVersions),
+            codes: LookupMap::new(StorageKey::Codes),
+            uploads: LookupMap::new(StorageKey::Uploads),
+            latest_version: None,
+            core_upgrades: LookupMap::new(StorageKey::CoreUpgrades),
+        }
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner.clone()
+    }
+
+    pub fn set_owner(&mut self, owner: AccountId) {
+        self.assert_owner();
+        self.owner = owner;
+    }
+
+    pub fn add_deployer(&mut self, deployer: AccountId) {
+        self.assert_owner();
+        self.deployers.insert(&deployer);
+    }
+
+    pub fn remove_deployer(&mut self, deployer: AccountId) {
+        self.assert_owner();
+        self.deployers.remove(&deployer);
+    }
+
+    pub fn get_deployers(&self) -> Vec<AccountId> {
+        self.deployers.to_vec()
+    }
+
+    /// Minimum deposit to create a core of `version`, which covers the storage of the core account,
+    /// including an upgrade of the same size staged in the core.
+    /// Use the latest version if `version` is none.
+    pub fn get_min_deposit(&self, version: Option<String>) -> U128 {
+        let (code_version, _) = self.get_code(version);
+        U128(Self::min_deposit(code_version.size.0))
+    }
+
+    /// Create a core account, deploy and initialize the core contract of `version`, or of the latest
+    /// version if it is none. The attached deposit goes to the core account.
+    /// If the creation fails, the deposit is refunded to the caller.
     #[payable]
     pub fn create_butter_core(
         &mut self,
@@ -22,24 +119,174 @@ impl Factory {
         ref_exchange: AccountId,
         wrapped_token: AccountId,
         owner: AccountId,
+        version: Option<String>,
     ) -> Promise {
-        let account_id = format!("{}.{}", name, env::current_account_id());
-        Promise::new(account_id.parse().unwrap())
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner || self.deployers.contains(&caller),
+            "unexpected caller {}",
+            caller
+        );
+        let (code_version, code) = self.get_code(version);
+        let deposit = env::attached_deposit();
+        let min_deposit = Self::min_deposit(code_version.size.0);
+        assert!(
+            deposit >= min_deposit,
+            "not enough deposit, at least {} is required",
+            min_deposit
+        );
+        assert!(
+            self.cores.get(&name).is_none(),
+            "butter core {} already exists",
+            name
+        );
+        let account_id: AccountId = format!("{}.{}", name, env::current_account_id())
+            .parse()
+            .unwrap();
+        let init_args = CoreInitArgs {
+            controller,
+            ref_exchange,
+            wrapped_token,
+            owner,
+            code_hash: code_version.code_hash,
+        };
+
+        Promise::new(account_id)
             .create_account()
-            .deploy_contract(BUTTER_CORE_BINARY.to_vec())
-            .transfer(env::attached_deposit())
+            .deploy_contract(code)
+            .transfer(deposit)
             .function_call(
                 "new".to_string(),
-                json!({
-                    "controller": controller,
-                    "ref_exchange": ref_exchange,
-                    "wrapped_token": wrapped_token,
-                    "owner": owner})
-                .to_string()
-                .as_bytes()
-                .to_vec(),
+                json!(init_args).to_string().as_bytes().to_vec(),
                 0,
-                env::prepaid_gas() - CREATE_CALL_GAS,
+                env::prepaid_gas() - CREATE_CALL_GAS - CALLBACK_CREATE_BUTTER_CORE_GAS,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_CREATE_BUTTER_CORE_GAS)
+                    .callback_create_butter_core(
+                        name,
+                        init_args,
+                        code_version,
+                        caller,
+                        U128(deposit),
+                    ),
             )
     }
+
+    /// Record the core if it is created and initialized successfully, otherwise refund the
+    /// deposit to the caller of create_butter_core, a failed creation leaves no record.
+    #[private]
+    pub fn callback_create_butter_core(
+        &mut self,
+        name: String,
+        init_args: CoreInitArgs,
+        code_version: CodeVersion,
+        caller: AccountId,
+        deposit: U128,
+    ) -> bool {
+        assert_eq!(
+            1,
+            env::promise_results_count(),
+            "promise has too many results"
+        );
+
+        match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => {
+                let core_info = CoreInfo {
+                    account_id: format!("{}.{}", name, env::current_account_id())
+                        .parse()
+                        .unwrap(),
+                    name,
+                    controller: init_args.controller,
+                    ref_exchange: init_args.ref_exchange,
+                    wrapped_token: init_args.wrapped_token,
+                    owner: init_args.owner,
+                    version: code_version.version,
+                    code_hash: code_version.code_hash,
+                    created_at: U64(env::block_height()),
+                };
+                let mut names = self
+                    .cores_by_controller
+                    .get(&core_info.controller)
+                    .unwrap_or_else(|| {
+                        UnorderedSet::new(StorageKey::ControllerCores {
+                            controller_hash: env::sha256_array(core_info.controller.as_bytes()),
+                        })
+                    });
+                names.insert(&core_info.name);
+                self.cores_by_controller
+                    .insert(&core_info.controller, &names);
+                self.cores.insert(&core_info.name, &core_info);
+                true
+            }
+            PromiseResult::Failed => {
+                env::log_str(&format!(
+                    "create butter core {} failed, refund {} to {}",
+                    name, deposit.0, caller
+                ));
+                Promise::new(caller).transfer(deposit.0);
+                false
+            }
+        }
+    }
+
+    pub fn get_core(&self, name: String) -> Option<CoreInfo> {
+        self.cores.get(&name)
+    }
+
+    pub fn get_cores(&self, from_index: u64, limit: u64) -> Vec<CoreInfo> {
+        self.cores
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_cores_by_controller(
+        &self,
+        controller: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<CoreInfo> {
+        match self.cores_by_controller.get(&controller) {
+            Some(names) => names
+                .iter()
+                .skip(from_index as usize)
+                .take(limit as usize)
+                .filter_map(|name| self.cores.get(&name))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_cores_count(&self) -> u64 {
+        self.cores.len()
+    }
+
+    /// Initialize the factory over the state written by the previous version, which had no fields.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(owner: AccountId) -> Self {
+        assert!(
+            env::storage_read(b"STATE").unwrap_or_default().is_empty(),
+            "factory state is already migrated"
+        );
+        Self::new(owner)
+    }
+}
+
+impl Factory {
+    pub(crate) fn assert_owner(&self) {
+        assert_eq!(
+            self.owner,
+            env::predecessor_account_id(),
+            "unexpected caller"
+        );
+    }
+
+    fn min_deposit(code_size: u64) -> Balance {
+        (2 * code_size + CORE_EXTRA_STORAGE_BYTES) as Balance * env::storage_byte_cost()
+    }
 }
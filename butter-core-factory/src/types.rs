@@ -0,0 +1,70 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{Base58CryptoHash, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::AccountId;
+
+/// A butter core contract deployed by the factory.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CoreInfo {
+    pub name: String,
+    pub account_id: AccountId,
+    pub controller: AccountId,
+    pub ref_exchange: AccountId,
+    pub wrapped_token: AccountId,
+    pub owner: AccountId,
+    /// Version of the deployed code.
+    pub version: String,
+    /// sha256 hash of the deployed wasm.
+    pub code_hash: Base58CryptoHash,
+    /// Block height when the creation of the core succeeded.
+    pub created_at: U64,
+}
+
+/// Arguments of the `new` method of the core.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CoreInitArgs {
+    pub controller: AccountId,
+    pub ref_exchange: AccountId,
+    pub wrapped_token: AccountId,
+    pub owner: AccountId,
+    pub code_hash: Base58CryptoHash,
+}
+
+/// A version of the core code stored in the factory.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CodeVersion {
+    /// Semantic version like "1.2.3".
+    pub version: String,
+    /// sha256 hash of the wasm.
+    pub code_hash: Base58CryptoHash,
+    /// Size of the wasm in bytes.
+    pub size: U64,
+    /// Block height when the version is added.
+    pub uploaded_at: U64,
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradeStatus {
+    Pending,
+    /// The code is staged in the core, which applies it after its upgrade delay.
+    Staged,
+    Failed,
+}
+
+/// An upgrade of a core started by the factory.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CoreUpgrade {
+    pub version: String,
+    pub code_hash: Base58CryptoHash,
+    pub status: UpgradeStatus,
+    /// Block height when the status is updated.
+    pub updated_at: U64,
+}
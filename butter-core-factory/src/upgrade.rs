@@ -0,0 +1,165 @@
+use crate::types::{CoreUpgrade, UpgradeStatus};
+use crate::*;
+
+/// Gas to call upgrade_from_factory on the core, which pulls the code and stages it.
+const CORE_UPGRADE_GAS: Gas = Gas(70_000_000_000_000);
+/// Gas to call callback_upgrade_core method.
+const CALLBACK_UPGRADE_CORE_GAS: Gas = Gas(10_000_000_000_000);
+/// Gas to call get_code_hash on the core.
+const CORE_GET_CODE_HASH_GAS: Gas = Gas(5_000_000_000_000);
+/// Gas to call callback_sync_core_version method.
+const CALLBACK_SYNC_CORE_VERSION_GAS: Gas = Gas(10_000_000_000_000);
+
+#[near_bindgen]
+impl Factory {
+    /// Stage the stored `version` in the cores of `names`, the owner of each core applies it
+    /// after the upgrade delay of the core. Cores can be upgraded in stages by calling it with a
+    /// few cores each time, see `get_cores_to_upgrade`.
+    pub fn upgrade_cores(&mut self, version: String, names: Vec<String>) -> Promise {
+        self.assert_owner();
+        assert!(!names.is_empty(), "no core to upgrade");
+        let code_version = self
+            .code_versions
+            .get(&version)
+            .unwrap_or_else(|| env::panic_str("unknown version"));
+
+        let mut promise: Option<Promise> = None;
+        for name in names {
+            let core_info = self
+                .cores
+                .get(&name)
+                .unwrap_or_else(|| env::panic_str(&format!("unknown core {}", name)));
+            if let Some(upgrade) = self.core_upgrades.get(&name) {
+                assert_ne!(
+                    upgrade.status,
+                    UpgradeStatus::Pending,
+                    "core {} is being upgraded",
+                    name
+                );
+            }
+            self.core_upgrades.insert(
+                &name,
+                &CoreUpgrade {
+                    version: version.clone(),
+                    code_hash: code_version.code_hash,
+                    status: UpgradeStatus::Pending,
+                    updated_at: U64(env::block_height()),
+                },
+            );
+
+            let upgrade_promise = ext_butter_core::ext(core_info.account_id)
+                .with_static_gas(CORE_UPGRADE_GAS)
+                .upgrade_from_factory(code_version.code_hash)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALLBACK_UPGRADE_CORE_GAS)
+                        .callback_upgrade_core(name),
+                );
+            promise = Some(match promise {
+                Some(p) => p.and(upgrade_promise),
+                None => upgrade_promise,
+            });
+        }
+        promise.unwrap()
+    }
+
+    /// Record whether the code is staged in core `name`.
+    #[private]
+    pub fn callback_upgrade_core(&mut self, name: String) -> bool {
+        assert_eq!(
+            1,
+            env::promise_results_count(),
+            "promise has too many results"
+        );
+
+        let mut upgrade = self.core_upgrades.get(&name).unwrap();
+        // the core returns the hash of the staged code
+        let succeeded = match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) => {
+                near_sdk::serde_json::from_slice::<Base58CryptoHash>(&x)
+                    .is_ok_and(|code_hash| code_hash == upgrade.code_hash)
+            }
+            PromiseResult::Failed => false,
+        };
+        upgrade.updated_at = U64(env::block_height());
+        if succeeded {
+            upgrade.status = UpgradeStatus::Staged;
+        } else {
+            env::log_str(&format!(
+                "upgrade core {} to {} failed",
+                name, upgrade.version
+            ));
+            upgrade.status = UpgradeStatus::Failed;
+        }
+        self.core_upgrades.insert(&name, &upgrade);
+        succeeded
+    }
+
+    /// Update the version of core `name` to its deployed code, once the core has applied the
+    /// staged upgrade. Anyone can call it.
+    pub fn sync_core_version(&mut self, name: String) -> Promise {
+        let core_info = self
+            .cores
+            .get(&name)
+            .unwrap_or_else(|| env::panic_str(&format!("unknown core {}", name)));
+        ext_butter_core::ext(core_info.account_id)
+            .with_static_gas(CORE_GET_CODE_HASH_GAS)
+            .get_code_hash()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_SYNC_CORE_VERSION_GAS)
+                    .callback_sync_core_version(name),
+            )
+    }
+
+    /// Record the version of the code deployed in core `name`. Returns the version, none if the
+    /// code is not a stored version.
+    #[private]
+    pub fn callback_sync_core_version(&mut self, name: String) -> Option<String> {
+        assert_eq!(
+            1,
+            env::promise_results_count(),
+            "promise has too many results"
+        );
+
+        let code_hash = match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) => {
+                near_sdk::serde_json::from_slice::<Option<Base58CryptoHash>>(&x)
+                    .ok()
+                    .flatten()
+            }
+            PromiseResult::Failed => None,
+        }?;
+        let code_version = self
+            .code_versions
+            .values()
+            .find(|code_version| code_version.code_hash == code_hash)?;
+        let mut core_info = self.cores.get(&name).unwrap();
+        core_info.version = code_version.version.clone();
+        core_info.code_hash = code_hash;
+        self.cores.insert(&name, &core_info);
+        Some(code_version.version)
+    }
+
+    /// The last upgrade of core `name`.
+    pub fn get_core_upgrade(&self, name: String) -> Option<CoreUpgrade> {
+        self.core_upgrades.get(&name)
+    }
+
+    /// Cores which are not on `version`, in the order of `get_cores`.
+    pub fn get_cores_to_upgrade(
+        &self,
+        version: String,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<CoreInfo> {
+        self.cores
+            .values()
+            .filter(|core_info| core_info.version != version)
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+}
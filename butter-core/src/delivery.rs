@@ -0,0 +1,922 @@
+use crate::types::{Delivery, LostFoundMessage, PendingDelivery, RefundPolicy};
+use crate::*;
+use near_sdk::json_types::U64;
+
+/// Gas to call ft_transfer_call method.
+pub(crate) const FT_TRANSFER_CALL_LOST_FOUND_GAS: Gas = Gas(35_000_000_000_000);
+/// Gas to call callback_redirect_delivery method.
+const CALLBACK_REDIRECT_DELIVERY_GAS: Gas = Gas(10_000_000_000_000);
+/// Gas to call callback_wrap_delivery method, which redirects the wrapped NEAR of a delivery.
+const CALLBACK_WRAP_DELIVERY_GAS: Gas =
+    Gas(5_000_000_000_000 + FT_TRANSFER_CALL_LOST_FOUND_GAS.0 + CALLBACK_REDIRECT_DELIVERY_GAS.0);
+/// Gas to give up a delivery and redirect it to the fallback account or MOS lost and found.
+pub(crate) const REDIRECT_DELIVERY_GAS: Gas =
+    Gas(NEAR_DEPOSIT_GAS.0 + CALLBACK_WRAP_DELIVERY_GAS.0);
+/// Gas to call callback_sweep method.
+const CALLBACK_SWEEP_GAS: Gas = Gas(10_000_000_000_000 + FT_TRANSFER_GAS.0);
+/// Gas to call callback_retry_delivery method.
+const CALLBACK_RETRY_DELIVERY_GAS: Gas = Gas(10_000_000_000_000 + REDIRECT_DELIVERY_GAS.0);
+
+pub const DEFAULT_MAX_DELIVERY_ATTEMPTS: u32 = 3;
+/// 7 days in nanoseconds.
+pub const DEFAULT_DELIVERY_TIMEOUT: u64 = 7 * 24 * 3600 * 1_000_000_000;
+
+#[near_bindgen]
+impl ButterCore {
+    pub fn get_max_delivery_attempts(&self) -> u32 {
+        self.max_delivery_attempts
+    }
+
+    pub fn set_max_delivery_attempts(&mut self, max_delivery_attempts: u32) {
+        self.assert_owner();
+        ensure!(
+            max_delivery_attempts > 0,
+            ButterCoreError::InvalidArgument(
+                "max delivery attempts should be positive".to_string()
+            )
+        );
+        self.max_delivery_attempts = max_delivery_attempts;
+    }
+
+    pub fn get_delivery_timeout(&self) -> U64 {
+        U64(self.delivery_timeout)
+    }
+
+    pub fn set_delivery_timeout(&mut self, delivery_timeout: U64) {
+        self.assert_owner();
+        self.delivery_timeout = delivery_timeout.0;
+    }
+
+    pub fn get_pending_delivery(&self, order_id: U64) -> Option<PendingDelivery> {
+        self.pending_deliveries.get(&order_id.0)
+    }
+
+    pub fn get_pending_deliveries(
+        &self,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(U64, PendingDelivery)> {
+        self.pending_deliveries
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(order_id, pending)| (U64(order_id), pending))
+            .collect()
+    }
+
+    /// Amount of `token` held by the core for pending deliveries and relayer fees.
+    pub fn get_pending_amount(&self, token: AccountId) -> U128 {
+        U128(self.pending_amounts.get(&token).unwrap_or(0))
+    }
+
+    /// Retry a failed delivery, anyone can call it with enough gas. A swap out is sent to MOS
+    /// again with its msg.
+    /// If the delivery has failed `max_delivery_attempts` times or timed out, the tokens are
+    /// redirected to the fallback account or MOS lost and found instead.
+    pub fn retry_delivery(&mut self, order_id: U64) -> Promise {
+        let pending = self
+            .pending_deliveries
+            .remove(&order_id.0)
+            .unwrap_or_else(|| ButterCoreError::NoPendingDelivery { order_id }.panic());
+
+        if pending.attempts >= self.max_delivery_attempts
+            || env::block_timestamp() - pending.created_at.0 > self.delivery_timeout
+        {
+            log!(
+                "delivery of order {} failed {} times, redirect it",
+                order_id.0,
+                pending.attempts
+            );
+            return self.redirect_delivery(order_id.0, pending);
+        }
+
+        let delivery = pending.delivery.clone();
+        let transfer = if delivery.is_native {
+            Promise::new(delivery.account_id).transfer(delivery.amount.0)
+        } else if let Some(msg) = delivery.msg {
+            ext_ft_core::ext(delivery.token)
+                .with_static_gas(FT_TRANSFER_CALL_MOS_GAS)
+                .with_attached_deposit(1)
+                .ft_transfer_call(delivery.account_id, delivery.amount, None, msg)
+        } else {
+            ext_ft_core::ext(delivery.token)
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(1)
+                .ft_transfer(delivery.account_id, delivery.amount, None)
+        };
+        transfer.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(CALLBACK_RETRY_DELIVERY_GAS)
+                .callback_retry_delivery(order_id.0, pending),
+        )
+    }
+
+    /// Check the retried delivery, the amount which is not delivered is queued again, or
+    /// redirected after `max_delivery_attempts` attempts. A retried swap out may be refunded in
+    /// part by MOS again. Returns whether the whole amount is delivered.
+    #[private]
+    pub fn callback_retry_delivery(&mut self, order_id: u64, pending: PendingDelivery) -> bool {
+        let delivery = pending.delivery.clone();
+        let unused_amount = match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) if delivery.msg.is_some() => {
+                delivery.amount.0 - transfer_call_used_amount(&delivery)
+            }
+            PromiseResult::Successful(_) => 0,
+            PromiseResult::Failed => delivery.amount.0,
+        };
+        self.release_pending_amount(&delivery);
+        if unused_amount == 0 {
+            return true;
+        }
+
+        let pending = PendingDelivery {
+            delivery: Delivery {
+                amount: U128(unused_amount),
+                ..delivery
+            },
+            attempts: pending.attempts + 1,
+            ..pending
+        };
+        if pending.attempts >= self.max_delivery_attempts {
+            log!(
+                "delivery of order {} failed {} times, redirect it",
+                order_id,
+                pending.attempts
+            );
+            self.reserve_pending_amount(&pending.delivery);
+            self.redirect_delivery(order_id, pending);
+        } else {
+            self.queue_delivery(order_id, pending);
+        }
+        false
+    }
+
+    #[private]
+    pub fn callback_sweep(
+        &mut self,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        let balance = match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) => serde_json::from_slice::<U128>(&x).ok(),
+            PromiseResult::Failed => None,
+        }
+        .unwrap_or_else(|| {
+            ButterCoreError::TokenCallFailed("get token balance of core".to_string()).panic()
+        })
+        .0;
+        let available = balance.saturating_sub(self.pending_amounts.get(&token).unwrap_or(0));
+        ensure!(
+            amount.0 <= available,
+            ButterCoreError::NotEnoughBalance {
+                available: U128(available)
+            }
+        );
+        ext_ft_core::ext(token)
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(1)
+            .ft_transfer(receiver_id, amount, None)
+    }
+
+    /// Check the delivery transfer, if it failed, queue the delivery to be retried.
+    /// Returns `result`.
+    #[private]
+    pub fn callback_check_transfer(
+        &mut self,
+        order_id: u64,
+        delivery: Delivery,
+        result: (U128, U128),
+    ) -> (U128, U128) {
+        match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_x) => {}
+            PromiseResult::Failed => {
+                log!(
+                    "transfer {} {} to user {} failed, queue it for retry as order {}",
+                    delivery.amount.0,
+                    if delivery.is_native {
+                        "NEAR".to_string()
+                    } else {
+                        delivery.token.to_string()
+                    },
+                    delivery.account_id,
+                    order_id
+                );
+                CoreEvent::OrderFailed {
+                    order_id: Some(U64(order_id)),
+                    error: &ButterCoreError::DeliveryFailed {
+                        order_id: U64(order_id),
+                    },
+                }
+                .emit();
+                self.queue_delivery(
+                    order_id,
+                    PendingDelivery {
+                        delivery,
+                        attempts: 1,
+                        created_at: U64(env::block_timestamp()),
+                    },
+                );
+            }
+        }
+        result
+    }
+
+    /// Check the swap out to MOS by ft_transfer_call, the amount not used by MOS is handled by
+    /// `on_refund`. Returns `result` with the amount out used by MOS.
+    #[private]
+    pub fn callback_transfer_call(
+        &mut self,
+        order_id: u64,
+        delivery: Delivery,
+        on_refund: RefundPolicy,
+        result: (U128, U128),
+    ) -> PromiseOrValue<(U128, U128)> {
+        let used_amount = transfer_call_used_amount(&delivery);
+        let refunded = delivery.amount.0 - used_amount;
+        if refunded == 0 {
+            return PromiseOrValue::Value(result);
+        }
+
+        CoreEvent::OrderFailed {
+            order_id: Some(U64(order_id)),
+            error: &ButterCoreError::TransferCallRefunded {
+                refunded: U128(refunded),
+            },
+        }
+        .emit();
+        let delivery = Delivery {
+            amount: U128(refunded),
+            ..delivery
+        };
+        let delivery = match on_refund {
+            RefundPolicy::Retry => {
+                log!(
+                    "swap out of order {} is refunded, queue {} for retry",
+                    order_id,
+                    refunded
+                );
+                self.queue_delivery(
+                    order_id,
+                    PendingDelivery {
+                        delivery,
+                        attempts: 1,
+                        created_at: U64(env::block_timestamp()),
+                    },
+                );
+                return PromiseOrValue::Value((result.0, U128(used_amount)));
+            }
+            RefundPolicy::ReturnToSender => Delivery {
+                fallback_account: Some(self.controller.clone()),
+                ..delivery
+            },
+            RefundPolicy::LostAndFound => delivery,
+        };
+        let pending = PendingDelivery {
+            delivery,
+            attempts: self.max_delivery_attempts,
+            created_at: U64(env::block_timestamp()),
+        };
+        self.reserve_pending_amount(&pending.delivery);
+        self.redirect_delivery(order_id, pending)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_RETURN_VALUE_GAS)
+                    .callback_return_value(result.0, U128(used_amount)),
+            )
+            .into()
+    }
+
+    /// Check the target call by ft_transfer_call, the amount not used by the target account is
+    /// delivered to it by ft_transfer, see `callback_target_refund`. Returns `result` with the
+    /// amount out used by the target call.
+    #[private]
+    pub fn callback_target_call(
+        &mut self,
+        order_id: u64,
+        delivery: Delivery,
+        result: (U128, U128),
+    ) -> PromiseOrValue<(U128, U128)> {
+        let used_amount = transfer_call_used_amount(&delivery);
+        let refunded = delivery.amount.0 - used_amount;
+        if refunded == 0 {
+            return PromiseOrValue::Value(result);
+        }
+
+        CoreEvent::OrderFailed {
+            order_id: Some(U64(order_id)),
+            error: &ButterCoreError::TransferCallRefunded {
+                refunded: U128(refunded),
+            },
+        }
+        .emit();
+        let delivery = Delivery {
+            amount: U128(refunded),
+            ..delivery
+        };
+        self.reserve_pending_amount(&delivery);
+        ext_ft_core::ext(delivery.token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(1)
+            .ft_transfer(delivery.account_id.clone(), delivery.amount, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_TARGET_REFUND_GAS)
+                    .callback_target_refund(order_id, delivery, (result.0, U128(used_amount))),
+            )
+            .into()
+    }
+
+    /// Check the transfer of the amount refunded by the target call to the target account, it is
+    /// redirected to the fallback account or MOS lost and found if the transfer failed.
+    /// Returns `result`.
+    #[private]
+    pub fn callback_target_refund(
+        &mut self,
+        order_id: u64,
+        delivery: Delivery,
+        result: (U128, U128),
+    ) -> (U128, U128) {
+        match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => self.release_pending_amount(&delivery),
+            PromiseResult::Failed => {
+                log!(
+                    "refund of order {} to the target account failed, redirect it",
+                    order_id
+                );
+                self.redirect_delivery(
+                    order_id,
+                    PendingDelivery {
+                        delivery,
+                        attempts: self.max_delivery_attempts,
+                        created_at: U64(env::block_timestamp()),
+                    },
+                );
+            }
+        }
+        result
+    }
+
+    /// Check the redirect of a pending delivery, the amount which is not received by the
+    /// fallback account or MOS lost and found is queued again. Returns whether the whole amount
+    /// is redirected.
+    #[private]
+    pub fn callback_redirect_delivery(&mut self, order_id: u64, pending: PendingDelivery) -> bool {
+        let delivery = pending.delivery.clone();
+        let lost_found = delivery.fallback_account.is_none();
+        // MOS lost and found may refund a part of the transfer, the whole amount is taken as
+        // used if the result is unexpected
+        let unused_amount = match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) if lost_found => serde_json::from_slice::<U128>(&x)
+                .map(|used_amount| {
+                    delivery.amount.0 - std::cmp::min(used_amount.0, delivery.amount.0)
+                })
+                .unwrap_or(0),
+            PromiseResult::Successful(_) => 0,
+            PromiseResult::Failed => delivery.amount.0,
+        };
+        self.release_pending_amount(&delivery);
+        if unused_amount == 0 {
+            return true;
+        }
+
+        log!(
+            "redirect of order {} failed, queue {} for retry",
+            order_id,
+            unused_amount
+        );
+        self.queue_delivery(
+            order_id,
+            PendingDelivery {
+                delivery: Delivery {
+                    amount: U128(unused_amount),
+                    ..delivery
+                },
+                ..pending
+            },
+        );
+        false
+    }
+
+    /// Redirect the native NEAR of the pending delivery to MOS lost and found as the wrapped NEAR
+    /// deposited for it. If the deposit failed, the NEAR is back in the core and the delivery is
+    /// queued again, the wrapped NEAR held by the core for other deliveries is not sent.
+    /// Returns whether the wrapped NEAR is redirected.
+    #[private]
+    pub fn callback_wrap_delivery(
+        &mut self,
+        order_id: u64,
+        pending: PendingDelivery,
+    ) -> PromiseOrValue<bool> {
+        match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => {
+                self.release_pending_amount(&pending.delivery);
+                let pending = PendingDelivery {
+                    delivery: Delivery {
+                        token: self.wrapped_token.clone(),
+                        is_native: false,
+                        ..pending.delivery
+                    },
+                    ..pending
+                };
+                self.reserve_pending_amount(&pending.delivery);
+                self.give_up_delivery(pending.delivery.clone(), true)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(CALLBACK_REDIRECT_DELIVERY_GAS)
+                            .callback_redirect_delivery(order_id, pending),
+                    )
+                    .into()
+            }
+            PromiseResult::Failed => {
+                log!("wrap of order {} failed, queue it for retry", order_id);
+                self.pending_deliveries.insert(&order_id, &pending);
+                PromiseOrValue::Value(false)
+            }
+        }
+    }
+}
+
+/// Amount of the delivery used by the receiver of its ft_transfer_call, read from the promise
+/// result. The whole amount is taken as used if the result is unexpected, the tokens are kept in
+/// the core rather than redirecting the tokens of others.
+fn transfer_call_used_amount(delivery: &Delivery) -> Balance {
+    match promise_result() {
+        PromiseResult::NotReady => env::abort(),
+        PromiseResult::Successful(x) => serde_json::from_slice::<U128>(&x)
+            .map(|used_amount| std::cmp::min(used_amount.0, delivery.amount.0))
+            .unwrap_or(delivery.amount.0),
+        PromiseResult::Failed => 0,
+    }
+}
+
+impl ButterCore {
+    /// Transfer `amount` of `token`, or NEAR if `token` is none, held by the core to
+    /// `receiver_id`. The amounts of the pending deliveries and relayer fees can not be swept.
+    pub(crate) fn sweep(
+        &self,
+        token: Option<AccountId>,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        match token {
+            Some(token) => ext_ft_core::ext(token.clone())
+                .with_static_gas(FT_BALANCE_OF_GAS)
+                .ft_balance_of(env::current_account_id())
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALLBACK_SWEEP_GAS)
+                        .callback_sweep(token, receiver_id, amount),
+                ),
+            None => {
+                let storage_cost = env::storage_byte_cost() * env::storage_usage() as Balance;
+                let available = env::account_balance()
+                    .saturating_sub(storage_cost)
+                    .saturating_sub(self.pending_native_amount);
+                ensure!(
+                    amount.0 <= available,
+                    ButterCoreError::NotEnoughBalance {
+                        available: U128(available)
+                    }
+                );
+                Promise::new(receiver_id).transfer(amount.0)
+            }
+        }
+    }
+
+    fn reserve_pending_amount(&mut self, delivery: &Delivery) {
+        if delivery.is_native {
+            self.pending_native_amount += delivery.amount.0;
+            return;
+        }
+        let pending_amount = self.pending_amounts.get(&delivery.token).unwrap_or(0);
+        self.pending_amounts
+            .insert(&delivery.token, &(pending_amount + delivery.amount.0));
+    }
+
+    fn release_pending_amount(&mut self, delivery: &Delivery) {
+        if delivery.is_native {
+            self.pending_native_amount =
+                self.pending_native_amount.saturating_sub(delivery.amount.0);
+            return;
+        }
+        let pending_amount = self.pending_amounts.get(&delivery.token).unwrap_or(0);
+        self.pending_amounts.insert(
+            &delivery.token,
+            &pending_amount.saturating_sub(delivery.amount.0),
+        );
+    }
+
+    /// Reserve the amount of the delivery and queue it to be retried.
+    fn queue_delivery(&mut self, order_id: u64, pending: PendingDelivery) {
+        self.reserve_pending_amount(&pending.delivery);
+        self.pending_deliveries.insert(&order_id, &pending);
+    }
+
+    /// Give up the pending delivery, see `give_up_delivery`. Its amount is kept reserved until
+    /// `callback_redirect_delivery` checks the transfer. Native NEAR is wrapped before it is
+    /// transferred to MOS lost and found, see `callback_wrap_delivery`.
+    fn redirect_delivery(&self, order_id: u64, pending: PendingDelivery) -> Promise {
+        if pending.delivery.is_native && pending.delivery.fallback_account.is_none() {
+            return ext_wnear_token::ext(self.wrapped_token.clone())
+                .with_static_gas(NEAR_DEPOSIT_GAS)
+                .with_attached_deposit(pending.delivery.amount.0)
+                .near_deposit()
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALLBACK_WRAP_DELIVERY_GAS)
+                        .callback_wrap_delivery(order_id, pending),
+                );
+        }
+        self.give_up_delivery(pending.delivery.clone(), false).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(CALLBACK_REDIRECT_DELIVERY_GAS)
+                .callback_redirect_delivery(order_id, pending),
+        )
+    }
+
+    /// Transfer the tokens of the delivery to the fallback account if any, otherwise to MOS lost
+    /// and found, `wrapped` tells MOS the tokens are the wrapped NEAR of a native delivery.
+    fn give_up_delivery(&self, delivery: Delivery, wrapped: bool) -> Promise {
+        if let Some(fallback_account) = delivery.fallback_account {
+            return if delivery.is_native {
+                Promise::new(fallback_account).transfer(delivery.amount.0)
+            } else {
+                ext_ft_core::ext(delivery.token)
+                    .with_static_gas(FT_TRANSFER_GAS)
+                    .with_attached_deposit(1)
+                    .ft_transfer(fallback_account, delivery.amount, None)
+            };
+        }
+
+        let msg = serde_json::to_string(&LostFoundMessage {
+            account: delivery.account_id,
+            is_native: wrapped,
+        })
+        .unwrap();
+        ext_ft_core::ext(delivery.token)
+            .with_static_gas(FT_TRANSFER_CALL_LOST_FOUND_GAS)
+            .with_attached_deposit(1)
+            .ft_transfer_call(self.controller.clone(), delivery.amount, None, msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig, VMContext};
+
+    const CORE: &str = "core0.corefac.near";
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    fn context() -> VMContext {
+        VMContextBuilder::new()
+            .current_account_id(account(CORE))
+            .predecessor_account_id(account(CORE))
+            .build()
+    }
+
+    fn setup() -> ButterCore {
+        testing_env!(context());
+        ButterCore::new(
+            account("mos.near"),
+            account("v2.ref-finance.near"),
+            account("wrap.near"),
+            account("owner.near"),
+            None,
+        )
+    }
+
+    fn promise_results(results: Vec<PromiseResult>) {
+        testing_env!(
+            context(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            results,
+        );
+    }
+
+    /// The arguments of the calls to `function_name` created by the last call.
+    fn created_calls(function_name: &str) -> Vec<serde_json::Value> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name: name,
+                    args,
+                    ..
+                } if name == function_name => Some(serde_json::from_slice(&args).unwrap()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn delivery(amount: u128) -> Delivery {
+        Delivery {
+            token: account("eth.near"),
+            account_id: account("alice.near"),
+            amount: U128(amount),
+            is_native: false,
+            fallback_account: None,
+            msg: None,
+        }
+    }
+
+    /// A pending delivery of 100 of `token`, reserved by the core and redirected by
+    /// callback_redirect_delivery with `result`.
+    fn redirect(core: &mut ButterCore, token: &str, result: PromiseResult) {
+        let pending = PendingDelivery {
+            delivery: Delivery {
+                token: account(token),
+                account_id: account("alice.near"),
+                amount: U128(100),
+                is_native: false,
+                fallback_account: None,
+                msg: None,
+            },
+            attempts: 3,
+            created_at: U64(0),
+        };
+        core.reserve_pending_amount(&pending.delivery);
+        testing_env!(
+            context(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+        core.callback_redirect_delivery(0, pending);
+    }
+
+    #[test]
+    fn test_redirect_delivery() {
+        let mut core = setup();
+        redirect(
+            &mut core,
+            "usdc.near",
+            PromiseResult::Successful(serde_json::to_vec(&U128(100)).unwrap()),
+        );
+
+        assert!(core.get_pending_delivery(U64(0)).is_none());
+        assert_eq!(core.get_pending_amount(account("usdc.near")).0, 0);
+    }
+
+    #[test]
+    fn test_redirect_delivery_failed() {
+        let mut core = setup();
+        redirect(&mut core, "usdc.near", PromiseResult::Failed);
+
+        let pending = core.get_pending_delivery(U64(0)).unwrap();
+        assert_eq!(pending.attempts, 3);
+        assert_eq!(pending.delivery.amount.0, 100);
+        assert_eq!(core.get_pending_amount(account("usdc.near")).0, 100);
+    }
+
+    #[test]
+    fn test_redirect_wrapped_delivery_refunded() {
+        let mut core = setup();
+        redirect(
+            &mut core,
+            "wrap.near",
+            PromiseResult::Successful(serde_json::to_vec(&U128(40)).unwrap()),
+        );
+
+        // the wrapped NEAR refunded by MOS lost and found is queued
+        let pending = core.get_pending_delivery(U64(0)).unwrap();
+        assert!(!pending.delivery.is_native);
+        assert_eq!(pending.delivery.amount.0, 60);
+        assert_eq!(core.get_pending_amount(account("wrap.near")).0, 60);
+    }
+
+    /// A pending delivery of 100 NEAR reserved by the core, which is redirected.
+    fn native_pending(core: &mut ButterCore) -> PendingDelivery {
+        let pending = PendingDelivery {
+            delivery: Delivery {
+                token: account("wrap.near"),
+                is_native: true,
+                ..delivery(100)
+            },
+            attempts: 3,
+            created_at: U64(0),
+        };
+        core.reserve_pending_amount(&pending.delivery);
+        pending
+    }
+
+    #[test]
+    fn test_wrap_delivery() {
+        let mut core = setup();
+        let pending = native_pending(&mut core);
+        assert_eq!(core.pending_native_amount, 100);
+
+        core.redirect_delivery(0, pending.clone());
+        assert_eq!(created_calls("callback_wrap_delivery").len(), 1);
+        assert!(created_calls("ft_transfer_call").is_empty());
+
+        promise_results(vec![PromiseResult::Successful(vec![])]);
+        core.callback_wrap_delivery(0, pending);
+
+        // the wrapped NEAR is sent to MOS lost and found and reserved instead of the NEAR
+        let redirects = created_calls("ft_transfer_call");
+        assert_eq!(redirects.len(), 1);
+        assert_eq!(redirects[0]["receiver_id"], "mos.near");
+        assert_eq!(redirects[0]["amount"], "100");
+        let msg: serde_json::Value =
+            serde_json::from_str(redirects[0]["msg"].as_str().unwrap()).unwrap();
+        assert_eq!(msg["is_native"], true);
+        assert_eq!(core.pending_native_amount, 0);
+        assert_eq!(core.get_pending_amount(account("wrap.near")).0, 100);
+    }
+
+    #[test]
+    fn test_wrap_delivery_failed() {
+        let mut core = setup();
+        let pending = native_pending(&mut core);
+        promise_results(vec![PromiseResult::Failed]);
+
+        core.callback_wrap_delivery(0, pending);
+
+        // no wrapped NEAR is sent, the NEAR delivery is queued again
+        assert!(created_calls("ft_transfer_call").is_empty());
+        let pending = core.get_pending_delivery(U64(0)).unwrap();
+        assert!(pending.delivery.is_native);
+        assert_eq!(pending.delivery.amount.0, 100);
+        assert_eq!(core.pending_native_amount, 100);
+        assert_eq!(core.get_pending_amount(account("wrap.near")).0, 0);
+    }
+
+    #[test]
+    fn test_sweep_native() {
+        let mut core = setup();
+        let pending = native_pending(&mut core);
+        let available =
+            env::account_balance() - env::storage_byte_cost() * env::storage_usage() as Balance;
+
+        // the NEAR of the pending delivery can't be swept until it is delivered
+        promise_results(vec![PromiseResult::Successful(vec![])]);
+        assert!(core.callback_retry_delivery(0, pending));
+        assert_eq!(core.pending_native_amount, 0);
+        core.sweep(None, account("owner.near"), U128(available));
+    }
+
+    #[test]
+    #[should_panic(expected = "E032")]
+    fn test_sweep_native_pending() {
+        let mut core = setup();
+        native_pending(&mut core);
+        let available =
+            env::account_balance() - env::storage_byte_cost() * env::storage_usage() as Balance;
+
+        core.sweep(None, account("owner.near"), U128(available - 50));
+    }
+
+    #[test]
+    fn test_target_call_refunded() {
+        let mut core = setup();
+        promise_results(vec![PromiseResult::Successful(
+            serde_json::to_vec(&U128(60)).unwrap(),
+        )]);
+
+        core.callback_target_call(0, delivery(100), (U128(0), U128(100)));
+
+        // the refunded amount is delivered to the target account
+        let transfers = created_calls("ft_transfer");
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0]["receiver_id"], "alice.near");
+        assert_eq!(transfers[0]["amount"], "40");
+        let refund = &created_calls("callback_target_refund")[0];
+        assert_eq!(refund["result"], serde_json::json!(["0", "60"]));
+        assert_eq!(core.get_pending_amount(account("eth.near")).0, 40);
+
+        promise_results(vec![PromiseResult::Successful(vec![])]);
+        let result = core.callback_target_refund(0, delivery(40), (U128(0), U128(60)));
+        assert_eq!(result, (U128(0), U128(60)));
+        assert!(created_calls("ft_transfer_call").is_empty());
+        assert!(core.get_pending_delivery(U64(0)).is_none());
+        assert_eq!(core.get_pending_amount(account("eth.near")).0, 0);
+    }
+
+    #[test]
+    fn test_target_refund_failed() {
+        let mut core = setup();
+        core.reserve_pending_amount(&delivery(40));
+        promise_results(vec![PromiseResult::Failed]);
+
+        core.callback_target_refund(0, delivery(40), (U128(0), U128(60)));
+
+        // the refund is redirected to MOS lost and found, it is kept reserved until checked
+        let redirects = created_calls("ft_transfer_call");
+        assert_eq!(redirects.len(), 1);
+        assert_eq!(redirects[0]["receiver_id"], "mos.near");
+        assert_eq!(redirects[0]["amount"], "40");
+        assert_eq!(core.get_pending_amount(account("eth.near")).0, 40);
+    }
+
+    #[test]
+    fn test_target_call_used() {
+        let mut core = setup();
+        promise_results(vec![PromiseResult::Successful(
+            serde_json::to_vec(&U128(100)).unwrap(),
+        )]);
+
+        core.callback_target_call(0, delivery(100), (U128(0), U128(100)));
+
+        assert!(created_calls("ft_transfer").is_empty());
+        assert_eq!(core.get_pending_amount(account("eth.near")).0, 0);
+    }
+
+    /// A swap out of `amount` to MOS.
+    fn swap_out(amount: u128) -> Delivery {
+        Delivery {
+            account_id: account("mos.near"),
+            msg: Some("swap".to_string()),
+            ..delivery(amount)
+        }
+    }
+
+    /// The swap out of 100 of which MOS uses 60, with `on_refund`.
+    fn refund_swap_out(core: &mut ButterCore, on_refund: RefundPolicy) {
+        promise_results(vec![PromiseResult::Successful(
+            serde_json::to_vec(&U128(60)).unwrap(),
+        )]);
+        match core.callback_transfer_call(0, swap_out(100), on_refund, (U128(100), U128(100))) {
+            PromiseOrValue::Value(result) => {
+                assert_eq!(on_refund, RefundPolicy::Retry);
+                assert_eq!(result, (U128(100), U128(60)));
+            }
+            PromiseOrValue::Promise(_) => assert_ne!(on_refund, RefundPolicy::Retry),
+        }
+    }
+
+    #[test]
+    fn test_swap_out_refunded_to_lost_and_found() {
+        let mut core = setup();
+        refund_swap_out(&mut core, RefundPolicy::LostAndFound);
+
+        let redirects = created_calls("ft_transfer_call");
+        assert_eq!(redirects.len(), 1);
+        assert_eq!(redirects[0]["receiver_id"], "mos.near");
+        assert_eq!(redirects[0]["amount"], "40");
+        let msg: serde_json::Value =
+            serde_json::from_str(redirects[0]["msg"].as_str().unwrap()).unwrap();
+        assert_eq!(msg["account"], "mos.near");
+        assert_eq!(core.get_pending_amount(account("eth.near")).0, 40);
+    }
+
+    #[test]
+    fn test_swap_out_refunded_to_sender() {
+        let mut core = setup();
+        refund_swap_out(&mut core, RefundPolicy::ReturnToSender);
+
+        assert!(created_calls("ft_transfer_call").is_empty());
+        let transfers = created_calls("ft_transfer");
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0]["receiver_id"], "mos.near");
+        assert_eq!(transfers[0]["amount"], "40");
+        assert_eq!(core.get_pending_amount(account("eth.near")).0, 40);
+    }
+
+    #[test]
+    fn test_swap_out_refunded_retry() {
+        let mut core = setup();
+        refund_swap_out(&mut core, RefundPolicy::Retry);
+
+        // the refunded amount is queued as the swap out with one attempt
+        assert!(get_created_receipts().is_empty());
+        let pending = core.get_pending_delivery(U64(0)).unwrap();
+        assert_eq!(pending.attempts, 1);
+        assert_eq!(pending.delivery.amount.0, 40);
+        assert_eq!(pending.delivery.msg.as_deref(), Some("swap"));
+        assert_eq!(core.get_pending_amount(account("eth.near")).0, 40);
+
+        // the retry sends the swap out to MOS again, which uses 30 of it
+        core.retry_delivery(U64(0));
+        let swaps = created_calls("ft_transfer_call");
+        assert_eq!(swaps.len(), 1);
+        assert_eq!(swaps[0]["receiver_id"], "mos.near");
+        assert_eq!(swaps[0]["amount"], "40");
+        assert_eq!(swaps[0]["msg"], "swap");
+        promise_results(vec![PromiseResult::Successful(
+            serde_json::to_vec(&U128(30)).unwrap(),
+        )]);
+        assert!(!core.callback_retry_delivery(0, pending));
+
+        let pending = core.get_pending_delivery(U64(0)).unwrap();
+        assert_eq!(pending.attempts, 2);
+        assert_eq!(pending.delivery.amount.0, 10);
+        assert_eq!(core.get_pending_amount(account("eth.near")).0, 10);
+    }
+}
@@ -0,0 +1,298 @@
+use crate::planner::PlanError;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, AccountId};
+use std::fmt;
+
+/// Errors of the core, each has a stable code which is the prefix of its message, so callers
+/// can react to the failures programmatically.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum ButterCoreError {
+    /// E001
+    Unauthorized(AccountId),
+    /// E002
+    NotEnoughDeposit,
+    /// E003
+    InvalidArgument(String),
+    /// E010
+    InvalidSwapMsg(String),
+    /// E011
+    InvalidPath(String),
+    /// E012
+    InvalidAmount(String),
+    /// E013
+    InvalidTarget(String),
+    /// E014
+    NotEnoughGas,
+    /// E015
+    HookNotAllowed {
+        contract_id: AccountId,
+        method_name: String,
+    },
+    /// E016
+    HookGasExceeded { gas: U64, max_gas: U64 },
+    /// E017
+    RelayerFeeExceeded { fee: U128, cap: U128 },
+    /// E018
+    GasDropExceeded { amount: U128, cap: U128 },
+    /// E019
+    TargetAccountUnavailable {
+        account_id: AccountId,
+        token: Option<AccountId>,
+    },
+    /// E020
+    RefExchangeFailed(String),
+    /// E021
+    UnexpectedUsedAmount { expected: U128, actual: U128 },
+    /// E022
+    SwapFailed,
+    /// E023
+    UnexpectedSwapResult,
+    /// E024
+    TokenCallFailed(String),
+    /// E030
+    DeliveryFailed { order_id: U64 },
+    /// E031
+    NoPendingDelivery { order_id: U64 },
+    /// E032
+    NotEnoughBalance { available: U128 },
+    /// E033
+    TransferCallRefunded { refunded: U128 },
+    /// E034
+    HookFailed { unused: U128 },
+    /// E040
+    NoStagedUpgrade,
+    /// E041
+    CodeHashMismatch,
+    /// E042
+    CannotRollBack(String),
+    /// E043
+    FactoryCallFailed(String),
+    /// E044
+    MigrationFailed(String),
+    /// E050
+    UnknownProposal(U64),
+    /// E051
+    TimeLocked { until: U64 },
+    /// E060
+    UnknownAdminProposal(U64),
+    /// E061
+    AlreadyApproved(AccountId),
+    /// E062
+    NotEnoughApprovals { approvals: u32, threshold: u32 },
+    /// E063
+    AdminProposalExpired(U64),
+    /// E064
+    AdminProposalNotExpired(U64),
+}
+
+impl ButterCoreError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ButterCoreError::Unauthorized(_) => "E001",
+            ButterCoreError::NotEnoughDeposit => "E002",
+            ButterCoreError::InvalidArgument(_) => "E003",
+            ButterCoreError::InvalidSwapMsg(_) => "E010",
+            ButterCoreError::InvalidPath(_) => "E011",
+            ButterCoreError::InvalidAmount(_) => "E012",
+            ButterCoreError::InvalidTarget(_) => "E013",
+            ButterCoreError::NotEnoughGas => "E014",
+            ButterCoreError::HookNotAllowed { .. } => "E015",
+            ButterCoreError::HookGasExceeded { .. } => "E016",
+            ButterCoreError::RelayerFeeExceeded { .. } => "E017",
+            ButterCoreError::GasDropExceeded { .. } => "E018",
+            ButterCoreError::TargetAccountUnavailable { .. } => "E019",
+            ButterCoreError::RefExchangeFailed(_) => "E020",
+            ButterCoreError::UnexpectedUsedAmount { .. } => "E021",
+            ButterCoreError::SwapFailed => "E022",
+            ButterCoreError::UnexpectedSwapResult => "E023",
+            ButterCoreError::TokenCallFailed(_) => "E024",
+            ButterCoreError::DeliveryFailed { .. } => "E030",
+            ButterCoreError::NoPendingDelivery { .. } => "E031",
+            ButterCoreError::NotEnoughBalance { .. } => "E032",
+            ButterCoreError::TransferCallRefunded { .. } => "E033",
+            ButterCoreError::HookFailed { .. } => "E034",
+            ButterCoreError::NoStagedUpgrade => "E040",
+            ButterCoreError::CodeHashMismatch => "E041",
+            ButterCoreError::CannotRollBack(_) => "E042",
+            ButterCoreError::FactoryCallFailed(_) => "E043",
+            ButterCoreError::MigrationFailed(_) => "E044",
+            ButterCoreError::UnknownProposal(_) => "E050",
+            ButterCoreError::TimeLocked { .. } => "E051",
+            ButterCoreError::UnknownAdminProposal(_) => "E060",
+            ButterCoreError::AlreadyApproved(_) => "E061",
+            ButterCoreError::NotEnoughApprovals { .. } => "E062",
+            ButterCoreError::AdminProposalExpired(_) => "E063",
+            ButterCoreError::AdminProposalNotExpired(_) => "E064",
+        }
+    }
+
+    pub fn panic(&self) -> ! {
+        // panic_str aborts in unit tests, which can't catch it
+        if cfg!(target_arch = "wasm32") {
+            env::panic_str(&self.to_string())
+        } else {
+            panic!("{}", self)
+        }
+    }
+}
+
+impl fmt::Display for ButterCoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.code())?;
+        match self {
+            ButterCoreError::Unauthorized(caller) => write!(f, "unexpected caller {}", caller),
+            ButterCoreError::NotEnoughDeposit => write!(f, "not enough deposit"),
+            ButterCoreError::InvalidArgument(e) => write!(f, "invalid argument, {}", e),
+            ButterCoreError::InvalidSwapMsg(e) => write!(f, "invalid core swap msg, {}", e),
+            ButterCoreError::InvalidPath(e)
+            | ButterCoreError::InvalidAmount(e)
+            | ButterCoreError::InvalidTarget(e) => write!(f, "invalid core swap msg, {}", e),
+            ButterCoreError::NotEnoughGas => write!(f, "not enough gas"),
+            ButterCoreError::HookNotAllowed {
+                contract_id,
+                method_name,
+            } => write!(f, "hook {}.{} is not allowed", contract_id, method_name),
+            ButterCoreError::HookGasExceeded { gas, max_gas } => {
+                write!(f, "gas of hook {} exceeds {}", gas.0, max_gas.0)
+            }
+            ButterCoreError::RelayerFeeExceeded { fee, cap } => {
+                write!(f, "relayer fee {} exceeds the cap {}", fee.0, cap.0)
+            }
+            ButterCoreError::GasDropExceeded { amount, cap } => {
+                write!(f, "gas drop {} exceeds the cap {}", amount.0, cap.0)
+            }
+            ButterCoreError::TargetAccountUnavailable {
+                account_id,
+                token: Some(token),
+            } => write!(
+                f,
+                "target account {} is not registered in {}",
+                account_id, token
+            ),
+            ButterCoreError::TargetAccountUnavailable {
+                account_id,
+                token: None,
+            } => write!(f, "target account {} does not exist", account_id),
+            ButterCoreError::RefExchangeFailed(e) => write!(f, "{} failed", e),
+            ButterCoreError::UnexpectedUsedAmount { expected, actual } => write!(
+                f,
+                "used amount is unexpected, expected: {}, actual: {}",
+                expected.0, actual.0
+            ),
+            ButterCoreError::SwapFailed => write!(f, "swap failed"),
+            ButterCoreError::UnexpectedSwapResult => write!(f, "unexpected swap result"),
+            ButterCoreError::TokenCallFailed(e) => write!(f, "{} failed", e),
+            ButterCoreError::DeliveryFailed { order_id } => {
+                write!(f, "delivery of order {} failed", order_id.0)
+            }
+            ButterCoreError::NoPendingDelivery { order_id } => {
+                write!(f, "no pending delivery for order {}", order_id.0)
+            }
+            ButterCoreError::NotEnoughBalance { available } => {
+                write!(f, "not enough balance, available {}", available.0)
+            }
+            ButterCoreError::TransferCallRefunded { refunded } => {
+                write!(
+                    f,
+                    "{} of ft_transfer_call is refunded by the receiver",
+                    refunded.0
+                )
+            }
+            ButterCoreError::HookFailed { unused } => write!(
+                f,
+                "hook failed, {} is delivered to the target account",
+                unused.0
+            ),
+            ButterCoreError::NoStagedUpgrade => write!(f, "no staged upgrade"),
+            ButterCoreError::CodeHashMismatch => write!(f, "code hash mismatch"),
+            ButterCoreError::CannotRollBack(e) => write!(f, "cannot roll back, {}", e),
+            ButterCoreError::FactoryCallFailed(e) => write!(f, "{} failed", e),
+            ButterCoreError::MigrationFailed(e) => write!(f, "cannot migrate state, {}", e),
+            ButterCoreError::UnknownProposal(id) => write!(f, "unknown proposal {}", id.0),
+            ButterCoreError::TimeLocked { until } => write!(f, "time locked until {}", until.0),
+            ButterCoreError::UnknownAdminProposal(id) => {
+                write!(f, "unknown admin proposal {}", id.0)
+            }
+            ButterCoreError::AlreadyApproved(admin) => {
+                write!(f, "proposal is already approved by {}", admin)
+            }
+            ButterCoreError::NotEnoughApprovals {
+                approvals,
+                threshold,
+            } => write!(f, "proposal has {} of {} approvals", approvals, threshold),
+            ButterCoreError::AdminProposalExpired(id) => {
+                write!(f, "admin proposal {} is expired", id.0)
+            }
+            ButterCoreError::AdminProposalNotExpired(id) => {
+                write!(f, "admin proposal {} is not expired", id.0)
+            }
+        }
+    }
+}
+
+impl From<PlanError> for ButterCoreError {
+    fn from(e: PlanError) -> Self {
+        match e {
+            PlanError::EmptyActions
+            | PlanError::TokenInMismatch { .. }
+            | PlanError::BrokenRoute { .. } => ButterCoreError::InvalidPath(e.to_string()),
+            PlanError::ZeroAmount
+            | PlanError::MissingAmountIn
+            | PlanError::AmountInExceeded { .. }
+            | PlanError::UnexpectedAmountIn { .. } => ButterCoreError::InvalidAmount(e.to_string()),
+            PlanError::InvalidNativeTarget
+            | PlanError::TargetTokenMismatch { .. }
+            | PlanError::UnexpectedSwapMsg
+            | PlanError::MissingSwapMsg
+            | PlanError::InvalidSwapOutAddress
+            | PlanError::SwapOutAddressMismatch
+            | PlanError::ToChainMismatch { .. }
+            | PlanError::UnexpectedTargetCall
+            | PlanError::TargetCallGasExceeded { .. }
+            | PlanError::UnexpectedHook
+            | PlanError::InvalidHookMethod
+            | PlanError::UnexpectedGasDrop
+            | PlanError::InternalGasDrop => ButterCoreError::InvalidTarget(e.to_string()),
+        }
+    }
+}
+
+/// Panic with `$err` if `$cond` is false.
+macro_rules! ensure {
+    ($cond:expr, $err:expr) => {
+        if !$cond {
+            $crate::errors::ButterCoreError::panic(&$err)
+        }
+    };
+}
+
+pub(crate) use ensure;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(
+            ButterCoreError::Unauthorized("alice.near".parse().unwrap()).to_string(),
+            "E001: unexpected caller alice.near"
+        );
+        assert_eq!(
+            ButterCoreError::from(PlanError::BrokenRoute { index: 1 }).code(),
+            "E011"
+        );
+        assert_eq!(ButterCoreError::from(PlanError::ZeroAmount).code(), "E012");
+        assert_eq!(
+            ButterCoreError::from(PlanError::InvalidNativeTarget).code(),
+            "E013"
+        );
+        assert_eq!(
+            ButterCoreError::RefExchangeFailed("call ref exchange".to_string()).to_string(),
+            "E020: call ref exchange failed"
+        );
+    }
+}
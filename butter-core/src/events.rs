@@ -0,0 +1,112 @@
+use crate::errors::ButterCoreError;
+use crate::types::{AdminAction, ConfigChange, UpgradeStatus};
+use crate::*;
+use near_sdk::json_types::{Base58CryptoHash, U64};
+use near_sdk::serde::{Serialize, Serializer};
+
+const EVENT_STANDARD: &str = "butter-core";
+const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// Events of the core, logged in the NEP-297 format.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data")]
+#[serde(rename_all = "snake_case")]
+pub enum CoreEvent<'a> {
+    ConfigChangeProposed {
+        proposal_id: U64,
+        change: &'a ConfigChange,
+        executable_at: U64,
+    },
+    ConfigChangeExecuted {
+        proposal_id: U64,
+        change: &'a ConfigChange,
+    },
+    ConfigChangeCancelled {
+        proposal_id: U64,
+        change: &'a ConfigChange,
+        cancelled_by: &'a AccountId,
+    },
+    UpgradeStaged {
+        code_hash: Base58CryptoHash,
+        executable_at: U64,
+    },
+    UpgradeCancelled {
+        code_hash: Base58CryptoHash,
+        cancelled_by: &'a AccountId,
+    },
+    UpgradeApplied {
+        code_hash: Base58CryptoHash,
+        status: UpgradeStatus,
+    },
+    AdminActionProposed {
+        proposal_id: U64,
+        action: &'a AdminAction,
+        proposer: &'a AccountId,
+    },
+    AdminActionApproved {
+        proposal_id: U64,
+        approved_by: &'a AccountId,
+    },
+    AdminActionExecuted {
+        proposal_id: U64,
+        action: &'a AdminAction,
+    },
+    RelayerFeeCharged {
+        order_id: U64,
+        relayer: &'a AccountId,
+        token: &'a AccountId,
+        amount: U128,
+    },
+    GasDropped {
+        order_id: U64,
+        account_id: &'a AccountId,
+        token: &'a AccountId,
+        amount: U128,
+    },
+    /// An order is rejected or failed, `order_id` is none if the order is rejected before it gets
+    /// an order id.
+    OrderFailed {
+        order_id: Option<U64>,
+        #[serde(serialize_with = "serialize_error")]
+        error: &'a ButterCoreError,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct ErrorLog {
+    code: &'static str,
+    message: String,
+}
+
+fn serialize_error<S: Serializer>(
+    error: &&ButterCoreError,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    ErrorLog {
+        code: error.code(),
+        message: error.to_string(),
+    }
+    .serialize(serializer)
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a CoreEvent<'a>,
+}
+
+impl CoreEvent<'_> {
+    pub fn emit(&self) {
+        let log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_STANDARD_VERSION,
+            event: self,
+        };
+        log!("EVENT_JSON:{}", serde_json::to_string(&log).unwrap());
+    }
+}
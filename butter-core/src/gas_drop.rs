@@ -0,0 +1,137 @@
+use crate::types::{GasDrop, SwapContext};
+use crate::*;
+use near_sdk::json_types::U64;
+
+/// A contract can't read the NEAR balance of another account, so instead of a balance threshold,
+/// an account receives at most `max_gas_drops` gas drops, which is 1 by default.
+pub const DEFAULT_MAX_GAS_DROPS: u32 = 1;
+
+#[near_bindgen]
+impl ButterCore {
+    /// Max amount of the output swapped by the gas drop of a swap whose output is `token`, gas
+    /// drops are not allowed if it is 0.
+    pub fn get_gas_drop_cap(&self, token: AccountId) -> U128 {
+        U128(self.gas_drop_caps.get(&token).unwrap_or(0))
+    }
+
+    pub fn set_gas_drop_cap(&mut self, token: AccountId, cap: U128) {
+        self.assert_owner();
+        if cap.0 == 0 {
+            self.gas_drop_caps.remove(&token);
+        } else {
+            self.gas_drop_caps.insert(&token, &cap.0);
+        }
+    }
+
+    /// Max number of gas drops received by an account, later gas drops are skipped and the whole
+    /// output is delivered.
+    pub fn get_max_gas_drops(&self) -> u32 {
+        self.max_gas_drops
+    }
+
+    pub fn set_max_gas_drops(&mut self, max_gas_drops: u32) {
+        self.assert_owner();
+        self.max_gas_drops = max_gas_drops;
+    }
+
+    /// Number of gas drops received by `account_id`, a gas drop is counted once its swap succeeds.
+    pub fn get_gas_drops(&self, account_id: AccountId) -> u32 {
+        self.gas_drops.get(&account_id).unwrap_or(0)
+    }
+
+    /// Start the swap of the gas drop `ctx` with the wrapped NEAR balance of the core before it,
+    /// which may hold wrapped NEAR for other swaps. The input of the gas drop is delivered as the
+    /// token if the balance is unknown.
+    #[private]
+    pub fn callback_drop_gas(&self, ctx: SwapContext) -> PromiseOrValue<(U128, U128)> {
+        match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) => match serde_json::from_slice::<U128>(&x) {
+                Ok(balance) => self
+                    .do_swap_execute(SwapContext {
+                        balance_before: Some(balance),
+                        ..ctx
+                    })
+                    .into(),
+                Err(_) => self.refund(ctx, U128(0)),
+            },
+            PromiseResult::Failed => self.refund(ctx, U128(0)),
+        }
+    }
+}
+
+impl ButterCore {
+    /// Check the amount of the gas drop is within the cap of the output token.
+    pub(crate) fn check_gas_drop(
+        &self,
+        token_out: &AccountId,
+        gas_drop: &GasDrop,
+    ) -> Result<(), ButterCoreError> {
+        let cap = self.gas_drop_caps.get(token_out).unwrap_or(0);
+        if gas_drop.amount.0 > cap {
+            return Err(ButterCoreError::GasDropExceeded {
+                amount: gas_drop.amount,
+                cap: U128(cap),
+            });
+        }
+        Ok(())
+    }
+
+    /// Amount of the output swapped by the gas drop of the swap, 0 if there is no gas drop, the
+    /// target account has received `max_gas_drops` or the core has been switched to the internal
+    /// swap mode, which doesn't allow gas drops, since the swap is planned.
+    pub(crate) fn gas_drop_amount(&self, ctx: &SwapContext) -> Balance {
+        match &ctx.gas_drop {
+            Some(gas_drop)
+                if self.swap_mode == SwapMode::Execute
+                    && self.get_gas_drops(ctx.target_account.clone()) < self.max_gas_drops =>
+            {
+                gas_drop.amount.0
+            }
+            _ => 0,
+        }
+    }
+
+    /// Count the gas drop of `ctx` for its target account once its swap succeeds.
+    pub(crate) fn record_gas_drop(&mut self, ctx: &SwapContext) {
+        let gas_drops = self.get_gas_drops(ctx.target_account.clone());
+        self.gas_drops.insert(&ctx.target_account, &(gas_drops + 1));
+        CoreEvent::GasDropped {
+            order_id: U64(ctx.order_id),
+            account_id: &ctx.target_account,
+            token: &ctx.token_in,
+            amount: ctx.amount_in,
+        }
+        .emit();
+    }
+
+    /// Swap a part of `amount_out` to NEAR for the target account along with the delivery, see
+    /// `SwapContext::gas_drop`. The gas drop is skipped if it is not less than `amount_out`.
+    /// Returns the amount left to deliver.
+    pub(crate) fn drop_gas(&self, ctx: &SwapContext, amount_out: U128) -> U128 {
+        let amount = self.gas_drop_amount(ctx);
+        if amount == 0 {
+            return amount_out;
+        }
+        if amount >= amount_out.0 {
+            log!(
+                "gas drop {} is not less than amount out {}, skipped",
+                amount,
+                amount_out.0
+            );
+            return amount_out;
+        }
+        // the gas drop is a single pool swap through the Execute flow of the swap, its output is
+        // delivered and counted by its own callbacks, it is not part of the result of the swap
+        let drop_ctx = ctx.gas_drop(ctx.gas_drop.as_ref().unwrap(), &self.wrapped_token);
+        ext_ft_core::ext(self.wrapped_token.clone())
+            .with_static_gas(FT_BALANCE_OF_GAS)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_DROP_GAS_GAS)
+                    .callback_drop_gas(drop_ctx),
+            );
+        U128(amount_out.0 - amount)
+    }
+}
@@ -0,0 +1,272 @@
+use crate::events::CoreEvent;
+use crate::types::{ConfigChange, Proposal};
+use crate::upgrade::MIN_UPGRADE_DELAY;
+use crate::*;
+use near_sdk::json_types::U64;
+
+/// 1 day in nanoseconds.
+pub const DEFAULT_TIMELOCK_DELAY: u64 = 24 * 3600 * 1_000_000_000;
+/// 1 hour in nanoseconds, the timelock delay can't be set below it.
+pub const MIN_TIMELOCK_DELAY: u64 = 3600 * 1_000_000_000;
+
+#[near_bindgen]
+impl ButterCore {
+    pub fn get_guardian(&self) -> Option<AccountId> {
+        self.guardian.clone()
+    }
+
+    pub fn get_timelock_delay(&self) -> U64 {
+        U64(self.timelock_delay)
+    }
+
+    pub fn get_proposal(&self, proposal_id: U64) -> Option<Proposal> {
+        self.proposals.get(&proposal_id.0)
+    }
+
+    pub fn get_proposals(&self, from_index: u64, limit: u64) -> Vec<(U64, Proposal)> {
+        self.proposals
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(proposal_id, proposal)| (U64(proposal_id), proposal))
+            .collect()
+    }
+
+    /// Propose a configuration change, which can be executed by the owner after the timelock
+    /// delay. Returns the proposal id.
+    pub fn propose_config_change(&mut self, change: ConfigChange) -> U64 {
+        self.assert_owner();
+        match &change {
+            ConfigChange::TimelockDelay(timelock_delay) => ensure!(
+                timelock_delay.0 >= MIN_TIMELOCK_DELAY,
+                ButterCoreError::InvalidArgument(format!(
+                    "timelock delay should be at least {}",
+                    MIN_TIMELOCK_DELAY
+                ))
+            ),
+            ConfigChange::UpgradeDelay(upgrade_delay) => ensure!(
+                upgrade_delay.0 >= MIN_UPGRADE_DELAY,
+                ButterCoreError::InvalidArgument(format!(
+                    "upgrade delay should be at least {}",
+                    MIN_UPGRADE_DELAY
+                ))
+            ),
+            ConfigChange::Multisig { admins, threshold } => {
+                Self::assert_multisig_threshold(admins, *threshold)
+            }
+            _ => {}
+        }
+
+        let proposal_id = self.next_proposal_id;
+        self.next_proposal_id += 1;
+        let proposed_at = env::block_timestamp();
+        let proposal = Proposal {
+            change,
+            proposed_at: U64(proposed_at),
+            executable_at: U64(proposed_at + self.timelock_delay),
+        };
+        self.proposals.insert(&proposal_id, &proposal);
+
+        CoreEvent::ConfigChangeProposed {
+            proposal_id: U64(proposal_id),
+            change: &proposal.change,
+            executable_at: proposal.executable_at,
+        }
+        .emit();
+        U64(proposal_id)
+    }
+
+    /// Execute a proposal after its timelock delay, a sweep or a ref withdraw returns the
+    /// transfer.
+    pub fn execute_config_change(&mut self, proposal_id: U64) -> PromiseOrValue<()> {
+        self.assert_owner();
+        let proposal = self
+            .proposals
+            .get(&proposal_id.0)
+            .unwrap_or_else(|| ButterCoreError::UnknownProposal(proposal_id).panic());
+        ensure!(
+            env::block_timestamp() >= proposal.executable_at.0,
+            ButterCoreError::TimeLocked {
+                until: proposal.executable_at
+            }
+        );
+
+        self.proposals.remove(&proposal_id.0);
+        CoreEvent::ConfigChangeExecuted {
+            proposal_id,
+            change: &proposal.change,
+        }
+        .emit();
+        match proposal.change {
+            ConfigChange::Owner(owner) => self.owner = owner,
+            ConfigChange::Multisig { admins, threshold } => self.setup_multisig(admins, threshold),
+            ConfigChange::Controller(controller) => self.controller = controller,
+            ConfigChange::RefExchange(ref_exchange) => self.ref_exchange = ref_exchange,
+            ConfigChange::WrappedToken(wrapped_token) => self.wrapped_token = wrapped_token,
+            ConfigChange::Factory(factory) => self.factory = factory,
+            ConfigChange::Guardian(guardian) => self.guardian = guardian,
+            ConfigChange::TimelockDelay(timelock_delay) => self.timelock_delay = timelock_delay.0,
+            ConfigChange::UpgradeDelay(upgrade_delay) => self.upgrade_delay = upgrade_delay.0,
+            ConfigChange::Sweep {
+                token,
+                receiver_id,
+                amount,
+            } => return self.sweep(token, receiver_id, amount).into(),
+            ConfigChange::RefWithdraw { token, amount } => {
+                return ext_ref_exchange::ext(self.ref_exchange.clone())
+                    .with_static_gas(REF_WITHDRAW_GAS)
+                    .with_attached_deposit(1)
+                    .withdraw(token, amount, None)
+                    .into()
+            }
+        }
+        PromiseOrValue::Value(())
+    }
+
+    /// Cancel a proposal, callable by the owner or the guardian.
+    pub fn cancel_config_change(&mut self, proposal_id: U64) {
+        let caller = env::predecessor_account_id();
+        ensure!(
+            caller == self.owner || Some(&caller) == self.guardian.as_ref(),
+            ButterCoreError::Unauthorized(caller)
+        );
+        let proposal = self
+            .proposals
+            .remove(&proposal_id.0)
+            .unwrap_or_else(|| ButterCoreError::UnknownProposal(proposal_id).panic());
+
+        CoreEvent::ConfigChangeCancelled {
+            proposal_id,
+            change: &proposal.change,
+            cancelled_by: &caller,
+        }
+        .emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upgrade::DEFAULT_UPGRADE_DELAY;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    fn setup() -> ButterCore {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(account("core0.corefac.near"))
+            .predecessor_account_id(account("owner.near"))
+            .build());
+        ButterCore::new(
+            account("mos.near"),
+            account("v2.ref-finance.near"),
+            account("wrap.near"),
+            account("owner.near"),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_upgrade_delay() {
+        let mut core = setup();
+        assert_eq!(core.get_upgrade_delay().0, DEFAULT_UPGRADE_DELAY);
+
+        let proposal_id =
+            core.propose_config_change(ConfigChange::UpgradeDelay(U64(MIN_UPGRADE_DELAY)));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(account("core0.corefac.near"))
+            .predecessor_account_id(account("owner.near"))
+            .block_timestamp(DEFAULT_TIMELOCK_DELAY)
+            .build());
+        core.execute_config_change(proposal_id);
+        assert_eq!(core.get_upgrade_delay().0, MIN_UPGRADE_DELAY);
+    }
+
+    #[test]
+    fn test_ref_withdraw() {
+        let mut core = setup();
+        let proposal_id = core.propose_config_change(ConfigChange::RefWithdraw {
+            token: account("eth.near"),
+            amount: U128(100),
+        });
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(account("core0.corefac.near"))
+            .predecessor_account_id(account("owner.near"))
+            .block_timestamp(DEFAULT_TIMELOCK_DELAY)
+            .build());
+        core.execute_config_change(proposal_id);
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, account("v2.ref-finance.near"));
+        match &receipts[0].actions[0] {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                deposit,
+                ..
+            } => {
+                assert_eq!(function_name, "withdraw");
+                assert_eq!(
+                    serde_json::from_slice::<serde_json::Value>(args).unwrap(),
+                    serde_json::json!({ "token_id": "eth.near", "amount": "100", "unregister": null })
+                );
+                assert_eq!(*deposit, 1);
+            }
+            action => panic!("unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "E003: invalid argument, upgrade delay should be at least")]
+    fn test_upgrade_delay_below_min() {
+        let mut core = setup();
+        core.propose_config_change(ConfigChange::UpgradeDelay(U64(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "E003: invalid argument, timelock delay should be at least")]
+    fn test_timelock_delay_below_min() {
+        let mut core = setup();
+        core.propose_config_change(ConfigChange::TimelockDelay(U64(MIN_TIMELOCK_DELAY - 1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "E051: time locked until")]
+    fn test_owner_change_before_delay() {
+        let mut core = setup();
+        let proposal_id = core.propose_config_change(ConfigChange::Owner(account("new.near")));
+        core.execute_config_change(proposal_id);
+    }
+
+    #[test]
+    fn test_multisig_change() {
+        let mut core = setup();
+        let proposal_id = core.propose_config_change(ConfigChange::Multisig {
+            admins: vec![account("admin0.near"), account("admin1.near")],
+            threshold: 2,
+        });
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(account("core0.corefac.near"))
+            .predecessor_account_id(account("owner.near"))
+            .block_timestamp(DEFAULT_TIMELOCK_DELAY)
+            .build());
+        core.execute_config_change(proposal_id);
+        assert_eq!(core.get_owner(), account("core0.corefac.near"));
+        assert_eq!(core.get_threshold(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "E003: invalid argument, threshold should be between 1 and")]
+    fn test_multisig_threshold_above_admins() {
+        let mut core = setup();
+        core.propose_config_change(ConfigChange::Multisig {
+            admins: vec![account("admin0.near"), account("admin0.near")],
+            threshold: 2,
+        });
+    }
+}
@@ -0,0 +1,136 @@
+use crate::types::{Delivery, Hook, HookConfig};
+use crate::*;
+use near_sdk::json_types::U64;
+
+#[near_bindgen]
+impl ButterCore {
+    /// Max gas of the hook calling `method_name` on `contract_id`, none if it is not allowed.
+    pub fn get_hook(&self, contract_id: AccountId, method_name: String) -> Option<U64> {
+        self.hooks.get(&(contract_id, method_name)).map(U64)
+    }
+
+    pub fn get_hooks(&self, from_index: u64, limit: u64) -> Vec<HookConfig> {
+        self.hooks
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|((contract_id, method_name), max_gas)| HookConfig {
+                contract_id,
+                method_name,
+                max_gas: U64(max_gas),
+            })
+            .collect()
+    }
+
+    /// Allow the hooks calling `method_name` on `contract_id` with at most `max_gas`.
+    pub fn set_hook(&mut self, contract_id: AccountId, method_name: String, max_gas: U64) {
+        self.assert_owner();
+        ensure!(
+            max_gas.0 > 0 && max_gas.0 <= MAX_TARGET_CALL_GAS.0,
+            ButterCoreError::InvalidArgument(format!(
+                "max gas should be between 1 and {}",
+                MAX_TARGET_CALL_GAS.0
+            ))
+        );
+        self.hooks.insert(&(contract_id, method_name), &max_gas.0);
+    }
+
+    pub fn remove_hook(&mut self, contract_id: AccountId, method_name: String) {
+        self.assert_owner();
+        self.hooks.remove(&(contract_id, method_name));
+    }
+
+    /// Call the hook with the NEAR unwrapped by near_withdraw attached.
+    #[private]
+    pub fn callback_call_hook(
+        &self,
+        order_id: u64,
+        hook: Hook,
+        delivery: Delivery,
+        result: (U128, U128),
+    ) -> Promise {
+        Promise::new(hook.contract_id)
+            .function_call(
+                hook.method_name,
+                hook.args.into_bytes(),
+                delivery.amount.0,
+                Gas(hook.gas.0),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_HOOK_GAS)
+                    .callback_hook(order_id, delivery, result),
+            )
+    }
+
+    /// Check the hook, the tokens not used by the hook are delivered to the target account as
+    /// `delivery` instead. Returns `result`.
+    #[private]
+    pub fn callback_hook(
+        &self,
+        order_id: u64,
+        delivery: Delivery,
+        result: (U128, U128),
+    ) -> PromiseOrValue<(U128, U128)> {
+        // the attached NEAR is used if the hook succeeded, the tokens are used as the result of
+        // ft_transfer_call
+        let unused_amount = match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) if delivery.is_native => 0,
+            PromiseResult::Successful(x) => serde_json::from_slice::<U128>(&x)
+                .map(|used_amount| delivery.amount.0.saturating_sub(used_amount.0))
+                .unwrap_or(0),
+            PromiseResult::Failed => delivery.amount.0,
+        };
+        if unused_amount == 0 {
+            return PromiseOrValue::Value(result);
+        }
+
+        CoreEvent::OrderFailed {
+            order_id: Some(U64(order_id)),
+            error: &ButterCoreError::HookFailed {
+                unused: U128(unused_amount),
+            },
+        }
+        .emit();
+        let delivery = Delivery {
+            amount: U128(unused_amount),
+            ..delivery
+        };
+        let transfer = if delivery.is_native {
+            Promise::new(delivery.account_id.clone()).transfer(unused_amount)
+        } else {
+            ext_ft_core::ext(delivery.token.clone())
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(1)
+                .ft_transfer(delivery.account_id.clone(), delivery.amount, None)
+        };
+        transfer
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_CHECK_TRANSFER_GAS)
+                    .callback_check_transfer(order_id, delivery, result),
+            )
+            .into()
+    }
+}
+
+impl ButterCore {
+    /// Check the hook is allowed and its gas is within the max gas of the allowlist.
+    pub(crate) fn check_hook(&self, hook: &Hook) -> Result<(), ButterCoreError> {
+        let max_gas = self
+            .hooks
+            .get(&(hook.contract_id.clone(), hook.method_name.clone()))
+            .ok_or_else(|| ButterCoreError::HookNotAllowed {
+                contract_id: hook.contract_id.clone(),
+                method_name: hook.method_name.clone(),
+            })?;
+        if hook.gas.0 > max_gas {
+            return Err(ButterCoreError::HookGasExceeded {
+                gas: hook.gas,
+                max_gas: U64(max_gas),
+            });
+        }
+        Ok(())
+    }
+}
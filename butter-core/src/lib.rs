@@ -1,22 +1,66 @@
-mod types;
+mod delivery;
+mod errors;
+mod events;
+mod gas_drop;
+mod governance;
+mod hooks;
+mod migration;
+mod multisig;
+pub mod planner;
+mod relayer;
+mod target;
+pub mod types;
+mod upgrade;
 
-use crate::types::{Action, CoreSwapMessage, TokenReceiverMessage};
+use crate::delivery::{
+    DEFAULT_DELIVERY_TIMEOUT, DEFAULT_MAX_DELIVERY_ATTEMPTS, REDIRECT_DELIVERY_GAS,
+};
+use crate::errors::{ensure, ButterCoreError};
+use crate::events::CoreEvent;
+use crate::gas_drop::DEFAULT_MAX_GAS_DROPS;
+use crate::governance::DEFAULT_TIMELOCK_DELAY;
+use crate::migration::{migrate_factory, migrate_state, read_state_version, write_state_version};
+use crate::planner::{plan_swap, DeliveryMode, SwapConfig, SwapPlan};
+use crate::types::{
+    Action, AdminProposal, BatchSwapResult, CoreSwapMessage, Delivery, FailurePolicy, MinOutPolicy,
+    PendingDelivery, Proposal, StagedUpgrade, SwapContext, SwapMode, UpgradeRecord,
+};
+use crate::upgrade::DEFAULT_UPGRADE_DELAY;
 use near_contract_standards::fungible_token::core::ext_ft_core;
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageBalance;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::env::panic_str;
-use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{Base58CryptoHash, Base64VecU8, U128, U64};
 use near_sdk::{
-    env, ext_contract, log, near_bindgen, serde_json, AccountId, Balance, Gas, PanicOnDefault,
-    Promise, PromiseOrValue, PromiseResult,
+    env, ext_contract, log, near_bindgen, serde_json, AccountId, Balance, BorshStorageKey,
+    CryptoHash, Gas, PanicOnDefault, Promise, PromiseOrValue, PromiseResult,
 };
 
 /// Gas to call ft_transfer_call method.
 const FT_TRANSFER_CALL_REF_GAS: Gas = Gas(84_000_000_000_000);
+/// Gas to call ft_transfer_call method with empty msg, which deposits into ref exchange.
+const FT_TRANSFER_CALL_REF_DEPOSIT_GAS: Gas = Gas(35_000_000_000_000);
 /// Gas to call ft_transfer_call method.
 const FT_TRANSFER_CALL_MOS_GAS: Gas = Gas(35_000_000_000_000);
-/// Gas to call ft_transfer_call method.
-// const FT_TRANSFER_CALL_LOST_FOUND_GAS: Gas = Gas(35_000_000_000_000);
+/// Gas to call ft_transfer_call method, not include the gas of ft_on_transfer of the receiver.
+const FT_TRANSFER_CALL_TARGET_GAS: Gas = Gas(30_000_000_000_000);
+/// Max gas of ft_on_transfer of the target account, given by the target call of a swap, and max
+/// gas of the hooks.
+const MAX_TARGET_CALL_GAS: Gas = Gas(50_000_000_000_000);
+/// Gas to call ft_transfer_call method with the Execute message of a single pool swap.
+const FT_TRANSFER_CALL_REF_GAS_DROP_GAS: Gas = Gas(50_000_000_000_000);
+/// Gas of the gas drop, which swaps a part of the output to wrapped NEAR and transfers the NEAR
+/// unwrapped to the target account.
+const GAS_DROP_GAS: Gas = Gas(FT_BALANCE_OF_GAS.0 + CALLBACK_DROP_GAS_GAS.0);
+/// Gas to call callback_drop_gas method, which starts the swap of the gas drop.
+const CALLBACK_DROP_GAS_GAS: Gas = Gas(5_000_000_000_000
+    + FT_TRANSFER_CALL_REF_GAS_DROP_GAS.0
+    + CALLBACK_GET_AMOUNT_OUT_GAS.0
+    + FT_BALANCE_OF_GAS.0
+    + CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_IN_GAS.0);
+/// Gas to check the target account, by storage_balance_of or a transfer of 0.
+const CHECK_TARGET_GAS: Gas = Gas(5_000_000_000_000);
 /// Gas to call ft_transfer method.
 const FT_TRANSFER_GAS: Gas = Gas(4_000_000_000_000);
 /// Gas to call ft_balance_of method.
@@ -25,23 +69,84 @@ const FT_BALANCE_OF_GAS: Gas = Gas(4_000_000_000_000);
 const NEAR_WITHDRAW_GAS: Gas = Gas(4_000_000_000_000);
 /// Gas to call near_deposit on wrap near contract
 const NEAR_DEPOSIT_GAS: Gas = Gas(7_000_000_000_000);
+/// Gas to call swap on ref exchange.
+const REF_SWAP_GAS: Gas = Gas(30_000_000_000_000);
+/// Gas to call withdraw on ref exchange, which includes ft_transfer and its callback.
+const REF_WITHDRAW_GAS: Gas = Gas(50_000_000_000_000);
+/// Gas to call storage_deposit on ref exchange.
+const REF_STORAGE_DEPOSIT_GAS: Gas = Gas(10_000_000_000_000);
+/// Gas to call register_tokens on ref exchange.
+const REF_REGISTER_TOKENS_GAS: Gas = Gas(10_000_000_000_000);
 /// Gas to call callback_return_value on wrap near contract
 const CALLBACK_RETURN_VALUE_GAS: Gas = Gas(3_000_000_000_000);
+/// Gas to call callback_unused_amount method.
+const CALLBACK_UNUSED_AMOUNT_GAS: Gas = Gas(3_000_000_000_000);
+/// Gas to refund the input token, used when swap in ref exchange failed.
+const REFUND_GAS: Gas = Gas(FT_TRANSFER_GAS.0 + CALLBACK_CHECK_TRANSFER_GAS.0);
 /// Gas to call callback_get_amount_out method, not include gas used in cross contract call.
 const CALLBACK_GET_AMOUNT_OUT_GAS: Gas = Gas(10_000_000_000_000);
+/// Gas to call callback_ref_deposit method, not include gas used in cross contract call.
+const CALLBACK_REF_DEPOSIT_GAS: Gas = Gas(10_000_000_000_000);
+/// Gas to call callback_ref_swap method, not include gas used in cross contract call.
+const CALLBACK_REF_SWAP_GAS: Gas = Gas(10_000_000_000_000);
+/// Gas to call callback_ref_withdraw method, not include gas used in cross contract call.
+const CALLBACK_REF_WITHDRAW_GAS: Gas = Gas(5_000_000_000_000);
+/// Gas to call callback_ref_balance method, not include gas used in cross contract call.
+const CALLBACK_REF_BALANCE_GAS: Gas = Gas(10_000_000_000_000);
+/// Gas to withdraw from ref exchange between two balance queries of the core, which check the
+/// received amount, not include the gas of the final callback.
+const REF_CHECKED_WITHDRAW_GAS: Gas = Gas(FT_BALANCE_OF_GAS.0
+    + CALLBACK_REF_BALANCE_GAS.0
+    + REF_WITHDRAW_GAS.0
+    + FT_BALANCE_OF_GAS.0);
+/// Gas to call callback_ref_refund method.
+const CALLBACK_REF_REFUND_GAS: Gas = Gas(5_000_000_000_000 + REFUND_GAS.0);
 /// Gas to call callback_transfer_to_target_account method.
 const CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_IN_GAS: Gas =
     Gas(14_000_000_000_000 + NEAR_WITHDRAW_GAS.0 + CALLBACK_TRANSFER_NEAR_GAS.0);
 const CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_OUT_GAS: Gas =
-    Gas(10_000_000_000_000 + FT_TRANSFER_CALL_MOS_GAS.0 + CALLBACK_RETURN_VALUE_GAS.0);
+    Gas(10_000_000_000_000 + FT_TRANSFER_CALL_MOS_GAS.0 + CALLBACK_TRANSFER_CALL_GAS.0);
+/// Gas to call callback_transfer_to_target_account method when the output is delivered with
+/// ft_transfer_call, not include the gas of ft_on_transfer of the target account.
+const CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_CALL_GAS: Gas =
+    Gas(10_000_000_000_000 + FT_TRANSFER_CALL_TARGET_GAS.0 + CALLBACK_TARGET_CALL_GAS.0);
+/// Gas to call callback_transfer_to_target_account method when the output is delivered by a hook,
+/// not include the gas of the hook.
+const CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_HOOK_GAS: Gas =
+    Gas(10_000_000_000_000 + NEAR_WITHDRAW_GAS.0 + CALLBACK_CALL_HOOK_GAS.0);
+/// Gas to call callback_call_hook method, not include the gas of the hook.
+const CALLBACK_CALL_HOOK_GAS: Gas =
+    Gas(5_000_000_000_000 + FT_TRANSFER_CALL_TARGET_GAS.0 + CALLBACK_HOOK_GAS.0);
+/// Gas to call callback_hook method, which may deliver the output not used by the hook.
+const CALLBACK_HOOK_GAS: Gas =
+    Gas(5_000_000_000_000 + FT_TRANSFER_GAS.0 + CALLBACK_CHECK_TRANSFER_GAS.0);
+/// Gas to call callback_transfer_call method, which may redirect the amount refunded by MOS.
+const CALLBACK_TRANSFER_CALL_GAS: Gas =
+    Gas(5_000_000_000_000 + REDIRECT_DELIVERY_GAS.0 + CALLBACK_RETURN_VALUE_GAS.0);
+/// Gas to call callback_target_call method, which may deliver the amount refunded by the target
+/// account to it.
+const CALLBACK_TARGET_CALL_GAS: Gas =
+    Gas(5_000_000_000_000 + FT_TRANSFER_GAS.0 + CALLBACK_TARGET_REFUND_GAS.0);
+/// Gas to call callback_target_refund method, which may redirect the refund of the target call.
+const CALLBACK_TARGET_REFUND_GAS: Gas = Gas(5_000_000_000_000 + REDIRECT_DELIVERY_GAS.0);
+/// Gas to call callback_transfer_to_target_account method when the output is refunded.
+const CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_REFUND_GAS: Gas = Gas(10_000_000_000_000 + REFUND_GAS.0);
+/// Gas to call callback_check_target method, not include the gas of the swap, which is more
+/// than the gas of the refund.
+const CALLBACK_CHECK_TARGET_GAS: Gas = Gas(10_000_000_000_000);
 /// Gas to call callback_check_transfer method.
 const CALLBACK_CHECK_TRANSFER_GAS: Gas = Gas(8_000_000_000_000 + FT_TRANSFER_GAS.0);
 
 const CALLBACK_TRANSFER_NEAR_GAS: Gas = Gas(8_000_000_000_000 + CALLBACK_CHECK_TRANSFER_GAS.0);
 
-const GAS_FOR_UPGRADE_SELF_DEPLOY: Gas = Gas(15_000_000_000_000);
+/// Gas to call callback_batch_swap method, not include the gas for each item.
+const CALLBACK_BATCH_SWAP_GAS: Gas = Gas(5_000_000_000_000);
+/// Gas to collect the result of each item in callback_batch_swap method.
+const CALLBACK_BATCH_SWAP_PER_ITEM_GAS: Gas = Gas(1_000_000_000_000);
+/// Gas reserved for creating the promises in batch_swap method.
+const BATCH_SWAP_RESERVED_GAS: Gas = Gas(15_000_000_000_000);
 
-const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+const GAS_FOR_UPGRADE_SELF_DEPLOY: Gas = Gas(15_000_000_000_000);
 
 #[ext_contract(ext_wnear_token)]
 pub trait ExtWNearToken {
@@ -49,6 +154,49 @@ pub trait ExtWNearToken {
     fn near_withdraw(&mut self, amount: U128) -> Promise;
 }
 
+#[ext_contract(ext_ref_exchange)]
+pub trait ExtRefExchange {
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>);
+    fn register_tokens(&mut self, token_ids: Vec<AccountId>);
+    fn swap(&mut self, actions: Vec<Action>, referral_id: Option<AccountId>) -> U128;
+    fn withdraw(&mut self, token_id: AccountId, amount: U128, unregister: Option<bool>) -> Promise;
+}
+
+#[ext_contract(ext_storage)]
+pub trait ExtStorage {
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
+}
+
+#[ext_contract(ext_factory)]
+pub trait ExtFactory {
+    fn get_code_by_hash(&self, code_hash: Base58CryptoHash) -> Base64VecU8;
+}
+
+#[derive(BorshStorageKey, BorshSerialize)]
+enum StorageKey {
+    PendingDeliveries,
+    PendingAmounts,
+    Codes,
+    Proposals,
+    Admins,
+    AdminProposals,
+    Hooks,
+    RelayerFees,
+    RelayerFeeCaps,
+    GasDropCaps,
+    GasDrops,
+}
+
+/// Result of the promise which a callback is called with, the callbacks of the core are called
+/// with one promise except callback_batch_swap.
+fn promise_result() -> PromiseResult {
+    ensure!(
+        env::promise_results_count() == 1,
+        ButterCoreError::InvalidArgument("promise has too many results".to_string())
+    );
+    env::promise_result(0)
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct ButterCore {
@@ -56,22 +204,101 @@ pub struct ButterCore {
     pub ref_exchange: AccountId,
     pub wrapped_token: AccountId,
     pub owner: AccountId,
+    /// The factory which created the core, it can upgrade the core to the code it stores.
+    pub factory: AccountId,
+    pub swap_mode: SwapMode,
+    pub min_out_policy: MinOutPolicy,
+    pub next_order_id: u64,
+    /// Failed deliveries by order id.
+    pub pending_deliveries: UnorderedMap<u64, PendingDelivery>,
+    /// Total amount of the pending deliveries and relayer fees by token, excluded from the swap
+    /// output.
+    pub pending_amounts: LookupMap<AccountId, Balance>,
+    /// Total amount of NEAR of the pending deliveries, which can't be swept.
+    pub pending_native_amount: Balance,
+    pub max_delivery_attempts: u32,
+    /// In nanoseconds.
+    pub delivery_timeout: u64,
+    /// Delay in nanoseconds between staging and applying an upgrade.
+    pub upgrade_delay: u64,
+    pub staged_upgrade: Option<StagedUpgrade>,
+    pub code_hash: Option<Base58CryptoHash>,
+    pub last_upgrade: Option<UpgradeRecord>,
+    /// Staged code by hash, the code of the other versions is pulled from the factory.
+    pub codes: LookupMap<CryptoHash, Vec<u8>>,
+    /// Account which can cancel the proposals of configuration changes.
+    pub guardian: Option<AccountId>,
+    /// Delay of the proposals in nanoseconds.
+    pub timelock_delay: u64,
+    pub next_proposal_id: u64,
+    /// Pending proposals of configuration changes by proposal id.
+    pub proposals: UnorderedMap<u64, Proposal>,
+    /// Multisig admins, who approve the admin actions once the core is its own owner.
+    pub admins: UnorderedSet<AccountId>,
+    /// Number of admin approvals to execute an admin action.
+    pub threshold: u32,
+    pub next_admin_proposal_id: u64,
+    pub admin_proposals: UnorderedMap<u64, AdminProposal>,
+    /// Max gas of the contract methods allowed to be called by hooks.
+    pub hooks: UnorderedMap<(AccountId, String), u64>,
+    /// Relayer fee balances by relayer and token.
+    pub relayer_fees: LookupMap<(AccountId, AccountId), Balance>,
+    /// Max relayer fee of a swap by its output token.
+    pub relayer_fee_caps: LookupMap<AccountId, Balance>,
+    /// Max amount of the output swapped by a gas drop by the output token.
+    pub gas_drop_caps: LookupMap<AccountId, Balance>,
+    /// Max number of gas drops received by an account.
+    pub max_gas_drops: u32,
+    /// Number of gas drops received by account.
+    pub gas_drops: LookupMap<AccountId, u32>,
 }
 
 #[near_bindgen]
 impl ButterCore {
+    /// `code_hash` is the hash of the deployed code, given by the factory so the first upgrade
+    /// can be rolled back.
     #[init]
     pub fn new(
         controller: AccountId,
         ref_exchange: AccountId,
         wrapped_token: AccountId,
         owner: AccountId,
+        code_hash: Option<Base58CryptoHash>,
     ) -> Self {
+        write_state_version();
         Self {
             controller,
             ref_exchange,
             wrapped_token,
             owner,
+            factory: env::predecessor_account_id(),
+            swap_mode: SwapMode::Execute,
+            min_out_policy: MinOutPolicy::RefundInput,
+            next_order_id: 0,
+            pending_deliveries: UnorderedMap::new(StorageKey::PendingDeliveries),
+            pending_amounts: LookupMap::new(StorageKey::PendingAmounts),
+            pending_native_amount: 0,
+            max_delivery_attempts: DEFAULT_MAX_DELIVERY_ATTEMPTS,
+            delivery_timeout: DEFAULT_DELIVERY_TIMEOUT,
+            upgrade_delay: DEFAULT_UPGRADE_DELAY,
+            staged_upgrade: None,
+            code_hash,
+            last_upgrade: None,
+            codes: LookupMap::new(StorageKey::Codes),
+            guardian: None,
+            timelock_delay: DEFAULT_TIMELOCK_DELAY,
+            next_proposal_id: 0,
+            proposals: UnorderedMap::new(StorageKey::Proposals),
+            admins: UnorderedSet::new(StorageKey::Admins),
+            threshold: 0,
+            next_admin_proposal_id: 0,
+            admin_proposals: UnorderedMap::new(StorageKey::AdminProposals),
+            hooks: UnorderedMap::new(StorageKey::Hooks),
+            relayer_fees: LookupMap::new(StorageKey::RelayerFees),
+            relayer_fee_caps: LookupMap::new(StorageKey::RelayerFeeCaps),
+            gas_drop_caps: LookupMap::new(StorageKey::GasDropCaps),
+            max_gas_drops: DEFAULT_MAX_GAS_DROPS,
+            gas_drops: LookupMap::new(StorageKey::GasDrops),
         }
     }
 
@@ -79,379 +306,910 @@ impl ButterCore {
         self.controller.clone()
     }
 
-    pub fn set_controller(&mut self, controller: AccountId) {
-        assert_eq!(
-            self.owner,
-            env::predecessor_account_id(),
-            "unexpected caller"
-        );
-        self.controller = controller;
-    }
-
     pub fn get_ref_exchange(&self) -> AccountId {
         self.ref_exchange.clone()
     }
 
-    pub fn set_ref_exchange(&mut self, ref_exchange: AccountId) {
-        assert_eq!(
-            self.owner,
-            env::predecessor_account_id(),
-            "unexpected caller"
-        );
-        self.ref_exchange = ref_exchange;
-    }
-
     pub fn get_wrapped_token(&self) -> AccountId {
         self.wrapped_token.clone()
     }
 
-    pub fn set_wrapped_token(&mut self, wrapped_token: AccountId) {
-        assert_eq!(
-            self.owner,
-            env::predecessor_account_id(),
-            "unexpected caller"
-        );
-        self.wrapped_token = wrapped_token;
-    }
-
     pub fn get_owner(&self) -> AccountId {
         self.owner.clone()
     }
 
-    pub fn set_owner(&mut self, owner: AccountId) {
-        assert_eq!(
-            self.owner,
-            env::predecessor_account_id(),
-            "unexpected caller"
-        );
-        self.owner = owner;
+    pub fn get_swap_mode(&self) -> SwapMode {
+        self.swap_mode
+    }
+
+    pub fn set_swap_mode(&mut self, swap_mode: SwapMode) {
+        self.assert_owner();
+        self.swap_mode = swap_mode;
+    }
+
+    pub fn get_min_out_policy(&self) -> MinOutPolicy {
+        self.min_out_policy
+    }
+
+    pub fn set_min_out_policy(&mut self, min_out_policy: MinOutPolicy) {
+        self.assert_owner();
+        self.min_out_policy = min_out_policy;
+    }
+
+    /// Register the core account in ref exchange and register `token_ids` in its internal account,
+    /// which is required by `SwapMode::Internal`.
+    /// The attached deposit, except 1 yocto for register_tokens, goes to ref exchange storage_deposit.
+    #[payable]
+    pub fn register_in_ref_exchange(&mut self, token_ids: Vec<AccountId>) -> Promise {
+        self.assert_owner();
+        let deposit = env::attached_deposit();
+        ensure!(deposit > 1, ButterCoreError::NotEnoughDeposit);
+
+        ext_ref_exchange::ext(self.ref_exchange.clone())
+            .with_static_gas(REF_STORAGE_DEPOSIT_GAS)
+            .with_attached_deposit(deposit - 1)
+            .storage_deposit(None, None)
+            .then(
+                ext_ref_exchange::ext(self.ref_exchange.clone())
+                    .with_static_gas(REF_REGISTER_TOKENS_GAS)
+                    .with_attached_deposit(1)
+                    .register_tokens(token_ids),
+            )
+    }
+
+    fn swap_config(&self) -> SwapConfig<'_> {
+        SwapConfig {
+            swap_mode: self.swap_mode,
+            min_out_policy: self.min_out_policy,
+            wrapped_token: &self.wrapped_token,
+        }
     }
 
-    fn do_swap(
+    /// Plan the swap, see `planner::plan_swap`, the hook should be allowed, the relayer fee and
+    /// the gas drop within their caps.
+    fn plan_swap(
         &self,
-        token: AccountId,
+        token_in: Option<&AccountId>,
         amount: U128,
-        token_out: AccountId,
-        token_receiver_msg: TokenReceiverMessage,
-        target_account: AccountId,
-        target_token: Option<AccountId>,
+        core_swap_msg: &CoreSwapMessage,
+    ) -> Result<SwapPlan, ButterCoreError> {
+        let plan = plan_swap(self.swap_config(), token_in, amount, core_swap_msg)?;
+        if let Some(hook) = &core_swap_msg.hook {
+            self.check_hook(hook)?;
+        }
+        if let Some(fee) = &core_swap_msg.relayer_fee {
+            self.check_relayer_fee(&plan.token_out, fee)?;
+        }
+        if let Some(gas_drop) = &core_swap_msg.gas_drop {
+            self.check_gas_drop(&plan.token_out, gas_drop)?;
+        }
+        Ok(plan)
+    }
+
+    fn new_swap_context(
+        &mut self,
+        plan: &SwapPlan,
+        core_swap_msg: CoreSwapMessage,
         direct_call: bool,
-    ) -> Promise {
-        let msg = serde_json::to_string(&token_receiver_msg).unwrap();
+    ) -> SwapContext {
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+        SwapContext {
+            order_id,
+            actions: core_swap_msg.actions,
+            token_in: plan.token_in.clone(),
+            amount_in: plan.amount_in,
+            token_out: plan.token_out.clone(),
+            target_account: core_swap_msg.target_account,
+            target_token: core_swap_msg.target_token,
+            swap_msg: core_swap_msg.swap_msg,
+            target_call: core_swap_msg.target_call,
+            hook: core_swap_msg.hook,
+            relayer_fee: core_swap_msg.relayer_fee,
+            gas_drop: core_swap_msg.gas_drop,
+            direct_call,
+            min_total_out: core_swap_msg.min_total_out,
+            on_failure: core_swap_msg
+                .on_failure
+                .unwrap_or(FailurePolicy::RefundToMos),
+            on_refund: core_swap_msg.on_refund.unwrap_or_default(),
+            reverted_amount_in: None,
+            is_gas_drop: false,
+            balance_before: None,
+        }
+    }
 
-        ext_ft_core::ext(token.clone())
-            .with_static_gas(FT_TRANSFER_CALL_REF_GAS)
+    fn do_swap(&self, plan: &SwapPlan, ctx: SwapContext) -> Promise {
+        match &plan.target_check {
+            Some(target_check) => self.check_target(target_check, plan.swap_mode, ctx),
+            None => self.do_swap_in_mode(plan.swap_mode, ctx),
+        }
+    }
+
+    fn do_swap_in_mode(&self, swap_mode: SwapMode, ctx: SwapContext) -> Promise {
+        match swap_mode {
+            SwapMode::Execute => self.do_swap_execute(ctx),
+            SwapMode::Internal => self.do_swap_internal(ctx),
+        }
+    }
+
+    fn do_swap_execute(&self, ctx: SwapContext) -> Promise {
+        let msg = planner::execute_msg(&ctx.actions);
+        let ref_gas = if ctx.is_gas_drop {
+            FT_TRANSFER_CALL_REF_GAS_DROP_GAS
+        } else {
+            FT_TRANSFER_CALL_REF_GAS
+        };
+
+        ext_ft_core::ext(ctx.token_in.clone())
+            .with_static_gas(ref_gas)
             .with_attached_deposit(1)
-            .ft_transfer_call(self.ref_exchange.clone(), amount, None, msg)
+            .ft_transfer_call(self.ref_exchange.clone(), ctx.amount_in, None, msg)
             .then(
                 Self::ext(env::current_account_id())
-                    .with_static_gas(if target_token.is_none() {
-                        Gas(CALLBACK_GET_AMOUNT_OUT_GAS.0
-                            + FT_BALANCE_OF_GAS.0
-                            + CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_OUT_GAS.0)
-                    } else {
-                        Gas(CALLBACK_GET_AMOUNT_OUT_GAS.0
-                            + FT_BALANCE_OF_GAS.0
-                            + CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_IN_GAS.0)
-                    })
-                    .callback_get_amount_out(
-                        token,
-                        amount,
-                        token_out,
-                        target_account,
-                        target_token,
-                        direct_call,
-                    ),
+                    .with_static_gas(Gas(CALLBACK_GET_AMOUNT_OUT_GAS.0
+                        + FT_BALANCE_OF_GAS.0
+                        + self.transfer_to_target_account_gas(&ctx).0))
+                    .callback_get_amount_out(ctx),
             )
     }
 
-    #[private]
-    pub fn callback_get_amount_out(
+    fn do_swap_internal(&self, ctx: SwapContext) -> Promise {
+        ext_ft_core::ext(ctx.token_in.clone())
+            .with_static_gas(FT_TRANSFER_CALL_REF_DEPOSIT_GAS)
+            .with_attached_deposit(1)
+            .ft_transfer_call(
+                self.ref_exchange.clone(),
+                ctx.amount_in,
+                None,
+                "".to_string(),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(CALLBACK_REF_DEPOSIT_GAS.0
+                        + REF_SWAP_GAS.0
+                        + self.callback_ref_swap_gas(&ctx).0))
+                    .callback_ref_deposit(ctx),
+            )
+    }
+
+    fn transfer_to_target_account_gas(&self, ctx: &SwapContext) -> Gas {
+        planner::transfer_to_target_account_gas(self.swap_config(), ctx)
+    }
+
+    fn callback_ref_swap_gas(&self, ctx: &SwapContext) -> Gas {
+        planner::callback_ref_swap_gas(self.transfer_to_target_account_gas(ctx))
+    }
+
+    /// Get the balance of the core before withdrawing `amount` from ref exchange, see
+    /// `callback_ref_balance`.
+    fn withdraw_from_ref(&self, ctx: SwapContext, amount: U128, refund: bool) -> Promise {
+        let token = if refund {
+            ctx.token_in.clone()
+        } else {
+            ctx.token_out.clone()
+        };
+        let callback_gas = if refund {
+            CALLBACK_REF_REFUND_GAS
+        } else {
+            Gas(CALLBACK_REF_WITHDRAW_GAS.0 + self.transfer_to_target_account_gas(&ctx).0)
+        };
+        ext_ft_core::ext(token)
+            .with_static_gas(FT_BALANCE_OF_GAS)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(CALLBACK_REF_BALANCE_GAS.0
+                        + REF_WITHDRAW_GAS.0
+                        + FT_BALANCE_OF_GAS.0
+                        + callback_gas.0))
+                    .callback_ref_balance(ctx, amount, refund),
+            )
+    }
+
+    /// The token balance of the core returned by ft_balance_of.
+    fn token_balance_result(&self) -> U128 {
+        match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) => serde_json::from_slice::<U128>(&x).ok(),
+            PromiseResult::Failed => None,
+        }
+        .unwrap_or_else(|| {
+            ButterCoreError::TokenCallFailed("get token balance of core".to_string()).panic()
+        })
+    }
+
+    /// Check that the balance of the core has increased by `amount` since `balance`. Otherwise
+    /// the tokens are left in the internal account of the core in ref exchange, the owner gets
+    /// them back by a `RefWithdraw` configuration change.
+    fn assert_received(&self, balance: U128, amount: U128) {
+        let received = self.token_balance_result().0.saturating_sub(balance.0);
+        if received < amount.0 {
+            log!(
+                "received {} of {} withdrawn from ref exchange",
+                received,
+                amount.0
+            );
+            ButterCoreError::RefExchangeFailed(format!(
+                "withdraw of {} from ref exchange",
+                amount.0
+            ))
+            .panic();
+        }
+    }
+
+    /// Give back the input token which is not used by ref exchange.
+    fn refund(&self, ctx: SwapContext, used_amount: U128) -> PromiseOrValue<(U128, U128)> {
+        let amount_in = ctx.reverted_amount_in.unwrap_or(used_amount);
+        let token = ctx.token_in.clone();
+        let amount = U128(ctx.amount_in.0 - used_amount.0);
+        self.give_back(ctx, token, amount, amount_in)
+    }
+
+    /// Give `amount` of `token` back according to the failure policy of the swap.
+    /// When refunding to MOS, the token received by `ft_on_transfer` is left as the first item
+    /// of the result, which `callback_unused_amount` returns as the unused amount of
+    /// `ft_on_transfer`, other tokens are transferred to controller.
+    /// `amount_in` is the used input amount reported to `swap` caller.
+    fn give_back(
         &self,
-        token_in: AccountId,
+        ctx: SwapContext,
+        token: AccountId,
         amount: U128,
-        token_out: AccountId,
-        target_account: AccountId,
-        target_token: Option<AccountId>,
-        direct_call: bool,
+        amount_in: U128,
     ) -> PromiseOrValue<(U128, U128)> {
-        assert_eq!(
-            1,
-            env::promise_results_count(),
-            "promise has too many results"
-        );
+        let amount_in = if ctx.direct_call { amount_in } else { U128(0) };
+        let receiver = match &ctx.on_failure {
+            FailurePolicy::RefundToMos => {
+                if !ctx.direct_call && &token == ctx.original_token_in() {
+                    return PromiseOrValue::Value((amount, U128(0)));
+                }
+                self.controller.clone()
+            }
+            FailurePolicy::DeliverToTarget => ctx.target_account.clone(),
+            FailurePolicy::DeliverToFallback(account) => account.clone(),
+        };
+        ext_ft_core::ext(token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(1)
+            .ft_transfer(receiver.clone(), amount, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_CHECK_TRANSFER_GAS)
+                    .callback_check_transfer(
+                        ctx.order_id,
+                        Delivery {
+                            token,
+                            account_id: receiver,
+                            amount,
+                            is_native: false,
+                            fallback_account: None,
+                            msg: None,
+                        },
+                        (amount_in, U128(0)),
+                    ),
+            )
+            .into()
+    }
 
-        match env::promise_result(0) {
+    #[private]
+    pub fn callback_get_amount_out(&self, ctx: SwapContext) -> PromiseOrValue<(U128, U128)> {
+        match promise_result() {
             PromiseResult::NotReady => env::abort(),
             PromiseResult::Successful(x) => {
-                let used_amount = serde_json::from_slice::<U128>(&x).unwrap();
-                if amount != used_amount {
-                    log!("used amount is unexpected, swap in ref exchange failed, expected: {:?}, actual: {:?}!", amount, used_amount);
-                    if direct_call {
-                        ext_ft_core::ext(token_in)
-                            .with_static_gas(FT_TRANSFER_GAS)
-                            .with_attached_deposit(1)
-                            .ft_transfer(
-                                self.controller.clone(),
-                                U128(amount.0 - used_amount.0),
-                                None,
-                            )
-                            .then(
-                                Self::ext(env::current_account_id())
-                                    .with_static_gas(CALLBACK_RETURN_VALUE_GAS)
-                                    .callback_return_value(used_amount, U128(0)),
-                            )
-                            .into()
-                    } else {
-                        PromiseOrValue::Value((U128(amount.0 - used_amount.0), U128(0)))
+                let used_amount = serde_json::from_slice::<U128>(&x)
+                    .unwrap_or_else(|_| ButterCoreError::UnexpectedSwapResult.panic());
+                if ctx.amount_in != used_amount {
+                    CoreEvent::OrderFailed {
+                        order_id: Some(U64(ctx.order_id)),
+                        error: &ButterCoreError::UnexpectedUsedAmount {
+                            expected: ctx.amount_in,
+                            actual: used_amount,
+                        },
                     }
+                    .emit();
+                    self.refund(ctx, used_amount)
                 } else {
-                    ext_ft_core::ext(token_out.clone())
+                    ext_ft_core::ext(ctx.token_out.clone())
                         .with_static_gas(FT_BALANCE_OF_GAS)
                         .ft_balance_of(env::current_account_id())
                         .then(
                             Self::ext(env::current_account_id())
-                                .with_static_gas(if target_token.is_none() {
-                                    CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_OUT_GAS
-                                } else {
-                                    CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_IN_GAS
-                                })
-                                .callback_transfer_to_target_account(
-                                    token_out,
-                                    target_account,
-                                    target_token,
-                                    amount,
-                                    direct_call,
-                                ),
+                                .with_static_gas(self.transfer_to_target_account_gas(&ctx))
+                                .callback_transfer_to_target_account(ctx),
                         )
                         .into()
                 }
             }
-            PromiseResult::Failed => panic_str("call ref exchange failed"),
+            PromiseResult::Failed => {
+                ButterCoreError::RefExchangeFailed("call ref exchange".to_string()).panic()
+            }
         }
     }
 
     #[private]
-    pub fn callback_return_value(&self, amount_in: U128, amount_out: U128) -> (U128, U128) {
-        (amount_in, amount_out)
-    }
-
-    #[private]
-    pub fn callback_transfer_to_target_account(
-        &self,
-        token_out: AccountId,
-        target_account: AccountId,
-        target_token_opt: Option<AccountId>,
-        amount_in: U128,
-        direct_call: bool,
-    ) -> PromiseOrValue<(U128, U128)> {
-        assert_eq!(
-            1,
-            env::promise_results_count(),
-            "promise has too many results"
-        );
-
-        match env::promise_result(0) {
+    pub fn callback_ref_deposit(&self, ctx: SwapContext) -> PromiseOrValue<(U128, U128)> {
+        match promise_result() {
             PromiseResult::NotReady => env::abort(),
             PromiseResult::Successful(x) => {
-                let amount_out = serde_json::from_slice::<U128>(&x).unwrap();
-                if amount_out.0 == 0 {
-                    log!("!!!caution: amount out should not be zero!!!");
-                    return PromiseOrValue::Value(if direct_call {
-                        (amount_in, U128(0))
-                    } else {
-                        (U128(0), U128(0))
-                    });
-                }
-                if let Some(target_token) = target_token_opt {
-                    // swap in
-                    // native token
-                    if self.wrapped_token == token_out && target_token.to_string().eq(ZERO_ADDRESS)
-                    {
-                        // near_withdraw() won't fail because the core account has been registered and it has a positive "amount_out" token
-                        ext_wnear_token::ext(self.wrapped_token.clone())
-                            .with_static_gas(NEAR_WITHDRAW_GAS)
-                            .with_attached_deposit(1)
-                            .near_withdraw(amount_out)
-                            .then(
-                                Self::ext(env::current_account_id())
-                                    .with_static_gas(CALLBACK_TRANSFER_NEAR_GAS)
-                                    .callback_transfer_near(
-                                        token_out,
-                                        target_account,
-                                        amount_in,
-                                        amount_out,
-                                    ),
-                            )
-                            .into()
-                    } else {
-                        ext_ft_core::ext(token_out.clone())
-                            .with_static_gas(FT_TRANSFER_GAS)
-                            .with_attached_deposit(1)
-                            .ft_transfer(target_account.clone(), amount_out, None)
-                            .then(
-                                Self::ext(env::current_account_id())
-                                    .with_static_gas(CALLBACK_CHECK_TRANSFER_GAS)
-                                    .callback_check_transfer(
-                                        token_out,
-                                        target_account,
-                                        amount_in,
-                                        amount_out,
-                                        false,
-                                    ),
-                            )
-                            .into()
+                let used_amount = serde_json::from_slice::<U128>(&x)
+                    .unwrap_or_else(|_| ButterCoreError::UnexpectedSwapResult.panic());
+                if ctx.amount_in != used_amount {
+                    CoreEvent::OrderFailed {
+                        order_id: Some(U64(ctx.order_id)),
+                        error: &ButterCoreError::UnexpectedUsedAmount {
+                            expected: ctx.amount_in,
+                            actual: used_amount,
+                        },
                     }
+                    .emit();
+                    self.refund(ctx, used_amount)
                 } else {
-                    // swap out
-                    // // always succeed because we give enough gas and MOS has been registered in token_out
-                    ext_ft_core::ext(token_out)
-                        .with_static_gas(FT_TRANSFER_CALL_MOS_GAS)
+                    ext_ref_exchange::ext(self.ref_exchange.clone())
+                        .with_static_gas(REF_SWAP_GAS)
                         .with_attached_deposit(1)
-                        .ft_transfer_call(target_account, amount_out, None, "".to_string())
+                        .swap(ctx.actions.clone(), None)
                         .then(
                             Self::ext(env::current_account_id())
-                                .with_static_gas(CALLBACK_RETURN_VALUE_GAS)
-                                .callback_return_value(
-                                    if direct_call { amount_in } else { U128(0) },
-                                    amount_out,
-                                ),
+                                .with_static_gas(self.callback_ref_swap_gas(&ctx))
+                                .callback_ref_swap(ctx),
                         )
                         .into()
                 }
             }
-            // actually get balance won't fail if we give enough gas
-            PromiseResult::Failed => panic_str("get token_out balance of core failed"),
+            PromiseResult::Failed => {
+                ButterCoreError::RefExchangeFailed("deposit to ref exchange".to_string()).panic()
+            }
         }
     }
 
     #[private]
-    pub fn callback_transfer_near(
-        &self,
-        token_out: AccountId,
-        target_account: AccountId,
-        amount_in: U128,
-        amount_out: U128,
-    ) -> Promise {
-        Promise::new(target_account.clone())
-            .transfer(Balance::from(amount_out))
+    pub fn callback_ref_swap(&self, ctx: SwapContext) -> Promise {
+        match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) => {
+                let amount_out = serde_json::from_slice::<U128>(&x)
+                    .unwrap_or_else(|_| ButterCoreError::UnexpectedSwapResult.panic());
+                self.withdraw_from_ref(ctx, amount_out, false)
+            }
+            PromiseResult::Failed => {
+                CoreEvent::OrderFailed {
+                    order_id: Some(U64(ctx.order_id)),
+                    error: &ButterCoreError::RefExchangeFailed("swap in ref exchange".to_string()),
+                }
+                .emit();
+                let amount_in = ctx.amount_in;
+                self.withdraw_from_ref(ctx, amount_in, true)
+            }
+        }
+    }
+
+    /// Withdraw `amount` of the output, or of the input if `refund`, from ref exchange, with the
+    /// balance of the core before the withdraw.
+    #[private]
+    pub fn callback_ref_balance(&self, ctx: SwapContext, amount: U128, refund: bool) -> Promise {
+        let balance = self.token_balance_result();
+        let token = if refund {
+            ctx.token_in.clone()
+        } else {
+            ctx.token_out.clone()
+        };
+        let callback = if refund {
+            Self::ext(env::current_account_id())
+                .with_static_gas(CALLBACK_REF_REFUND_GAS)
+                .callback_ref_refund(ctx, balance)
+        } else {
+            Self::ext(env::current_account_id())
+                .with_static_gas(Gas(
+                    CALLBACK_REF_WITHDRAW_GAS.0 + self.transfer_to_target_account_gas(&ctx).0
+                ))
+                .callback_ref_withdraw(ctx, amount, balance)
+        };
+        ext_ref_exchange::ext(self.ref_exchange.clone())
+            .with_static_gas(REF_WITHDRAW_GAS)
+            .with_attached_deposit(1)
+            .withdraw(token.clone(), amount, None)
             .then(
-                Self::ext(env::current_account_id())
-                    .with_static_gas(CALLBACK_CHECK_TRANSFER_GAS)
-                    .callback_check_transfer(
-                        token_out,
-                        target_account,
-                        amount_in,
-                        amount_out,
-                        true,
-                    ),
+                ext_ft_core::ext(token)
+                    .with_static_gas(FT_BALANCE_OF_GAS)
+                    .ft_balance_of(env::current_account_id()),
             )
+            .then(callback)
+    }
+
+    /// Deliver the output withdrawn from ref exchange. Ref exchange keeps the tokens in the
+    /// internal account of the core if their transfer fails, so the output is only delivered if
+    /// the balance of the core has increased by `amount_out` since `balance`.
+    #[private]
+    pub fn callback_ref_withdraw(
+        &mut self,
+        ctx: SwapContext,
+        amount_out: U128,
+        balance: U128,
+    ) -> PromiseOrValue<(U128, U128)> {
+        self.assert_received(balance, amount_out);
+        self.transfer_to_target_account(ctx, amount_out)
     }
 
+    /// Refund the input withdrawn from ref exchange, which is checked as in
+    /// `callback_ref_withdraw`.
     #[private]
-    pub fn callback_check_transfer(
+    pub fn callback_ref_refund(
         &self,
-        token: AccountId,
-        account: AccountId,
-        amount_in: U128,
-        amount: U128,
-        is_native: bool,
-    ) -> (U128, U128) {
-        assert_eq!(
-            1,
-            env::promise_results_count(),
-            "promise has too many results"
-        );
+        ctx: SwapContext,
+        balance: U128,
+    ) -> PromiseOrValue<(U128, U128)> {
+        self.assert_received(balance, ctx.amount_in);
+        self.refund(ctx, U128(0))
+    }
+
+    #[private]
+    pub fn callback_return_value(&self, amount_in: U128, amount_out: U128) -> (U128, U128) {
+        (amount_in, amount_out)
+    }
 
-        match env::promise_result(0) {
+    /// Convert the result of a swap started by `ft_on_transfer` to its unused amount, which is
+    /// the first item of the result. The tokens are taken as used if the swap failed, they are
+    /// kept in the core rather than refunding the tokens reserved for others.
+    #[private]
+    pub fn callback_unused_amount(&self) -> U128 {
+        match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) => serde_json::from_slice::<(U128, U128)>(&x)
+                .map(|(unused_amount, _)| unused_amount)
+                .unwrap_or(U128(0)),
+            PromiseResult::Failed => U128(0),
+        }
+    }
+
+    #[private]
+    pub fn callback_transfer_to_target_account(
+        &mut self,
+        ctx: SwapContext,
+    ) -> PromiseOrValue<(U128, U128)> {
+        match promise_result() {
             PromiseResult::NotReady => env::abort(),
-            PromiseResult::Successful(_x) => {}
+            PromiseResult::Successful(x) => {
+                let balance = serde_json::from_slice::<U128>(&x).unwrap_or_else(|_| {
+                    ButterCoreError::TokenCallFailed("get token_out balance of core".to_string())
+                        .panic()
+                });
+                let amount_out = match ctx.balance_before {
+                    Some(balance_before) => U128(balance.0.saturating_sub(balance_before.0)),
+                    None => {
+                        let pending_amount = self.pending_amounts.get(&ctx.token_out).unwrap_or(0);
+                        U128(balance.0.saturating_sub(pending_amount))
+                    }
+                };
+                self.transfer_to_target_account(ctx, amount_out)
+            }
+            // actually get balance won't fail if we give enough gas
             PromiseResult::Failed => {
-                // if transfer to user failed, transfer to mos
-                if is_native {
-                    // ext_wnear_token::ext(self.wrapped_token.clone())
-                    //     .with_static_gas(NEAR_DEPOSIT_GAS)
-                    //     .with_attached_deposit(amount.0)
-                    //     .near_deposit()
-                    //     .then(
-                    //         ext_ft_core::ext(token)
-                    //             .with_static_gas(FT_TRANSFER_GAS)
-                    //             .with_attached_deposit(1)
-                    //             .ft_transfer(self.controller.clone(), amount, Some(memo)),
-                    //     );
-                    log!(format!(
-                        "transfer NEAR to user {} failed, transfer to mos instead",
-                        account
-                    ));
-                    Promise::new(self.controller.clone()).transfer(amount.0);
-                } else {
-                    let memo = format!(
-                        "transfer {} to user {} failed, transfer to mos instead",
-                        token, account
-                    );
-                    ext_ft_core::ext(token)
-                        .with_static_gas(FT_TRANSFER_GAS)
-                        .with_attached_deposit(1)
-                        .ft_transfer(self.controller.clone(), amount, Some(memo));
-                }
+                ButterCoreError::TokenCallFailed("get token_out balance of core".to_string())
+                    .panic()
+            }
+        }
+    }
+
+    fn transfer_to_target_account(
+        &mut self,
+        ctx: SwapContext,
+        amount_out: U128,
+    ) -> PromiseOrValue<(U128, U128)> {
+        if amount_out.0 == 0 {
+            log!("!!!caution: amount out should not be zero!!!");
+            return PromiseOrValue::Value(if ctx.direct_call {
+                (ctx.reverted_amount_in.unwrap_or(ctx.amount_in), U128(0))
+            } else {
+                (U128(0), U128(0))
+            });
+        }
+        if let Some(reverted_amount_in) = ctx.reverted_amount_in {
+            return self.refund_reverted(ctx, reverted_amount_in, amount_out);
+        }
+        if ctx.is_gas_drop {
+            self.record_gas_drop(&ctx);
+        }
+        if let Some(min_total_out) = ctx.min_total_out {
+            let deductions =
+                ctx.relayer_fee.as_ref().map_or(0, |fee| fee.amount.0) + self.gas_drop_amount(&ctx);
+            if amount_out.0.saturating_sub(deductions) < min_total_out.0 {
+                log!(
+                    "amount out {:?} is less than min total out {:?}, policy: {:?}",
+                    amount_out,
+                    min_total_out,
+                    self.min_out_policy
+                );
+                return self.handle_min_total_out_not_met(ctx, amount_out);
+            }
+        }
+        let amount_out = self.charge_relayer_fee(&ctx, amount_out);
+        let amount_out = self.drop_gas(&ctx, amount_out);
+        let delivery = planner::delivery_mode(
+            self.swap_config(),
+            &ctx.token_out,
+            ctx.target_token.as_ref(),
+            ctx.target_call.as_ref(),
+            ctx.hook.as_ref(),
+        );
+        let fallback_account = ctx.fallback_account();
+        let token_out = ctx.token_out;
+        let target_account = ctx.target_account;
+        let amount_in = ctx.amount_in;
+        let result = (
+            if ctx.direct_call { amount_in } else { U128(0) },
+            amount_out,
+        );
+        match delivery {
+            DeliveryMode::Native => {
+                // near_withdraw() won't fail because the core account has been registered and it has a positive "amount_out" token
+                ext_wnear_token::ext(self.wrapped_token.clone())
+                    .with_static_gas(NEAR_WITHDRAW_GAS)
+                    .with_attached_deposit(1)
+                    .near_withdraw(amount_out)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(CALLBACK_TRANSFER_NEAR_GAS)
+                            .callback_transfer_near(
+                                ctx.order_id,
+                                Delivery {
+                                    token: token_out,
+                                    account_id: target_account,
+                                    amount: amount_out,
+                                    is_native: true,
+                                    fallback_account,
+                                    msg: None,
+                                },
+                                result,
+                            ),
+                    )
+                    .into()
+            }
+            DeliveryMode::Transfer => ext_ft_core::ext(token_out.clone())
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(1)
+                .ft_transfer(target_account.clone(), amount_out, None)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALLBACK_CHECK_TRANSFER_GAS)
+                        .callback_check_transfer(
+                            ctx.order_id,
+                            Delivery {
+                                token: token_out,
+                                account_id: target_account,
+                                amount: amount_out,
+                                is_native: false,
+                                fallback_account,
+                                msg: None,
+                            },
+                            result,
+                        ),
+                )
+                .into(),
+            // the output not used by the hook is delivered to the target account by callback_hook
+            DeliveryMode::Hook { native: true, gas } => {
+                ext_wnear_token::ext(self.wrapped_token.clone())
+                    .with_static_gas(NEAR_WITHDRAW_GAS)
+                    .with_attached_deposit(1)
+                    .near_withdraw(amount_out)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(Gas(CALLBACK_CALL_HOOK_GAS.0 + gas.0))
+                            .callback_call_hook(
+                                ctx.order_id,
+                                ctx.hook.unwrap(),
+                                Delivery {
+                                    token: token_out,
+                                    account_id: target_account,
+                                    amount: amount_out,
+                                    is_native: true,
+                                    fallback_account,
+                                    msg: None,
+                                },
+                                result,
+                            ),
+                    )
+                    .into()
+            }
+            DeliveryMode::Hook { native: false, gas } => {
+                let hook = ctx.hook.unwrap();
+                ext_ft_core::ext(token_out.clone())
+                    .with_static_gas(Gas(FT_TRANSFER_CALL_TARGET_GAS.0 + gas.0))
+                    .with_attached_deposit(1)
+                    .ft_transfer_call(hook.contract_id, amount_out, None, hook.args)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(CALLBACK_HOOK_GAS)
+                            .callback_hook(
+                                ctx.order_id,
+                                Delivery {
+                                    token: token_out,
+                                    account_id: target_account,
+                                    amount: amount_out,
+                                    is_native: false,
+                                    fallback_account,
+                                    msg: None,
+                                },
+                                result,
+                            ),
+                    )
+                    .into()
+            }
+            // the target account may use a part of the output and refund the rest, which is
+            // checked by callback_target_call
+            DeliveryMode::Call(gas) => ext_ft_core::ext(token_out.clone())
+                .with_static_gas(Gas(FT_TRANSFER_CALL_TARGET_GAS.0 + gas.0))
+                .with_attached_deposit(1)
+                .ft_transfer_call(
+                    target_account.clone(),
+                    amount_out,
+                    None,
+                    ctx.target_call
+                        .map(|target_call| target_call.msg)
+                        .unwrap_or_default(),
+                )
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALLBACK_TARGET_CALL_GAS)
+                        .callback_target_call(
+                            ctx.order_id,
+                            Delivery {
+                                token: token_out,
+                                account_id: target_account,
+                                amount: amount_out,
+                                is_native: false,
+                                fallback_account,
+                                msg: None,
+                            },
+                            result,
+                        ),
+                )
+                .into(),
+            // MOS may reject the swap out and refund a part of it, which is checked by
+            // callback_transfer_call
+            DeliveryMode::SwapOut => {
+                let msg = planner::swap_out_msg(ctx.swap_msg.as_ref());
+                ext_ft_core::ext(token_out.clone())
+                    .with_static_gas(FT_TRANSFER_CALL_MOS_GAS)
+                    .with_attached_deposit(1)
+                    .ft_transfer_call(target_account.clone(), amount_out, None, msg.clone())
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(CALLBACK_TRANSFER_CALL_GAS)
+                            .callback_transfer_call(
+                                ctx.order_id,
+                                Delivery {
+                                    token: token_out,
+                                    account_id: target_account,
+                                    amount: amount_out,
+                                    is_native: false,
+                                    fallback_account,
+                                    msg: Some(msg),
+                                },
+                                ctx.on_refund,
+                                result,
+                            ),
+                    )
+                    .into()
+            }
+        }
+    }
+
+    fn handle_min_total_out_not_met(
+        &self,
+        ctx: SwapContext,
+        amount_out: U128,
+    ) -> PromiseOrValue<(U128, U128)> {
+        match self.min_out_policy {
+            MinOutPolicy::RefundInput => self
+                .do_swap_in_mode(self.swap_mode, ctx.reverse(amount_out))
+                .into(),
+            MinOutPolicy::RefundOutput => {
+                let token = ctx.token_out.clone();
+                let amount_in = ctx.amount_in;
+                self.give_back(ctx, token, amount_out, amount_in)
             }
         }
-        (amount_in, amount)
+    }
+
+    /// Give back the input token recovered by a reverse swap.
+    fn refund_reverted(
+        &self,
+        ctx: SwapContext,
+        reverted_amount_in: U128,
+        recovered: U128,
+    ) -> PromiseOrValue<(U128, U128)> {
+        let token = ctx.token_out.clone();
+        let amount_in = U128(reverted_amount_in.0.saturating_sub(recovered.0));
+        self.give_back(ctx, token, recovered, amount_in)
+    }
+
+    #[private]
+    pub fn callback_transfer_near(
+        &self,
+        order_id: u64,
+        delivery: Delivery,
+        result: (U128, U128),
+    ) -> Promise {
+        Promise::new(delivery.account_id.clone())
+            .transfer(Balance::from(delivery.amount))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_CHECK_TRANSFER_GAS)
+                    .callback_check_transfer(order_id, delivery, result),
+            )
     }
 
     pub fn swap(&mut self, amount: U128, core_swap_msg: CoreSwapMessage) -> PromiseOrValue<U128> {
-        assert_eq!(
-            self.controller,
-            env::predecessor_account_id(),
-            "unexpected caller, caller should be {}",
-            self.controller
+        self.assert_controller();
+
+        // the core can't tell which tokens are sent by MOS for the swap, so an invalid swap is
+        // rejected before any token is moved and MOS handles the failure
+        let plan = self
+            .plan_swap(None, amount, &core_swap_msg)
+            .unwrap_or_else(|e| e.panic());
+        let ctx = self.new_swap_context(&plan, core_swap_msg, true);
+
+        PromiseOrValue::from(self.do_swap(&plan, ctx))
+    }
+
+    /// Do independent swaps in one transaction, each item is `(amount, core_swap_msg)` as the
+    /// arguments of `swap`. Items which are invalid are not swapped and get an error in the
+    /// result, the failure of one swap doesn't affect the others. The whole batch fails with
+    /// `NotEnoughGas` if the prepaid gas doesn't cover all the valid items, and in the execute
+    /// mode with `InvalidArgument` if two valid items have the same output token, counting the
+    /// wrapped NEAR of a gas drop.
+    pub fn batch_swap(
+        &mut self,
+        swaps: Vec<(U128, CoreSwapMessage)>,
+    ) -> PromiseOrValue<Vec<BatchSwapResult>> {
+        self.assert_controller();
+
+        let callback_gas = Gas(
+            CALLBACK_BATCH_SWAP_GAS.0 + CALLBACK_BATCH_SWAP_PER_ITEM_GAS.0 * swaps.len() as u64
         );
+        let plans: Vec<Result<SwapPlan, ButterCoreError>> = swaps
+            .iter()
+            .map(|(amount, core_swap_msg)| self.plan_swap(None, *amount, core_swap_msg))
+            .collect();
+        let swap_gas: u64 = plans
+            .iter()
+            .filter_map(|plan| plan.as_ref().ok())
+            .map(|plan| plan.gas.0)
+            .sum();
+        ensure!(
+            swap_gas + callback_gas.0 + BATCH_SWAP_RESERVED_GAS.0
+                <= (env::prepaid_gas() - env::used_gas()).0,
+            ButterCoreError::NotEnoughGas
+        );
+        // the execute mode measures the output by the balance of the core, which the swaps of a
+        // batch with the same output token would take from each other
+        let mut tokens_out: Vec<&AccountId> = vec![];
+        for (plan, (_, core_swap_msg)) in plans.iter().zip(&swaps) {
+            let plan = match plan {
+                Ok(plan) if plan.swap_mode == SwapMode::Execute => plan,
+                _ => continue,
+            };
+            let mut touched = vec![&plan.token_out];
+            if core_swap_msg.gas_drop.is_some() {
+                touched.push(&self.wrapped_token);
+            }
+            for token in touched {
+                ensure!(
+                    !tokens_out.contains(&token),
+                    ButterCoreError::InvalidArgument(format!(
+                        "token out {} is repeated in the batch",
+                        token
+                    ))
+                );
+                tokens_out.push(token);
+            }
+        }
 
-        let Action::Swap(first_swap_action) = core_swap_msg.actions.first().unwrap().clone();
-        let Action::Swap(last_swap_action) = core_swap_msg.actions.last().unwrap().clone();
-        let token_receiver_msg = TokenReceiverMessage::Execute {
-            referral_id: None,
-            actions: core_swap_msg.actions,
-        };
+        let mut errors: Vec<Option<ButterCoreError>> = Vec::with_capacity(swaps.len());
+        let mut promise: Option<Promise> = None;
+        for (plan, (_, core_swap_msg)) in plans.into_iter().zip(swaps) {
+            let plan = match plan {
+                Ok(plan) => plan,
+                Err(e) => {
+                    errors.push(Some(e));
+                    continue;
+                }
+            };
+            errors.push(None);
+            let ctx = self.new_swap_context(&plan, core_swap_msg, true);
+            let swap_promise = self.do_swap(&plan, ctx);
+            promise = Some(match promise {
+                Some(p) => p.and(swap_promise),
+                None => swap_promise,
+            });
+        }
 
-        PromiseOrValue::from(self.do_swap(
-            first_swap_action.token_in,
-            amount,
-            last_swap_action.token_out,
-            token_receiver_msg,
-            core_swap_msg.target_account,
-            core_swap_msg.target_token,
-            true,
-        ))
+        match promise {
+            Some(p) => p
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(callback_gas)
+                        .callback_batch_swap(errors),
+                )
+                .into(),
+            None => PromiseOrValue::Value(
+                errors
+                    .into_iter()
+                    .map(|error| BatchSwapResult::new(U128(0), U128(0), error))
+                    .collect(),
+            ),
+        }
     }
 
-    pub fn upgrade_self(&mut self, code: Base64VecU8) {
-        assert!(
+    /// Collect the results of `batch_swap`, `errors` has an item for each swap, which is none if
+    /// the swap has been started.
+    #[private]
+    pub fn callback_batch_swap(
+        &self,
+        errors: Vec<Option<ButterCoreError>>,
+    ) -> Vec<BatchSwapResult> {
+        let mut promise_index = 0;
+        errors
+            .into_iter()
+            .map(|error| {
+                if error.is_some() {
+                    return BatchSwapResult::new(U128(0), U128(0), error);
+                }
+                let result = env::promise_result(promise_index);
+                promise_index += 1;
+                match result {
+                    PromiseResult::Successful(x) => {
+                        match serde_json::from_slice::<(U128, U128)>(&x) {
+                            Ok((amount_in, amount_out)) => {
+                                BatchSwapResult::new(amount_in, amount_out, None)
+                            }
+                            Err(_) => BatchSwapResult::new(
+                                U128(0),
+                                U128(0),
+                                Some(ButterCoreError::UnexpectedSwapResult),
+                            ),
+                        }
+                    }
+                    _ => BatchSwapResult::new(U128(0), U128(0), Some(ButterCoreError::SwapFailed)),
+                }
+            })
+            .collect()
+    }
+
+    fn is_owner(&self) -> bool {
+        env::predecessor_account_id() == self.owner
+    }
+
+    fn assert_owner(&self) {
+        ensure!(
             self.is_owner(),
-            "unexpected caller {}",
-            env::predecessor_account_id()
+            ButterCoreError::Unauthorized(env::predecessor_account_id())
         );
+    }
 
-        let current_id = env::current_account_id();
-        let promise_id = env::promise_batch_create(&current_id);
-        env::promise_batch_action_deploy_contract(promise_id, &code.0);
-        env::promise_batch_action_function_call(
-            promise_id,
-            "migrate",
-            &[],
-            0,
-            env::prepaid_gas() - env::used_gas() - GAS_FOR_UPGRADE_SELF_DEPLOY,
+    fn assert_controller(&self) {
+        ensure!(
+            env::predecessor_account_id() == self.controller,
+            ButterCoreError::Unauthorized(env::predecessor_account_id())
         );
     }
 
-    fn is_owner(&self) -> bool {
-        env::predecessor_account_id() == self.owner
+    /// Version of the state layout.
+    pub fn get_version(&self) -> u32 {
+        read_state_version()
     }
 
+    /// Migrate the state to the current version. The args are `{"factory": "<account id>"}`,
+    /// the factory which created the core, it is only used if the state does not record it yet.
+    /// The cores deployed before the state versioning call migrate with empty args, see
+    /// `migration::migrate_factory`, so the args are read by the method itself.
     #[private]
     #[init(ignore_state)]
     pub fn migrate() -> Self {
-        let core: ButterCore = env::state_read().expect("ERR_CONTRACT_IS_NOT_INITIALIZED");
+        let state = env::storage_read(b"STATE").expect("ERR_CONTRACT_IS_NOT_INITIALIZED");
+        let core = migrate_state(read_state_version(), &state, migrate_factory());
+        write_state_version();
         core
     }
 }
@@ -464,30 +1222,387 @@ impl FungibleTokenReceiver for ButterCore {
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
-        assert_eq!(
-            self.controller, sender_id,
-            "unexpected caller, caller should be {}",
-            self.controller
+        ensure!(
+            self.controller == sender_id,
+            ButterCoreError::Unauthorized(sender_id)
         );
 
-        let core_swap_msg =
-            serde_json::from_str::<CoreSwapMessage>(&msg).expect("unexpected core swap msg format");
+        // return all the tokens received as unused if the message is invalid, they are refunded
+        // to MOS
+        let core_swap_msg = match serde_json::from_str::<CoreSwapMessage>(&msg) {
+            Ok(core_swap_msg) => core_swap_msg,
+            Err(e) => {
+                CoreEvent::OrderFailed {
+                    order_id: None,
+                    error: &ButterCoreError::InvalidSwapMsg(e.to_string()),
+                }
+                .emit();
+                return PromiseOrValue::Value(amount);
+            }
+        };
         let token = env::predecessor_account_id();
+        let plan = match self.plan_swap(Some(&token), amount, &core_swap_msg) {
+            Ok(plan) => plan,
+            Err(e) => {
+                CoreEvent::OrderFailed {
+                    order_id: None,
+                    error: &e,
+                }
+                .emit();
+                return PromiseOrValue::Value(amount);
+            }
+        };
+        let ctx = self.new_swap_context(&plan, core_swap_msg, false);
 
-        let Action::Swap(swap_action) = core_swap_msg.actions.last().unwrap().clone();
-        let token_receiver_msg = TokenReceiverMessage::Execute {
-            referral_id: None,
-            actions: core_swap_msg.actions,
+        self.do_swap(&plan, ctx)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_UNUSED_AMOUNT_GAS)
+                    .callback_unused_amount(),
+            )
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{DeliveryAsset, GasDrop, SwapAction};
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig, VMContext};
+
+    const CORE: &str = "core0.corefac.near";
+    const MOS: &str = "mos.near";
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    fn context(predecessor: &str) -> VMContext {
+        VMContextBuilder::new()
+            .current_account_id(account(CORE))
+            .predecessor_account_id(account(predecessor))
+            .prepaid_gas(Gas(300_000_000_000_000))
+            .build()
+    }
+
+    /// Set the results of the promises the next callback depends on.
+    fn promise_results(results: Vec<PromiseResult>) {
+        testing_env!(
+            context(CORE),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            results,
+        );
+    }
+
+    fn setup() -> ButterCore {
+        testing_env!(context(MOS));
+        ButterCore::new(
+            account(MOS),
+            account("v2.ref-finance.near"),
+            account("wrap.near"),
+            account("owner.near"),
+            None,
+        )
+    }
+
+    /// Message swapping `amount` of USDC to ETH for alice.
+    fn swap_msg(amount: u128) -> CoreSwapMessage {
+        CoreSwapMessage {
+            actions: vec![Action::Swap(SwapAction {
+                pool_id: 0,
+                token_in: account("usdc.near"),
+                amount_in: Some(U128(amount)),
+                token_out: account("eth.near"),
+                min_amount_out: U128(0),
+            })],
+            target_account: account("alice.near"),
+            target_token: Some(DeliveryAsset::Token(account("eth.near"))),
+            swap_msg: None,
+            target_call: None,
+            hook: None,
+            relayer_fee: None,
+            gas_drop: None,
+            check_target: None,
+            min_total_out: None,
+            on_failure: None,
+            on_refund: None,
+        }
+    }
+
+    fn swap_context(core: &mut ButterCore, amount: u128) -> SwapContext {
+        let core_swap_msg = swap_msg(amount);
+        let plan = core.plan_swap(None, U128(amount), &core_swap_msg).unwrap();
+        core.new_swap_context(&plan, core_swap_msg, true)
+    }
+
+    fn balance(amount: u128) -> PromiseResult {
+        PromiseResult::Successful(serde_json::to_vec(&U128(amount)).unwrap())
+    }
+
+    #[test]
+    fn test_ref_withdraw() {
+        let mut core = setup();
+        let ctx = swap_context(&mut core, 100);
+
+        promise_results(vec![balance(250)]);
+        core.callback_ref_withdraw(ctx, U128(200), U128(50));
+        assert_eq!(get_created_receipts().len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "E020: withdraw of 200 from ref exchange failed")]
+    fn test_ref_withdraw_not_received() {
+        let mut core = setup();
+        let ctx = swap_context(&mut core, 100);
+
+        // ref exchange keeps the output in the internal account if its transfer failed
+        promise_results(vec![balance(50)]);
+        core.callback_ref_withdraw(ctx, U128(200), U128(50));
+    }
+
+    #[test]
+    #[should_panic(expected = "E020: withdraw of 100 from ref exchange failed")]
+    fn test_ref_refund_not_received() {
+        let mut core = setup();
+        let ctx = swap_context(&mut core, 100);
+
+        promise_results(vec![balance(60)]);
+        core.callback_ref_refund(ctx, U128(0));
+    }
+
+    /// The arguments of the calls to `function_name` created by the last call.
+    fn created_calls(function_name: &str) -> Vec<serde_json::Value> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name: name,
+                    args,
+                    ..
+                } if name == function_name => Some(serde_json::from_slice(&args).unwrap()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Context of a swap of `amount` with a gas drop of 10 of its output.
+    fn gas_drop_context(core: &mut ButterCore, amount: u128) -> SwapContext {
+        testing_env!(context("owner.near"));
+        core.set_gas_drop_cap(account("eth.near"), U128(10));
+        let mut core_swap_msg = swap_msg(amount);
+        core_swap_msg.gas_drop = Some(GasDrop {
+            pool_id: 2,
+            amount: U128(10),
+            min_amount_out: U128(1),
+        });
+        let plan = core.plan_swap(None, U128(amount), &core_swap_msg).unwrap();
+        core.new_swap_context(&plan, core_swap_msg, true)
+    }
+
+    #[test]
+    fn test_gas_drop() {
+        let mut core = setup();
+        let ctx = gas_drop_context(&mut core, 100);
+
+        promise_results(vec![balance(100)]);
+        core.callback_transfer_to_target_account(ctx);
+        assert_eq!(created_calls("ft_balance_of")[0]["account_id"], CORE);
+        assert_eq!(created_calls("ft_transfer")[0]["amount"], "90");
+        let drop_ctx: SwapContext =
+            serde_json::from_value(created_calls("callback_drop_gas")[0]["ctx"].clone()).unwrap();
+        assert_eq!(drop_ctx.amount_in, U128(10));
+
+        promise_results(vec![balance(0)]);
+        core.callback_drop_gas(drop_ctx);
+        let swaps = created_calls("ft_transfer_call");
+        assert_eq!(swaps.len(), 1);
+        assert_eq!(swaps[0]["amount"], "10");
+        let drop_ctx: SwapContext =
+            serde_json::from_value(created_calls("callback_get_amount_out")[0]["ctx"].clone())
+                .unwrap();
+        assert_eq!(drop_ctx.balance_before, Some(U128(0)));
+        // the gas drop is counted once its swap succeeds
+        assert_eq!(core.get_gas_drops(account("alice.near")), 0);
+
+        promise_results(vec![balance(5)]);
+        core.callback_transfer_to_target_account(drop_ctx);
+        assert_eq!(created_calls("near_withdraw")[0]["amount"], "5");
+        assert_eq!(core.get_gas_drops(account("alice.near")), 1);
+
+        // the account has received max_gas_drops
+        let ctx = gas_drop_context(&mut core, 100);
+        promise_results(vec![balance(100)]);
+        core.callback_transfer_to_target_account(ctx);
+        assert!(created_calls("ft_transfer_call").is_empty());
+        assert_eq!(created_calls("ft_transfer")[0]["amount"], "100");
+    }
+
+    #[test]
+    fn test_gas_drop_with_wrapped_near_held() {
+        let mut core = setup();
+        let ctx = gas_drop_context(&mut core, 100);
+        let drop_ctx = ctx.gas_drop(ctx.gas_drop.as_ref().unwrap(), &account("wrap.near"));
+
+        // the core already holds 30 wrapped NEAR, which are not part of the output of the gas drop
+        promise_results(vec![balance(30)]);
+        core.callback_drop_gas(drop_ctx);
+        let drop_ctx: SwapContext =
+            serde_json::from_value(created_calls("callback_get_amount_out")[0]["ctx"].clone())
+                .unwrap();
+        assert_eq!(drop_ctx.balance_before, Some(U128(30)));
+
+        promise_results(vec![balance(35)]);
+        core.callback_transfer_to_target_account(drop_ctx);
+        assert_eq!(created_calls("near_withdraw")[0]["amount"], "5");
+        assert_eq!(core.get_gas_drops(account("alice.near")), 1);
+    }
+
+    #[test]
+    fn test_gas_drop_balance_failed() {
+        let mut core = setup();
+        let ctx = gas_drop_context(&mut core, 100);
+        let drop_ctx = ctx.gas_drop(ctx.gas_drop.as_ref().unwrap(), &account("wrap.near"));
+
+        // the input of the gas drop is delivered as the token without swapping it
+        promise_results(vec![PromiseResult::Failed]);
+        core.callback_drop_gas(drop_ctx);
+        assert!(created_calls("ft_transfer_call").is_empty());
+        let transfers = created_calls("ft_transfer");
+        assert_eq!(transfers[0]["receiver_id"], "alice.near");
+        assert_eq!(transfers[0]["amount"], "10");
+    }
+
+    #[test]
+    fn test_gas_drop_failed() {
+        let mut core = setup();
+        let ctx = gas_drop_context(&mut core, 100);
+        let drop_ctx = ctx.gas_drop(ctx.gas_drop.as_ref().unwrap(), &account("wrap.near"));
+
+        // ref exchange fails and the input of the gas drop is delivered as the token
+        promise_results(vec![balance(0)]);
+        core.callback_get_amount_out(drop_ctx);
+        let transfers = created_calls("ft_transfer");
+        assert_eq!(transfers[0]["receiver_id"], "alice.near");
+        assert_eq!(transfers[0]["amount"], "10");
+        assert_eq!(core.get_gas_drops(account("alice.near")), 0);
+    }
+
+    #[test]
+    fn test_gas_drop_skipped_in_internal_mode() {
+        let mut core = setup();
+        let ctx = gas_drop_context(&mut core, 100);
+
+        // the core is switched to the internal mode after the swap is planned
+        core.set_swap_mode(SwapMode::Internal);
+        promise_results(vec![balance(100)]);
+        core.callback_transfer_to_target_account(ctx);
+        assert!(created_calls("ft_transfer_call").is_empty());
+        assert_eq!(created_calls("ft_transfer")[0]["amount"], "100");
+    }
+
+    #[test]
+    fn test_batch_swap() {
+        let mut core = setup();
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(account(CORE))
+            .predecessor_account_id(account(MOS))
+            .prepaid_gas(Gas(1_000_000_000_000_000))
+            .build());
+        let mut fallback_msg = CoreSwapMessage {
+            on_failure: Some(FailurePolicy::DeliverToFallback(account("bob.near"))),
+            target_token: Some(DeliveryAsset::Token(account("dai.near"))),
+            ..swap_msg(100)
         };
+        let Action::Swap(action) = &mut fallback_msg.actions[0];
+        action.token_out = account("dai.near");
+
+        core.batch_swap(vec![
+            (U128(100), swap_msg(100)),
+            (U128(50), swap_msg(100)),
+            (U128(100), fallback_msg),
+        ]);
+
+        // each started swap keeps its own failure policy
+        let on_failures: Vec<FailurePolicy> = created_calls("callback_get_amount_out")
+            .into_iter()
+            .map(|args| serde_json::from_value(args["ctx"]["on_failure"].clone()).unwrap())
+            .collect();
+        assert_eq!(
+            on_failures,
+            vec![
+                FailurePolicy::RefundToMos,
+                FailurePolicy::DeliverToFallback(account("bob.near"))
+            ]
+        );
+        let errors: Vec<Option<ButterCoreError>> =
+            serde_json::from_value(created_calls("callback_batch_swap")[0]["errors"].clone())
+                .unwrap();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].is_none());
+        assert_eq!(errors[1].as_ref().unwrap().code(), "E012");
+        assert!(errors[2].is_none());
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "E003: invalid argument, token out eth.near is repeated in the batch"
+    )]
+    fn test_batch_swap_repeated_token_out() {
+        let mut core = setup();
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(account(CORE))
+            .predecessor_account_id(account(MOS))
+            .prepaid_gas(Gas(1_000_000_000_000_000))
+            .build());
+
+        core.batch_swap(vec![(U128(100), swap_msg(100)), (U128(50), swap_msg(50))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "E014: not enough gas")]
+    fn test_batch_swap_not_enough_gas() {
+        let mut core = setup();
+
+        core.batch_swap(vec![
+            (U128(100), swap_msg(100)),
+            (U128(100), swap_msg(100)),
+            (U128(100), swap_msg(100)),
+        ]);
+    }
+
+    #[test]
+    fn test_callback_batch_swap() {
+        let core = setup();
+        promise_results(vec![
+            PromiseResult::Successful(serde_json::to_vec(&(U128(100), U128(200))).unwrap()),
+            PromiseResult::Failed,
+        ]);
 
-        PromiseOrValue::from(self.do_swap(
-            token,
-            amount,
-            swap_action.token_out.clone(),
-            token_receiver_msg,
-            core_swap_msg.target_account,
-            core_swap_msg.target_token,
-            false,
-        ))
+        let results = core.callback_batch_swap(vec![
+            None,
+            Some(ButterCoreError::InvalidAmount(
+                "amount mismatch".to_string(),
+            )),
+            None,
+        ]);
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            (results[0].amount_in, results[0].amount_out),
+            (U128(100), U128(200))
+        );
+        assert_eq!(results[0].error_code, None);
+        assert_eq!(results[1].error_code.as_deref(), Some("E012"));
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some("E012: invalid core swap msg, amount mismatch")
+        );
+        assert_eq!(results[2].amount_out, U128(0));
+        assert_eq!(results[2].error_code.as_deref(), Some("E022"));
     }
 }
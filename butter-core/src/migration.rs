@@ -0,0 +1,241 @@
+//! Versioned state of ButterCore.
+//!
+//! The version of the state layout is stored under `VERSION_KEY`, a state without it is version 1,
+//! which is the layout of the initially deployed ButterCore. `ButterCoreV1` is kept with a
+//! `migrate` function to the current layout, and `migrate_state` applies it.
+//!
+//! To change the layout of `ButterCore`: copy the current struct as the next `ButterCoreVn`,
+//! add its `migrate` function to the new layout, increase `STATE_VERSION` and add a test with a
+//! serialized fixture of the copied layout.
+
+use crate::*;
+use near_sdk::serde::Deserialize;
+
+/// Version of the `ButterCore` state layout.
+pub const STATE_VERSION: u32 = 2;
+
+const VERSION_KEY: &[u8] = b"VERSION";
+
+/// State layout of the initially deployed ButterCore.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ButterCoreV1 {
+    pub controller: AccountId,
+    pub ref_exchange: AccountId,
+    pub wrapped_token: AccountId,
+    pub owner: AccountId,
+}
+
+impl ButterCoreV1 {
+    pub fn migrate(self, factory: AccountId) -> ButterCore {
+        ButterCore {
+            controller: self.controller,
+            ref_exchange: self.ref_exchange,
+            wrapped_token: self.wrapped_token,
+            owner: self.owner,
+            factory,
+            swap_mode: SwapMode::Execute,
+            min_out_policy: MinOutPolicy::RefundInput,
+            next_order_id: 0,
+            pending_deliveries: UnorderedMap::new(StorageKey::PendingDeliveries),
+            pending_amounts: LookupMap::new(StorageKey::PendingAmounts),
+            pending_native_amount: 0,
+            max_delivery_attempts: DEFAULT_MAX_DELIVERY_ATTEMPTS,
+            delivery_timeout: DEFAULT_DELIVERY_TIMEOUT,
+            upgrade_delay: DEFAULT_UPGRADE_DELAY,
+            staged_upgrade: None,
+            code_hash: None,
+            last_upgrade: None,
+            codes: LookupMap::new(StorageKey::Codes),
+            guardian: None,
+            timelock_delay: DEFAULT_TIMELOCK_DELAY,
+            next_proposal_id: 0,
+            proposals: UnorderedMap::new(StorageKey::Proposals),
+            admins: UnorderedSet::new(StorageKey::Admins),
+            threshold: 0,
+            next_admin_proposal_id: 0,
+            admin_proposals: UnorderedMap::new(StorageKey::AdminProposals),
+            hooks: UnorderedMap::new(StorageKey::Hooks),
+            relayer_fees: LookupMap::new(StorageKey::RelayerFees),
+            relayer_fee_caps: LookupMap::new(StorageKey::RelayerFeeCaps),
+            gas_drop_caps: LookupMap::new(StorageKey::GasDropCaps),
+            max_gas_drops: DEFAULT_MAX_GAS_DROPS,
+            gas_drops: LookupMap::new(StorageKey::GasDrops),
+        }
+    }
+}
+
+/// Args of `migrate`, `factory` is none for the upgrade of a core deployed before the state
+/// versioning, whose `upgrade_self` calls migrate with empty args.
+#[derive(Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+struct MigrateArgs {
+    factory: Option<AccountId>,
+}
+
+/// The factory given to `migrate`, or the parent account of the core if it is not given, which is
+/// the factory of a core created by it. The owner can change it later with a `factory`
+/// configuration change.
+pub fn migrate_factory() -> AccountId {
+    let input = env::input().unwrap_or_default();
+    let args = if input.is_empty() {
+        MigrateArgs::default()
+    } else {
+        serde_json::from_slice::<MigrateArgs>(&input).unwrap_or_else(|e| {
+            ButterCoreError::MigrationFailed(format!("invalid args, {}", e)).panic()
+        })
+    };
+    args.factory.unwrap_or_else(|| {
+        let account_id = env::current_account_id();
+        account_id
+            .as_str()
+            .split_once('.')
+            .and_then(|(_, parent)| parent.parse().ok())
+            .unwrap_or(account_id)
+    })
+}
+
+pub fn read_state_version() -> u32 {
+    env::storage_read(VERSION_KEY)
+        .map(|version| u32::try_from_slice(&version).unwrap())
+        .unwrap_or(1)
+}
+
+pub fn write_state_version() {
+    env::storage_write(VERSION_KEY, &STATE_VERSION.try_to_vec().unwrap());
+}
+
+/// Read the state of `version` and migrate it to the current layout. `factory` is set as the
+/// factory of a state without it.
+pub fn migrate_state(version: u32, state: &[u8], factory: AccountId) -> ButterCore {
+    match version {
+        1 => ButterCoreV1::try_from_slice(state)
+            .unwrap_or_else(|_| {
+                ButterCoreError::MigrationFailed("unexpected state of version 1".to_string())
+                    .panic()
+            })
+            .migrate(factory),
+        STATE_VERSION => ButterCore::try_from_slice(state).unwrap_or_else(|_| {
+            ButterCoreError::MigrationFailed("unexpected state of current version".to_string())
+                .panic()
+        }),
+        _ => ButterCoreError::MigrationFailed(format!("unknown state version {}", version)).panic(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    const CORE: &str = "core0.corefac.near";
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    /// Context of `migrate` called with `args`.
+    fn setup(args: &str) {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(account(CORE))
+            .predecessor_account_id(account(CORE))
+            .build();
+        context.input = args.as_bytes().to_vec();
+        testing_env!(context);
+    }
+
+    /// Borsh encoding of a string: u32 length in little endian followed by the bytes.
+    fn borsh_str(value: &str) -> Vec<u8> {
+        let mut bytes = (value.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    fn fixture_v1() -> Vec<u8> {
+        [
+            borsh_str("mos.near"),
+            borsh_str("v2.ref-finance.near"),
+            borsh_str("wrap.near"),
+            borsh_str("multisig.near"),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_migrate_from_v1() {
+        setup(r#"{"factory": "factory.near"}"#);
+        env::storage_write(b"STATE", &fixture_v1());
+
+        let core = ButterCore::migrate();
+
+        assert_eq!(core.controller, account("mos.near"));
+        assert_eq!(core.ref_exchange, account("v2.ref-finance.near"));
+        assert_eq!(core.wrapped_token, account("wrap.near"));
+        assert_eq!(core.owner, account("multisig.near"));
+        assert_eq!(core.factory, account("factory.near"));
+        assert_eq!(core.swap_mode, SwapMode::Execute);
+        assert_eq!(core.min_out_policy, MinOutPolicy::RefundInput);
+        assert_eq!(core.next_order_id, 0);
+        assert_eq!(core.pending_deliveries.len(), 0);
+        assert_eq!(core.max_delivery_attempts, DEFAULT_MAX_DELIVERY_ATTEMPTS);
+        assert_eq!(core.delivery_timeout, DEFAULT_DELIVERY_TIMEOUT);
+        assert_eq!(core.upgrade_delay, DEFAULT_UPGRADE_DELAY);
+        assert_eq!(core.code_hash, None);
+        assert_eq!(core.guardian, None);
+        assert_eq!(core.timelock_delay, DEFAULT_TIMELOCK_DELAY);
+        assert_eq!(core.admins.len(), 0);
+        assert_eq!(core.threshold, 0);
+        assert_eq!(core.hooks.len(), 0);
+        assert_eq!(core.relayer_fee_caps.get(&account("wrap.near")), None);
+        assert_eq!(core.gas_drop_caps.get(&account("wrap.near")), None);
+        assert_eq!(core.max_gas_drops, DEFAULT_MAX_GAS_DROPS);
+        assert_eq!(core.get_version(), STATE_VERSION);
+    }
+
+    #[test]
+    fn test_migrate_from_v1_without_args() {
+        // upgrade_self of a core of version 1 calls migrate with empty args
+        setup("");
+        env::storage_write(b"STATE", &fixture_v1());
+
+        let core = ButterCore::migrate();
+
+        assert_eq!(core.controller, account("mos.near"));
+        assert_eq!(core.owner, account("multisig.near"));
+        assert_eq!(core.factory, account("corefac.near"));
+        assert_eq!(core.get_version(), STATE_VERSION);
+    }
+
+    #[test]
+    fn test_migrate_current_version() {
+        setup(r#"{"factory": "factory.near"}"#);
+        let mut core = ButterCore::new(
+            account("mos.near"),
+            account("v2.ref-finance.near"),
+            account("wrap.near"),
+            account("multisig.near"),
+            None,
+        );
+        core.swap_mode = SwapMode::Internal;
+        core.next_order_id = 42;
+        env::storage_write(b"STATE", &core.try_to_vec().unwrap());
+
+        let core = ButterCore::migrate();
+
+        assert_eq!(core.controller, account("mos.near"));
+        assert_eq!(core.factory, account(CORE));
+        assert_eq!(core.swap_mode, SwapMode::Internal);
+        assert_eq!(core.next_order_id, 42);
+        assert_eq!(core.get_version(), STATE_VERSION);
+    }
+
+    #[test]
+    #[should_panic(expected = "E044: cannot migrate state, unknown state version")]
+    fn test_migrate_unknown_version() {
+        setup("");
+        env::storage_write(VERSION_KEY, &(STATE_VERSION + 1).try_to_vec().unwrap());
+        env::storage_write(b"STATE", &fixture_v1());
+
+        ButterCore::migrate();
+    }
+}
@@ -0,0 +1,396 @@
+use crate::events::CoreEvent;
+use crate::types::{AdminAction, AdminProposal};
+use crate::*;
+use near_sdk::json_types::U64;
+use near_sdk::serde_json::json;
+use std::collections::HashSet;
+
+/// Gas kept by execute_admin_action, the rest is attached to the action.
+const EXECUTE_ADMIN_ACTION_RESERVED_GAS: Gas = Gas(10_000_000_000_000);
+/// Lifetime of an admin proposal, 7 days in nanoseconds.
+const ADMIN_PROPOSAL_EXPIRY: u64 = 7 * 24 * 3600 * 1_000_000_000;
+
+#[near_bindgen]
+impl ButterCore {
+    pub fn get_admins(&self) -> Vec<AccountId> {
+        self.admins.to_vec()
+    }
+
+    pub fn get_threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub fn get_admin_proposal(&self, proposal_id: U64) -> Option<AdminProposal> {
+        self.admin_proposals.get(&proposal_id.0)
+    }
+
+    pub fn get_admin_proposals(&self, from_index: u64, limit: u64) -> Vec<(U64, AdminProposal)> {
+        self.admin_proposals
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(proposal_id, proposal)| (U64(proposal_id), proposal))
+            .collect()
+    }
+
+    /// Propose an admin action, approved by the proposer. Returns the proposal id.
+    pub fn propose_admin_action(&mut self, action: AdminAction) -> U64 {
+        let proposer = self.assert_admin();
+
+        let proposal_id = self.next_admin_proposal_id;
+        self.next_admin_proposal_id += 1;
+        let created_at = env::block_timestamp();
+        let proposal = AdminProposal {
+            action,
+            proposer: proposer.clone(),
+            approvals: vec![proposer.clone()],
+            created_at: U64(created_at),
+            expires_at: U64(created_at + ADMIN_PROPOSAL_EXPIRY),
+        };
+        self.admin_proposals.insert(&proposal_id, &proposal);
+
+        CoreEvent::AdminActionProposed {
+            proposal_id: U64(proposal_id),
+            action: &proposal.action,
+            proposer: &proposer,
+        }
+        .emit();
+        U64(proposal_id)
+    }
+
+    pub fn approve_admin_action(&mut self, proposal_id: U64) {
+        let admin = self.assert_admin();
+        let mut proposal = self.get_live_admin_proposal(proposal_id.0);
+        ensure!(
+            !proposal.approvals.contains(&admin),
+            ButterCoreError::AlreadyApproved(admin)
+        );
+        proposal.approvals.push(admin.clone());
+        self.admin_proposals.insert(&proposal_id.0, &proposal);
+
+        CoreEvent::AdminActionApproved {
+            proposal_id,
+            approved_by: &admin,
+        }
+        .emit();
+    }
+
+    /// Execute an admin action approved by `threshold` of the current admins, the action is
+    /// called on the core by itself as the owner.
+    pub fn execute_admin_action(&mut self, proposal_id: U64) -> Promise {
+        self.assert_admin();
+        let proposal = self.get_live_admin_proposal(proposal_id.0);
+        let approvals = proposal
+            .approvals
+            .iter()
+            .filter(|admin| self.admins.contains(admin))
+            .count();
+        ensure!(
+            approvals >= self.threshold as usize,
+            ButterCoreError::NotEnoughApprovals {
+                approvals: approvals as u32,
+                threshold: self.threshold,
+            }
+        );
+        ensure!(
+            self.owner == env::current_account_id(),
+            ButterCoreError::Unauthorized(env::current_account_id())
+        );
+        self.admin_proposals.remove(&proposal_id.0);
+
+        let (method_name, args, deposit) = match &proposal.action {
+            AdminAction::SetSwapMode(swap_mode) => {
+                ("set_swap_mode", json!({ "swap_mode": swap_mode }), 0)
+            }
+            AdminAction::SetMinOutPolicy(min_out_policy) => (
+                "set_min_out_policy",
+                json!({ "min_out_policy": min_out_policy }),
+                0,
+            ),
+            AdminAction::SetMaxDeliveryAttempts(max_delivery_attempts) => (
+                "set_max_delivery_attempts",
+                json!({ "max_delivery_attempts": max_delivery_attempts }),
+                0,
+            ),
+            AdminAction::SetDeliveryTimeout(delivery_timeout) => (
+                "set_delivery_timeout",
+                json!({ "delivery_timeout": delivery_timeout }),
+                0,
+            ),
+            AdminAction::RegisterInRefExchange { token_ids, deposit } => (
+                "register_in_ref_exchange",
+                json!({ "token_ids": token_ids }),
+                deposit.0,
+            ),
+            AdminAction::ProposeConfigChange(change) => {
+                ("propose_config_change", json!({ "change": change }), 0)
+            }
+            AdminAction::ExecuteConfigChange(id) => {
+                ("execute_config_change", json!({ "proposal_id": id }), 0)
+            }
+            AdminAction::CancelConfigChange(id) => {
+                ("cancel_config_change", json!({ "proposal_id": id }), 0)
+            }
+            AdminAction::ApplyUpgrade(code_hash) => {
+                ("apply_upgrade", json!({ "code_hash": code_hash }), 0)
+            }
+            AdminAction::CancelUpgrade => ("cancel_upgrade", json!({}), 0),
+            AdminAction::RollbackUpgrade => ("rollback_upgrade", json!({}), 0),
+            AdminAction::SetHook(hook) => (
+                "set_hook",
+                json!({
+                    "contract_id": hook.contract_id,
+                    "method_name": hook.method_name,
+                    "max_gas": hook.max_gas,
+                }),
+                0,
+            ),
+            AdminAction::RemoveHook {
+                contract_id,
+                method_name,
+            } => (
+                "remove_hook",
+                json!({ "contract_id": contract_id, "method_name": method_name }),
+                0,
+            ),
+            AdminAction::SetRelayerFeeCap { token, cap } => (
+                "set_relayer_fee_cap",
+                json!({ "token": token, "cap": cap }),
+                0,
+            ),
+            AdminAction::SetGasDropCap { token, cap } => {
+                ("set_gas_drop_cap", json!({ "token": token, "cap": cap }), 0)
+            }
+            AdminAction::SetMaxGasDrops(max_gas_drops) => (
+                "set_max_gas_drops",
+                json!({ "max_gas_drops": max_gas_drops }),
+                0,
+            ),
+        };
+
+        CoreEvent::AdminActionExecuted {
+            proposal_id,
+            action: &proposal.action,
+        }
+        .emit();
+        Promise::new(env::current_account_id()).function_call(
+            method_name.to_string(),
+            args.to_string().into_bytes(),
+            deposit,
+            env::prepaid_gas() - env::used_gas() - EXECUTE_ADMIN_ACTION_RESERVED_GAS,
+        )
+    }
+
+    /// Remove an expired admin proposal, callable by any admin.
+    pub fn remove_expired_admin_proposal(&mut self, proposal_id: U64) {
+        self.assert_admin();
+        let proposal = self
+            .admin_proposals
+            .get(&proposal_id.0)
+            .unwrap_or_else(|| ButterCoreError::UnknownAdminProposal(proposal_id).panic());
+        ensure!(
+            env::block_timestamp() > proposal.expires_at.0,
+            ButterCoreError::AdminProposalNotExpired(proposal_id)
+        );
+        self.admin_proposals.remove(&proposal_id.0);
+    }
+}
+
+impl ButterCore {
+    /// Hand the ownership to `threshold` of `admins`: the core becomes its own owner and executes
+    /// the admin actions approved by them. Pending admin proposals are dropped.
+    pub(crate) fn setup_multisig(&mut self, admins: Vec<AccountId>, threshold: u32) {
+        self.admins.clear();
+        self.admins.extend(admins);
+        self.threshold = threshold;
+        self.admin_proposals.clear();
+        self.owner = env::current_account_id();
+    }
+
+    pub(crate) fn assert_multisig_threshold(admins: &[AccountId], threshold: u32) {
+        let admin_count = admins.iter().collect::<HashSet<_>>().len();
+        ensure!(
+            threshold > 0 && threshold as usize <= admin_count,
+            ButterCoreError::InvalidArgument(
+                "threshold should be between 1 and the number of admins".to_string()
+            )
+        );
+    }
+
+    pub(crate) fn is_admin(&self, account_id: &AccountId) -> bool {
+        self.admins.contains(account_id)
+    }
+
+    fn assert_admin(&self) -> AccountId {
+        let caller = env::predecessor_account_id();
+        ensure!(
+            self.is_admin(&caller),
+            ButterCoreError::Unauthorized(caller)
+        );
+        caller
+    }
+
+    fn get_live_admin_proposal(&self, proposal_id: u64) -> AdminProposal {
+        let proposal = self
+            .admin_proposals
+            .get(&proposal_id)
+            .unwrap_or_else(|| ButterCoreError::UnknownAdminProposal(U64(proposal_id)).panic());
+        ensure!(
+            env::block_timestamp() <= proposal.expires_at.0,
+            ButterCoreError::AdminProposalExpired(U64(proposal_id))
+        );
+        proposal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SwapMode;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, VMContext};
+
+    const CORE: &str = "core0.corefac.near";
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    fn context(predecessor: &str, block_timestamp: u64) -> VMContext {
+        VMContextBuilder::new()
+            .current_account_id(account(CORE))
+            .predecessor_account_id(account(predecessor))
+            .block_timestamp(block_timestamp)
+            .prepaid_gas(Gas(300_000_000_000_000))
+            .build()
+    }
+
+    /// A core owned by 2 of 3 admins with a proposal of admin0 to set the swap mode.
+    fn setup() -> (ButterCore, U64) {
+        testing_env!(context(CORE, 0));
+        let mut core = ButterCore::new(
+            account("mos.near"),
+            account("v2.ref-finance.near"),
+            account("wrap.near"),
+            account("owner.near"),
+            None,
+        );
+        core.setup_multisig(
+            vec![
+                account("admin0.near"),
+                account("admin1.near"),
+                account("admin2.near"),
+            ],
+            2,
+        );
+        testing_env!(context("admin0.near", 0));
+        let proposal_id = core.propose_admin_action(AdminAction::SetSwapMode(SwapMode::Internal));
+        (core, proposal_id)
+    }
+
+    #[test]
+    #[should_panic(expected = "E061: proposal is already approved by admin0.near")]
+    fn test_approve_twice() {
+        let (mut core, proposal_id) = setup();
+        core.approve_admin_action(proposal_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "E062: proposal has 1 of 2 approvals")]
+    fn test_execute_below_threshold() {
+        let (mut core, proposal_id) = setup();
+        core.execute_admin_action(proposal_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "E062: proposal has 1 of 2 approvals")]
+    fn test_execute_with_approval_of_removed_admin() {
+        let (mut core, proposal_id) = setup();
+        testing_env!(context("admin1.near", 0));
+        core.approve_admin_action(proposal_id);
+
+        core.admins.remove(&account("admin1.near"));
+        testing_env!(context("admin0.near", 0));
+        core.execute_admin_action(proposal_id);
+    }
+
+    #[test]
+    fn test_execute_admin_action() {
+        let (mut core, proposal_id) = setup();
+        testing_env!(context("admin1.near", 0));
+        core.approve_admin_action(proposal_id);
+
+        core.execute_admin_action(proposal_id);
+        assert!(core.get_admin_proposal(proposal_id).is_none());
+        // the action is called on the core by itself as the owner
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, account(CORE));
+        match &receipts[0].actions[0] {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                deposit,
+                ..
+            } => {
+                assert_eq!(function_name, "set_swap_mode");
+                assert_eq!(
+                    serde_json::from_slice::<serde_json::Value>(args).unwrap(),
+                    json!({ "swap_mode": "internal" })
+                );
+                assert_eq!(*deposit, 0);
+            }
+            action => panic!("unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_approve_until_expiry() {
+        let (mut core, proposal_id) = setup();
+        testing_env!(context("admin1.near", ADMIN_PROPOSAL_EXPIRY));
+        core.approve_admin_action(proposal_id);
+        assert_eq!(
+            core.get_admin_proposal(proposal_id)
+                .unwrap()
+                .approvals
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "E063: admin proposal 0 is expired")]
+    fn test_approve_after_expiry() {
+        let (mut core, proposal_id) = setup();
+        testing_env!(context("admin1.near", ADMIN_PROPOSAL_EXPIRY + 1));
+        core.approve_admin_action(proposal_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "E063: admin proposal 0 is expired")]
+    fn test_execute_after_expiry() {
+        let (mut core, proposal_id) = setup();
+        testing_env!(context("admin1.near", 0));
+        core.approve_admin_action(proposal_id);
+
+        testing_env!(context("admin0.near", ADMIN_PROPOSAL_EXPIRY + 1));
+        core.execute_admin_action(proposal_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "E064: admin proposal 0 is not expired")]
+    fn test_remove_admin_proposal_before_expiry() {
+        let (mut core, proposal_id) = setup();
+        testing_env!(context("admin1.near", ADMIN_PROPOSAL_EXPIRY));
+        core.remove_expired_admin_proposal(proposal_id);
+    }
+
+    #[test]
+    fn test_remove_expired_admin_proposal() {
+        let (mut core, proposal_id) = setup();
+        testing_env!(context("admin1.near", ADMIN_PROPOSAL_EXPIRY + 1));
+        core.remove_expired_admin_proposal(proposal_id);
+        assert!(core.get_admin_proposal(proposal_id).is_none());
+    }
+}
@@ -0,0 +1,1311 @@
+//! Planning of a swap, which validates the core swap message and decides the promise chain, its
+//! gas and how the output is delivered, without creating any promise.
+
+use crate::types::{
+    is_implicit_account, Action, CoreSwapMessage, DeliveryAsset, Hook, MinOutPolicy, SwapContext,
+    SwapMode, SwapMsg, TargetCall, TokenReceiverMessage, ZERO_ADDRESS,
+};
+use crate::*;
+use std::fmt;
+
+/// Settings of the core used to plan a swap.
+#[derive(Clone, Copy)]
+pub struct SwapConfig<'a> {
+    pub swap_mode: SwapMode,
+    pub min_out_policy: MinOutPolicy,
+    pub wrapped_token: &'a AccountId,
+}
+
+/// How the output is delivered to the target account.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeliveryMode {
+    /// Swap in, ft_transfer the output token.
+    Transfer,
+    /// Swap in, unwrap the wrapped token and transfer NEAR.
+    Native,
+    /// Swap in, ft_transfer_call the output token with the msg of the target call, the value is
+    /// the gas of ft_on_transfer of the target account.
+    Call(Gas),
+    /// Swap in, call the hook with the output, NEAR unwrapped from the wrapped token if `native`.
+    Hook { native: bool, gas: Gas },
+    /// Swap out, ft_transfer_call the output token to MOS.
+    SwapOut,
+}
+
+/// Check of the target account before the swap, see `CoreSwapMessage::check_target`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TargetCheck {
+    /// The target account should be registered in the token by storage_balance_of.
+    Registered(AccountId),
+    /// The named target account of native NEAR should exist, checked by a transfer of 0.
+    Exists,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SwapStep {
+    /// Check the target account, the input is refunded if the check fails.
+    CheckTarget,
+    /// ft_transfer_call the input to ref exchange with the Execute message.
+    RefExecute,
+    /// ft_transfer_call the input to ref exchange with empty message.
+    RefDeposit,
+    /// Call swap on ref exchange.
+    RefSwap,
+    /// Withdraw the output from ref exchange.
+    RefWithdraw,
+    /// Get the output balance of the core.
+    GetBalance,
+    Deliver(DeliveryMode),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PlanError {
+    EmptyActions,
+    ZeroAmount,
+    /// The token received is not the input token of the first action.
+    TokenInMismatch {
+        expected: AccountId,
+        actual: AccountId,
+    },
+    /// The input token of the action at `index` is not the output token of the previous one.
+    BrokenRoute {
+        index: usize,
+    },
+    /// The first action has no amount_in.
+    MissingAmountIn,
+    /// The amount_in of the first action is more than the amount of the swap.
+    AmountInExceeded {
+        amount_in: U128,
+        amount: U128,
+    },
+    /// The action at `index` has amount_in, which should take the output of the previous one.
+    UnexpectedAmountIn {
+        index: usize,
+    },
+    /// The target token is native NEAR but the output is not the wrapped token.
+    InvalidNativeTarget,
+    /// The target token is not the output token.
+    TargetTokenMismatch {
+        expected: AccountId,
+        actual: AccountId,
+    },
+    /// The swap msg is given but the swap is not a swap out.
+    UnexpectedSwapMsg,
+    /// The target token is a swap out without swap msg.
+    MissingSwapMsg,
+    /// The address of the swap out is not a hex address.
+    InvalidSwapOutAddress,
+    /// The address of the swap out is not the target token of the swap msg.
+    SwapOutAddressMismatch,
+    /// The chain of the swap msg is not the chain of the target token.
+    ToChainMismatch {
+        expected: U128,
+        actual: U128,
+    },
+    /// The target call is given but the target token is not a token.
+    UnexpectedTargetCall,
+    /// The gas of the target call is more than `MAX_TARGET_CALL_GAS`.
+    TargetCallGasExceeded {
+        gas: U64,
+    },
+    /// The hook is given but the target token is not native NEAR or a token, or with a target
+    /// call.
+    UnexpectedHook,
+    /// The hook of a token doesn't call `ft_on_transfer`.
+    InvalidHookMethod,
+    /// The gas drop is given but the target token is not a token other than the wrapped token.
+    UnexpectedGasDrop,
+    /// The gas drop is given in the internal swap mode, whose promise chain with the gas drop
+    /// needs more than 300 TGas.
+    InternalGasDrop,
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanError::EmptyActions => write!(f, "empty actions"),
+            PlanError::ZeroAmount => write!(f, "amount should be positive"),
+            PlanError::TokenInMismatch { expected, actual } => write!(
+                f,
+                "token in mismatch, expected: {}, actual: {}",
+                expected, actual
+            ),
+            PlanError::BrokenRoute { index } => write!(
+                f,
+                "token in of action {} is not the token out of the previous action",
+                index
+            ),
+            PlanError::MissingAmountIn => write!(f, "amount in of the first action is missing"),
+            PlanError::AmountInExceeded { amount_in, amount } => write!(
+                f,
+                "amount in of the first action {} exceeds the amount {}",
+                amount_in.0, amount.0
+            ),
+            PlanError::UnexpectedAmountIn { index } => {
+                write!(f, "action {} should not have amount in", index)
+            }
+            PlanError::InvalidNativeTarget => {
+                write!(f, "native target token requires the wrapped token out")
+            }
+            PlanError::TargetTokenMismatch { expected, actual } => write!(
+                f,
+                "target token mismatch, expected: {}, actual: {}",
+                expected, actual
+            ),
+            PlanError::UnexpectedSwapMsg => write!(f, "swap msg requires a swap out"),
+            PlanError::MissingSwapMsg => write!(f, "swap out requires a swap msg"),
+            PlanError::InvalidSwapOutAddress => write!(f, "invalid address of swap out"),
+            PlanError::SwapOutAddressMismatch => {
+                write!(f, "address of swap out is not the target token of swap msg")
+            }
+            PlanError::UnexpectedTargetCall => write!(f, "target call requires a target token"),
+            PlanError::UnexpectedHook => write!(
+                f,
+                "hook requires a native or token target without target call"
+            ),
+            PlanError::InvalidHookMethod => {
+                write!(f, "hook of a token should call ft_on_transfer")
+            }
+            PlanError::UnexpectedGasDrop => write!(
+                f,
+                "gas drop requires a target token other than the wrapped token"
+            ),
+            PlanError::InternalGasDrop => write!(f, "gas drop requires the execute swap mode"),
+            PlanError::TargetCallGasExceeded { gas } => write!(
+                f,
+                "gas of target call {} exceeds {}",
+                gas.0, MAX_TARGET_CALL_GAS.0
+            ),
+            PlanError::ToChainMismatch { expected, actual } => write!(
+                f,
+                "to chain of swap msg mismatch, expected: {}, actual: {}",
+                expected.0, actual.0
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SwapPlan {
+    pub swap_mode: SwapMode,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in: U128,
+    pub steps: Vec<SwapStep>,
+    pub delivery: DeliveryMode,
+    pub target_check: Option<TargetCheck>,
+    /// Gas of the whole promise chain.
+    pub gas: Gas,
+}
+
+/// Plan the swap of `amount` by `msg`. `token_in` is the token received by `ft_on_transfer`,
+/// none if the swap is called directly, which takes the input token of the first action.
+pub fn plan_swap(
+    config: SwapConfig,
+    token_in: Option<&AccountId>,
+    amount: U128,
+    msg: &CoreSwapMessage,
+) -> Result<SwapPlan, PlanError> {
+    validate_swap(config, token_in, amount, msg)?;
+    let (Action::Swap(first_action), Action::Swap(last_action)) =
+        (&msg.actions[0], &msg.actions[msg.actions.len() - 1]);
+
+    let delivery = delivery_mode(
+        config,
+        &last_action.token_out,
+        msg.target_token.as_ref(),
+        msg.target_call.as_ref(),
+        msg.hook.as_ref(),
+    );
+    let mut steps = match config.swap_mode {
+        SwapMode::Execute => vec![SwapStep::RefExecute, SwapStep::GetBalance],
+        SwapMode::Internal => vec![
+            SwapStep::RefDeposit,
+            SwapStep::RefSwap,
+            SwapStep::RefWithdraw,
+        ],
+    };
+    steps.push(SwapStep::Deliver(delivery));
+    let target_check = target_check(config, msg);
+    if target_check.is_some() {
+        steps.insert(0, SwapStep::CheckTarget);
+    }
+    let transfer_gas = delivery_gas(
+        config,
+        delivery,
+        msg.min_total_out.is_some(),
+        msg.gas_drop.is_some(),
+    );
+
+    Ok(SwapPlan {
+        swap_mode: config.swap_mode,
+        token_in: first_action.token_in.clone(),
+        token_out: last_action.token_out.clone(),
+        amount_in: amount,
+        steps,
+        delivery,
+        gas: match target_check {
+            Some(_) => Gas(CHECK_TARGET_GAS.0
+                + CALLBACK_CHECK_TARGET_GAS.0
+                + swap_gas(config, transfer_gas).0),
+            None => swap_gas(config, transfer_gas),
+        },
+        target_check,
+    })
+}
+
+/// Check the swap of `amount` by `msg` before any token is moved: the actions should be a chain
+/// starting from the received token, with the input amount on the first action only.
+pub fn validate_swap(
+    config: SwapConfig,
+    token_in: Option<&AccountId>,
+    amount: U128,
+    msg: &CoreSwapMessage,
+) -> Result<(), PlanError> {
+    let (Action::Swap(first_action), Action::Swap(last_action)) =
+        match (msg.actions.first(), msg.actions.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(PlanError::EmptyActions),
+        };
+    if amount.0 == 0 {
+        return Err(PlanError::ZeroAmount);
+    }
+    if let Some(token_in) = token_in {
+        if token_in != &first_action.token_in {
+            return Err(PlanError::TokenInMismatch {
+                expected: first_action.token_in.clone(),
+                actual: token_in.clone(),
+            });
+        }
+    }
+    match first_action.amount_in {
+        None => return Err(PlanError::MissingAmountIn),
+        Some(amount_in) if amount_in.0 > amount.0 => {
+            return Err(PlanError::AmountInExceeded { amount_in, amount })
+        }
+        Some(_) => {}
+    }
+    for (index, pair) in msg.actions.windows(2).enumerate() {
+        let (Action::Swap(previous), Action::Swap(action)) = (&pair[0], &pair[1]);
+        if action.token_in != previous.token_out {
+            return Err(PlanError::BrokenRoute { index: index + 1 });
+        }
+        if action.amount_in.is_some() {
+            return Err(PlanError::UnexpectedAmountIn { index: index + 1 });
+        }
+    }
+    if let Some(target_call) = &msg.target_call {
+        if !matches!(msg.target_token, Some(DeliveryAsset::Token(_))) {
+            return Err(PlanError::UnexpectedTargetCall);
+        }
+        if target_call.gas.0 > MAX_TARGET_CALL_GAS.0 {
+            return Err(PlanError::TargetCallGasExceeded {
+                gas: target_call.gas,
+            });
+        }
+    }
+    if let Some(hook) = &msg.hook {
+        match &msg.target_token {
+            _ if msg.target_call.is_some() => return Err(PlanError::UnexpectedHook),
+            Some(DeliveryAsset::Native) => {}
+            Some(DeliveryAsset::Token(_)) if hook.method_name != "ft_on_transfer" => {
+                return Err(PlanError::InvalidHookMethod)
+            }
+            Some(DeliveryAsset::Token(_)) => {}
+            _ => return Err(PlanError::UnexpectedHook),
+        }
+    }
+    if let Some(gas_drop) = &msg.gas_drop {
+        if !matches!(msg.target_token, Some(DeliveryAsset::Token(_)))
+            || &last_action.token_out == config.wrapped_token
+        {
+            return Err(PlanError::UnexpectedGasDrop);
+        }
+        if gas_drop.amount.0 == 0 {
+            return Err(PlanError::ZeroAmount);
+        }
+        if config.swap_mode == SwapMode::Internal {
+            return Err(PlanError::InternalGasDrop);
+        }
+    }
+    match (&msg.target_token, &msg.swap_msg) {
+        (Some(DeliveryAsset::Native), _) if &last_action.token_out != config.wrapped_token => {
+            Err(PlanError::InvalidNativeTarget)
+        }
+        (Some(DeliveryAsset::Token(token)), _) if token != &last_action.token_out => {
+            Err(PlanError::TargetTokenMismatch {
+                expected: last_action.token_out.clone(),
+                actual: token.clone(),
+            })
+        }
+        (
+            Some(DeliveryAsset::Native | DeliveryAsset::Token(_) | DeliveryAsset::Legacy(_)),
+            Some(_),
+        ) => Err(PlanError::UnexpectedSwapMsg),
+        (Some(DeliveryAsset::SwapOut { .. }), None) => Err(PlanError::MissingSwapMsg),
+        (Some(DeliveryAsset::SwapOut { chain, .. }), Some(swap_msg))
+            if chain.0 as u128 != swap_msg.to_chain.0 =>
+        {
+            Err(PlanError::ToChainMismatch {
+                expected: U128(chain.0 as u128),
+                actual: swap_msg.to_chain,
+            })
+        }
+        (Some(DeliveryAsset::SwapOut { address, .. }), Some(swap_msg)) => {
+            match parse_address(address) {
+                None => Err(PlanError::InvalidSwapOutAddress),
+                Some(address) if address != swap_msg.swap_data_1.target_token => {
+                    Err(PlanError::SwapOutAddressMismatch)
+                }
+                Some(_) => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Bytes of the hex `address`, with or without `0x`.
+fn parse_address(address: &str) -> Option<Vec<u8>> {
+    let address = address.strip_prefix("0x").unwrap_or(address);
+    if address.is_empty() || !address.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..address.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(address.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A swap without target token is a swap out, a swap in to `DeliveryAsset::Native` delivers
+/// native NEAR if the output is the wrapped token, a swap in with target call delivers the
+/// output token by ft_transfer_call, and a swap in with hook calls the hook with the output.
+/// A legacy target token delivers native NEAR if it is `ZERO_ADDRESS` and the output is the
+/// wrapped token, the output token otherwise.
+pub fn delivery_mode(
+    config: SwapConfig,
+    token_out: &AccountId,
+    target_token: Option<&DeliveryAsset>,
+    target_call: Option<&TargetCall>,
+    hook: Option<&Hook>,
+) -> DeliveryMode {
+    let native = token_out == config.wrapped_token;
+    match (target_token, target_call, hook) {
+        (None | Some(DeliveryAsset::SwapOut { .. }), _, _) => DeliveryMode::SwapOut,
+        (Some(DeliveryAsset::Native), _, Some(hook)) if native => DeliveryMode::Hook {
+            native: true,
+            gas: Gas(hook.gas.0),
+        },
+        (Some(DeliveryAsset::Native), _, None) if native => DeliveryMode::Native,
+        (Some(DeliveryAsset::Legacy(token)), _, _) if native && token.as_str() == ZERO_ADDRESS => {
+            DeliveryMode::Native
+        }
+        (Some(DeliveryAsset::Token(_)), _, Some(hook)) => DeliveryMode::Hook {
+            native: false,
+            gas: Gas(hook.gas.0),
+        },
+        (Some(DeliveryAsset::Token(_)), Some(target_call), None) => {
+            DeliveryMode::Call(Gas(target_call.gas.0))
+        }
+        _ => DeliveryMode::Transfer,
+    }
+}
+
+/// Check of the target account of a swap in if `check_target` is set. A token should be registered
+/// for both implicit and named accounts, an implicit account is created by the transfer of native
+/// NEAR, while a named one should exist.
+pub fn target_check(config: SwapConfig, msg: &CoreSwapMessage) -> Option<TargetCheck> {
+    if msg.check_target != Some(true) {
+        return None;
+    }
+    let Action::Swap(last_action) = msg.actions.last()?;
+    let delivery = delivery_mode(
+        config,
+        &last_action.token_out,
+        msg.target_token.as_ref(),
+        msg.target_call.as_ref(),
+        msg.hook.as_ref(),
+    );
+    match delivery {
+        DeliveryMode::SwapOut => None,
+        DeliveryMode::Native | DeliveryMode::Hook { native: true, .. } => {
+            (!is_implicit_account(&msg.target_account)).then_some(TargetCheck::Exists)
+        }
+        _ => Some(TargetCheck::Registered(last_action.token_out.clone())),
+    }
+}
+
+/// Message of ft_transfer_call to MOS which delivers the output of a swap out, it carries the
+/// onward swap if any.
+pub fn swap_out_msg(swap_msg: Option<&SwapMsg>) -> String {
+    swap_msg
+        .map(|swap_msg| serde_json::to_string(swap_msg).unwrap())
+        .unwrap_or_default()
+}
+
+/// Message of ft_transfer_call to ref exchange which executes `actions`.
+pub fn execute_msg(actions: &[Action]) -> String {
+    serde_json::to_string(&TokenReceiverMessage::Execute {
+        referral_id: None,
+        actions: actions.to_vec(),
+    })
+    .unwrap()
+}
+
+/// Gas of the whole promise chain of the swap, `transfer_gas` is the gas of
+/// callback_transfer_to_target_account.
+pub fn swap_gas(config: SwapConfig, transfer_gas: Gas) -> Gas {
+    match config.swap_mode {
+        SwapMode::Execute => Gas(FT_TRANSFER_CALL_REF_GAS.0
+            + CALLBACK_GET_AMOUNT_OUT_GAS.0
+            + FT_BALANCE_OF_GAS.0
+            + transfer_gas.0),
+        SwapMode::Internal => Gas(FT_TRANSFER_CALL_REF_DEPOSIT_GAS.0
+            + CALLBACK_REF_DEPOSIT_GAS.0
+            + REF_SWAP_GAS.0
+            + callback_ref_swap_gas(transfer_gas).0),
+    }
+}
+
+/// Gas of callback_transfer_to_target_account when the output is swapped back to the input token
+/// because it is less than min_total_out.
+pub fn reverse_gas(config: SwapConfig) -> Gas {
+    Gas(10_000_000_000_000 + swap_gas(config, CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_REFUND_GAS).0)
+}
+
+/// Gas of callback_ref_swap, which withdraws the output or refunds the input.
+pub fn callback_ref_swap_gas(transfer_gas: Gas) -> Gas {
+    let withdraw_out = REF_CHECKED_WITHDRAW_GAS.0 + CALLBACK_REF_WITHDRAW_GAS.0 + transfer_gas.0;
+    let withdraw_in = REF_CHECKED_WITHDRAW_GAS.0 + CALLBACK_REF_REFUND_GAS.0;
+    Gas(CALLBACK_REF_SWAP_GAS.0 + std::cmp::max(withdraw_out, withdraw_in))
+}
+
+/// Gas of callback_transfer_to_target_account for the swap of `ctx`.
+pub fn transfer_to_target_account_gas(config: SwapConfig, ctx: &SwapContext) -> Gas {
+    if ctx.reverted_amount_in.is_some() {
+        return CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_REFUND_GAS;
+    }
+    let delivery = delivery_mode(
+        config,
+        &ctx.token_out,
+        ctx.target_token.as_ref(),
+        ctx.target_call.as_ref(),
+        ctx.hook.as_ref(),
+    );
+    delivery_gas(
+        config,
+        delivery,
+        ctx.min_total_out.is_some(),
+        ctx.gas_drop.is_some(),
+    )
+}
+
+/// Gas of callback_transfer_to_target_account, a gas drop is sent along with the delivery.
+fn delivery_gas(
+    config: SwapConfig,
+    delivery: DeliveryMode,
+    has_min_total_out: bool,
+    has_gas_drop: bool,
+) -> Gas {
+    let gas = match delivery {
+        DeliveryMode::SwapOut => CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_OUT_GAS,
+        DeliveryMode::Call(gas) => Gas(CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_CALL_GAS.0 + gas.0),
+        DeliveryMode::Hook { gas, .. } => {
+            Gas(CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_HOOK_GAS.0 + gas.0)
+        }
+        DeliveryMode::Transfer | DeliveryMode::Native => {
+            CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_IN_GAS
+        }
+    };
+    let gas = if has_gas_drop {
+        Gas(gas.0 + GAS_DROP_GAS.0)
+    } else {
+        gas
+    };
+    if !has_min_total_out {
+        return gas;
+    }
+    match config.min_out_policy {
+        MinOutPolicy::RefundInput => std::cmp::max(gas, reverse_gas(config)),
+        MinOutPolicy::RefundOutput => {
+            std::cmp::max(gas, CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_REFUND_GAS)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{GasDrop, RefundPolicy, SwapAction, SwapData};
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    fn config(swap_mode: SwapMode, wrapped_token: &AccountId) -> SwapConfig<'_> {
+        SwapConfig {
+            swap_mode,
+            min_out_policy: MinOutPolicy::RefundInput,
+            wrapped_token,
+        }
+    }
+
+    fn swap(token_in: &str, token_out: &str) -> Action {
+        Action::Swap(SwapAction {
+            pool_id: 0,
+            token_in: account(token_in),
+            amount_in: None,
+            token_out: account(token_out),
+            min_amount_out: U128(0),
+        })
+    }
+
+    /// Message swapping 100 of the input token of the first action.
+    fn msg(mut actions: Vec<Action>, target_token: Option<DeliveryAsset>) -> CoreSwapMessage {
+        if let Some(Action::Swap(first_action)) = actions.first_mut() {
+            first_action.amount_in = Some(U128(100));
+        }
+        CoreSwapMessage {
+            actions,
+            target_account: account("alice.near"),
+            target_token,
+            swap_msg: None,
+            target_call: None,
+            hook: None,
+            relayer_fee: None,
+            gas_drop: None,
+            check_target: None,
+            min_total_out: None,
+            on_failure: None,
+            on_refund: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_swap_in_execute() {
+        let wrap = account("wrap.near");
+        let msg = msg(
+            vec![
+                swap("usdc.near", "wrap.near"),
+                swap("wrap.near", "eth.near"),
+            ],
+            Some(DeliveryAsset::Token(account("eth.near"))),
+        );
+
+        let plan = plan_swap(
+            config(SwapMode::Execute, &wrap),
+            Some(&account("usdc.near")),
+            U128(100),
+            &msg,
+        )
+        .unwrap();
+
+        assert_eq!(plan.swap_mode, SwapMode::Execute);
+        assert_eq!(plan.token_in, account("usdc.near"));
+        assert_eq!(plan.token_out, account("eth.near"));
+        assert_eq!(plan.amount_in, U128(100));
+        assert_eq!(plan.delivery, DeliveryMode::Transfer);
+        assert_eq!(
+            plan.steps,
+            vec![
+                SwapStep::RefExecute,
+                SwapStep::GetBalance,
+                SwapStep::Deliver(DeliveryMode::Transfer)
+            ]
+        );
+        assert_eq!(
+            plan.gas,
+            Gas(FT_TRANSFER_CALL_REF_GAS.0
+                + CALLBACK_GET_AMOUNT_OUT_GAS.0
+                + FT_BALANCE_OF_GAS.0
+                + CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_IN_GAS.0)
+        );
+    }
+
+    #[test]
+    fn test_plan_swap_out_internal() {
+        let wrap = account("wrap.near");
+        let msg = msg(vec![swap("usdc.near", "eth.near")], None);
+
+        let plan = plan_swap(config(SwapMode::Internal, &wrap), None, U128(100), &msg).unwrap();
+
+        assert_eq!(plan.token_in, account("usdc.near"));
+        assert_eq!(plan.delivery, DeliveryMode::SwapOut);
+        assert_eq!(
+            plan.steps,
+            vec![
+                SwapStep::RefDeposit,
+                SwapStep::RefSwap,
+                SwapStep::RefWithdraw,
+                SwapStep::Deliver(DeliveryMode::SwapOut)
+            ]
+        );
+        assert_eq!(
+            plan.gas,
+            Gas(FT_TRANSFER_CALL_REF_DEPOSIT_GAS.0
+                + CALLBACK_REF_DEPOSIT_GAS.0
+                + REF_SWAP_GAS.0
+                + callback_ref_swap_gas(CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_OUT_GAS).0)
+        );
+    }
+
+    #[test]
+    fn test_delivery_mode() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+        let native = DeliveryAsset::Native;
+        let token = DeliveryAsset::Token(wrap.clone());
+        let target_call = TargetCall {
+            msg: "deposit".to_string(),
+            gas: U64(20_000_000_000_000),
+        };
+        let hook = Hook {
+            contract_id: account("lending.near"),
+            method_name: "deposit".to_string(),
+            args: "{}".to_string(),
+            gas: U64(30_000_000_000_000),
+        };
+
+        assert_eq!(
+            delivery_mode(config, &wrap, Some(&native), None, None),
+            DeliveryMode::Native
+        );
+        assert_eq!(
+            delivery_mode(config, &wrap, Some(&token), None, None),
+            DeliveryMode::Transfer
+        );
+        assert_eq!(
+            delivery_mode(config, &wrap, Some(&token), Some(&target_call), None),
+            DeliveryMode::Call(Gas(20_000_000_000_000))
+        );
+        assert_eq!(
+            delivery_mode(config, &wrap, Some(&native), None, Some(&hook)),
+            DeliveryMode::Hook {
+                native: true,
+                gas: Gas(30_000_000_000_000)
+            }
+        );
+        assert_eq!(
+            delivery_mode(config, &wrap, Some(&token), None, Some(&hook)),
+            DeliveryMode::Hook {
+                native: false,
+                gas: Gas(30_000_000_000_000)
+            }
+        );
+        // only the wrapped token can be unwrapped
+        assert_eq!(
+            delivery_mode(config, &account("usdc.near"), Some(&native), None, None),
+            DeliveryMode::Transfer
+        );
+        assert_eq!(
+            delivery_mode(config, &wrap, None, None, None),
+            DeliveryMode::SwapOut
+        );
+        assert_eq!(
+            delivery_mode(
+                config,
+                &wrap,
+                Some(&DeliveryAsset::SwapOut {
+                    chain: U64(1),
+                    address: "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2".to_string(),
+                }),
+                None,
+                None
+            ),
+            DeliveryMode::SwapOut
+        );
+    }
+
+    #[test]
+    fn test_validate_hook() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+        let mut msg = msg(
+            vec![swap("usdc.near", "eth.near")],
+            Some(DeliveryAsset::Token(account("eth.near"))),
+        );
+        msg.hook = Some(Hook {
+            contract_id: account("lending.near"),
+            method_name: "ft_on_transfer".to_string(),
+            args: "{}".to_string(),
+            gas: U64(30_000_000_000_000),
+        });
+
+        assert_eq!(validate_swap(config, None, U128(100), &msg), Ok(()));
+        msg.target_call = Some(TargetCall {
+            msg: "deposit".to_string(),
+            gas: U64(20_000_000_000_000),
+        });
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::UnexpectedHook)
+        );
+        msg.target_call = None;
+        msg.hook.as_mut().unwrap().method_name = "deposit".to_string();
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::InvalidHookMethod)
+        );
+        msg.target_token = None;
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::UnexpectedHook)
+        );
+    }
+
+    #[test]
+    fn test_plan_gas_drop() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+        let gas_drop = GasDrop {
+            pool_id: 2,
+            amount: U128(10),
+            min_amount_out: U128(1),
+        };
+        let mut to_eth = msg(
+            vec![swap("usdc.near", "eth.near")],
+            Some(DeliveryAsset::Token(account("eth.near"))),
+        );
+        let gas = plan_swap(config, None, U128(100), &to_eth).unwrap().gas;
+        to_eth.gas_drop = Some(gas_drop.clone());
+
+        let plan = plan_swap(config, None, U128(100), &to_eth).unwrap();
+        assert_eq!(plan.gas, Gas(gas.0 + GAS_DROP_GAS.0));
+        let internal = SwapConfig {
+            swap_mode: SwapMode::Internal,
+            ..config
+        };
+        assert_eq!(
+            validate_swap(internal, None, U128(100), &to_eth),
+            Err(PlanError::InternalGasDrop)
+        );
+        to_eth.gas_drop.as_mut().unwrap().amount = U128(0);
+        assert_eq!(
+            validate_swap(config, None, U128(100), &to_eth),
+            Err(PlanError::ZeroAmount)
+        );
+        to_eth.gas_drop = Some(gas_drop.clone());
+        to_eth.target_token = None;
+        assert_eq!(
+            validate_swap(config, None, U128(100), &to_eth),
+            Err(PlanError::UnexpectedGasDrop)
+        );
+
+        let mut to_wrap = msg(
+            vec![swap("usdc.near", "wrap.near")],
+            Some(DeliveryAsset::Token(account("wrap.near"))),
+        );
+        to_wrap.gas_drop = Some(gas_drop);
+        assert_eq!(
+            validate_swap(config, None, U128(100), &to_wrap),
+            Err(PlanError::UnexpectedGasDrop)
+        );
+        to_wrap.target_token = Some(DeliveryAsset::Native);
+        assert_eq!(
+            validate_swap(config, None, U128(100), &to_wrap),
+            Err(PlanError::UnexpectedGasDrop)
+        );
+    }
+
+    #[test]
+    fn test_plan_target_check() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+        let mut to_eth = msg(
+            vec![swap("usdc.near", "eth.near")],
+            Some(DeliveryAsset::Token(account("eth.near"))),
+        );
+        let gas = plan_swap(config, None, U128(100), &to_eth).unwrap().gas;
+        to_eth.check_target = Some(true);
+
+        let plan = plan_swap(config, None, U128(100), &to_eth).unwrap();
+        assert_eq!(
+            plan.target_check,
+            Some(TargetCheck::Registered(account("eth.near")))
+        );
+        assert_eq!(plan.steps[0], SwapStep::CheckTarget);
+        assert_eq!(
+            plan.gas,
+            Gas(gas.0 + CHECK_TARGET_GAS.0 + CALLBACK_CHECK_TARGET_GAS.0)
+        );
+        // implicit accounts are checked for tokens too
+        to_eth.target_account =
+            account("98793cd91a3f870fb126f66285808c7e094afcfc4eda8a970f6648cdf0dbd6de");
+        assert_eq!(
+            target_check(config, &to_eth),
+            Some(TargetCheck::Registered(account("eth.near")))
+        );
+        to_eth.target_token = None;
+        assert_eq!(target_check(config, &to_eth), None);
+
+        let mut to_near = msg(
+            vec![swap("usdc.near", "wrap.near")],
+            Some(DeliveryAsset::Native),
+        );
+        to_near.check_target = Some(true);
+        assert_eq!(target_check(config, &to_near), Some(TargetCheck::Exists));
+        to_near.target_account =
+            account("98793cd91a3f870fb126f66285808c7e094afcfc4eda8a970f6648cdf0dbd6de");
+        assert_eq!(target_check(config, &to_near), None);
+        to_near.target_account = account("0xb794f5ea0ba39494ce839613fffba74279579268");
+        assert_eq!(target_check(config, &to_near), None);
+        to_near.check_target = None;
+        to_near.target_account = account("alice.near");
+        assert_eq!(target_check(config, &to_near), None);
+    }
+
+    #[test]
+    fn test_plan_legacy_target_token() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+        let zero_address = DeliveryAsset::Legacy(account(ZERO_ADDRESS));
+
+        // the output token is delivered whatever the legacy target token is
+        let mut to_eth = msg(
+            vec![swap("usdc.near", "eth.near")],
+            Some(DeliveryAsset::Legacy(account("dai.near"))),
+        );
+        let plan = plan_swap(config, None, U128(100), &to_eth).unwrap();
+        assert_eq!(plan.delivery, DeliveryMode::Transfer);
+        to_eth.target_token = Some(zero_address.clone());
+        let plan = plan_swap(config, None, U128(100), &to_eth).unwrap();
+        assert_eq!(plan.delivery, DeliveryMode::Transfer);
+        to_eth.check_target = Some(true);
+        assert_eq!(
+            target_check(config, &to_eth),
+            Some(TargetCheck::Registered(account("eth.near")))
+        );
+
+        let mut to_near = msg(
+            vec![swap("usdc.near", "wrap.near")],
+            Some(zero_address.clone()),
+        );
+        let plan = plan_swap(config, None, U128(100), &to_near).unwrap();
+        assert_eq!(plan.delivery, DeliveryMode::Native);
+        to_near.check_target = Some(true);
+        assert_eq!(target_check(config, &to_near), Some(TargetCheck::Exists));
+        to_near.target_token = Some(DeliveryAsset::Legacy(account("usdc.near")));
+        let plan = plan_swap(config, None, U128(100), &to_near).unwrap();
+        assert_eq!(plan.delivery, DeliveryMode::Transfer);
+
+        // the new fields require a typed target token
+        to_near.target_call = Some(TargetCall {
+            msg: "".to_string(),
+            gas: U64(10_000_000_000_000),
+        });
+        assert_eq!(
+            validate_swap(config, None, U128(100), &to_near),
+            Err(PlanError::UnexpectedTargetCall)
+        );
+    }
+
+    #[test]
+    fn test_plan_native_swap_in() {
+        let wrap = account("wrap.near");
+        let msg = msg(
+            vec![swap("usdc.near", "wrap.near")],
+            Some(DeliveryAsset::Native),
+        );
+
+        let plan = plan_swap(config(SwapMode::Execute, &wrap), None, U128(100), &msg).unwrap();
+
+        assert_eq!(plan.delivery, DeliveryMode::Native);
+        assert_eq!(
+            plan.steps.last(),
+            Some(&SwapStep::Deliver(DeliveryMode::Native))
+        );
+    }
+
+    #[test]
+    fn test_plan_min_total_out_gas() {
+        let wrap = account("wrap.near");
+        let mut msg = msg(
+            vec![swap("usdc.near", "eth.near")],
+            Some(DeliveryAsset::Token(account("eth.near"))),
+        );
+        msg.min_total_out = Some(U128(90));
+        let swap_gas_of = |min_out_policy| {
+            let config = SwapConfig {
+                min_out_policy,
+                ..config(SwapMode::Execute, &wrap)
+            };
+            plan_swap(config, None, U128(100), &msg).unwrap().gas
+        };
+
+        let base = FT_TRANSFER_CALL_REF_GAS.0 + CALLBACK_GET_AMOUNT_OUT_GAS.0 + FT_BALANCE_OF_GAS.0;
+        assert_eq!(
+            swap_gas_of(MinOutPolicy::RefundInput),
+            Gas(base
+                + 10_000_000_000_000
+                + base
+                + CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_REFUND_GAS.0)
+        );
+        assert_eq!(
+            swap_gas_of(MinOutPolicy::RefundOutput),
+            Gas(base
+                + std::cmp::max(
+                    CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_SWAP_IN_GAS.0,
+                    CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_REFUND_GAS.0
+                ))
+        );
+    }
+
+    #[test]
+    fn test_plan_errors() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+
+        assert_eq!(
+            plan_swap(config, None, U128(100), &msg(vec![], None)).unwrap_err(),
+            PlanError::EmptyActions
+        );
+        assert_eq!(
+            plan_swap(
+                config,
+                None,
+                U128(0),
+                &msg(vec![swap("usdc.near", "eth.near")], None)
+            )
+            .unwrap_err(),
+            PlanError::ZeroAmount
+        );
+        assert_eq!(
+            plan_swap(
+                config,
+                Some(&account("dai.near")),
+                U128(100),
+                &msg(vec![swap("usdc.near", "eth.near")], None)
+            )
+            .unwrap_err(),
+            PlanError::TokenInMismatch {
+                expected: account("usdc.near"),
+                actual: account("dai.near"),
+            }
+        );
+        assert_eq!(
+            plan_swap(
+                config,
+                None,
+                U128(100),
+                &msg(
+                    vec![
+                        swap("usdc.near", "wrap.near"),
+                        swap("wrap.near", "eth.near"),
+                        swap("dai.near", "usdt.near")
+                    ],
+                    None
+                )
+            )
+            .unwrap_err(),
+            PlanError::BrokenRoute { index: 2 }
+        );
+    }
+
+    #[test]
+    fn test_validate_amount_in() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+        let mut msg = msg(
+            vec![
+                swap("usdc.near", "wrap.near"),
+                swap("wrap.near", "eth.near"),
+            ],
+            None,
+        );
+
+        assert_eq!(validate_swap(config, None, U128(100), &msg), Ok(()));
+        assert_eq!(
+            validate_swap(config, None, U128(99), &msg),
+            Err(PlanError::AmountInExceeded {
+                amount_in: U128(100),
+                amount: U128(99),
+            })
+        );
+
+        let Action::Swap(second_action) = &mut msg.actions[1];
+        second_action.amount_in = Some(U128(1));
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::UnexpectedAmountIn { index: 1 })
+        );
+
+        let Action::Swap(first_action) = &mut msg.actions[0];
+        first_action.amount_in = None;
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::MissingAmountIn)
+        );
+    }
+
+    #[test]
+    fn test_validate_native_target() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+
+        assert_eq!(
+            validate_swap(
+                config,
+                None,
+                U128(100),
+                &msg(
+                    vec![swap("usdc.near", "wrap.near")],
+                    Some(DeliveryAsset::Native)
+                )
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            validate_swap(
+                config,
+                None,
+                U128(100),
+                &msg(
+                    vec![swap("wrap.near", "usdc.near")],
+                    Some(DeliveryAsset::Native)
+                )
+            ),
+            Err(PlanError::InvalidNativeTarget)
+        );
+    }
+
+    #[test]
+    fn test_validate_target_token() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+
+        assert_eq!(
+            validate_swap(
+                config,
+                None,
+                U128(100),
+                &msg(
+                    vec![swap("usdc.near", "eth.near")],
+                    Some(DeliveryAsset::Token(account("wrap.near")))
+                )
+            ),
+            Err(PlanError::TargetTokenMismatch {
+                expected: account("eth.near"),
+                actual: account("wrap.near"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_plan_target_call() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+        let mut msg = msg(
+            vec![swap("usdc.near", "eth.near")],
+            Some(DeliveryAsset::Token(account("eth.near"))),
+        );
+        msg.target_call = Some(TargetCall {
+            msg: "deposit".to_string(),
+            gas: U64(20_000_000_000_000),
+        });
+
+        let plan = plan_swap(config, None, U128(100), &msg).unwrap();
+        assert_eq!(plan.delivery, DeliveryMode::Call(Gas(20_000_000_000_000)));
+        assert_eq!(
+            plan.gas,
+            swap_gas(
+                config,
+                Gas(CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_CALL_GAS.0 + 20_000_000_000_000)
+            )
+        );
+
+        msg.target_call = Some(TargetCall {
+            msg: "deposit".to_string(),
+            gas: U64(MAX_TARGET_CALL_GAS.0 + 1),
+        });
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::TargetCallGasExceeded {
+                gas: U64(MAX_TARGET_CALL_GAS.0 + 1)
+            })
+        );
+        msg.target_token = Some(DeliveryAsset::Native);
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::UnexpectedTargetCall)
+        );
+    }
+
+    fn swap_msg(to_chain: u128) -> SwapMsg {
+        let swap_data = SwapData {
+            swap_param: vec![],
+            target_token: vec![1; 20],
+            to_address: vec![2; 20],
+        };
+        SwapMsg {
+            map_target_token: [0; 20],
+            to_chain: U128(to_chain),
+            swap_data_0: swap_data.clone(),
+            swap_data_1: swap_data,
+        }
+    }
+
+    #[test]
+    fn test_validate_swap_msg() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+        let swap_out = |address: &str| DeliveryAsset::SwapOut {
+            chain: U64(56),
+            address: address.to_string(),
+        };
+        let mut msg = msg(vec![swap("usdc.near", "eth.near")], None);
+        msg.swap_msg = Some(swap_msg(56));
+
+        assert_eq!(validate_swap(config, None, U128(100), &msg), Ok(()));
+        msg.target_token = Some(swap_out("0x0101010101010101010101010101010101010101"));
+        assert_eq!(validate_swap(config, None, U128(100), &msg), Ok(()));
+        msg.swap_msg = Some(swap_msg(1));
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::ToChainMismatch {
+                expected: U128(56),
+                actual: U128(1),
+            })
+        );
+        msg.swap_msg = None;
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::MissingSwapMsg)
+        );
+
+        msg.swap_msg = Some(swap_msg(56));
+        msg.target_token = Some(swap_out("0101010101010101010101010101010101010101"));
+        assert_eq!(validate_swap(config, None, U128(100), &msg), Ok(()));
+        msg.target_token = Some(swap_out("0x55d398326f99059ff775485246999027b3197955"));
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::SwapOutAddressMismatch)
+        );
+        for address in ["", "0x", "0x010", "0x010g"] {
+            msg.target_token = Some(swap_out(address));
+            assert_eq!(
+                validate_swap(config, None, U128(100), &msg),
+                Err(PlanError::InvalidSwapOutAddress)
+            );
+        }
+        msg.target_token = Some(DeliveryAsset::Token(account("eth.near")));
+        assert_eq!(
+            validate_swap(config, None, U128(100), &msg),
+            Err(PlanError::UnexpectedSwapMsg)
+        );
+    }
+
+    #[test]
+    fn test_swap_out_msg() {
+        assert_eq!(swap_out_msg(None), "");
+
+        let swap_msg = swap_msg(56);
+        let msg = swap_out_msg(Some(&swap_msg));
+        assert_eq!(serde_json::from_str::<SwapMsg>(&msg).unwrap(), swap_msg);
+    }
+
+    #[test]
+    fn test_transfer_to_target_account_gas_of_reverse_swap() {
+        let wrap = account("wrap.near");
+        let config = config(SwapMode::Execute, &wrap);
+        let ctx = SwapContext {
+            order_id: 0,
+            actions: vec![swap("usdc.near", "eth.near")],
+            token_in: account("usdc.near"),
+            amount_in: U128(100),
+            token_out: account("eth.near"),
+            target_account: account("alice.near"),
+            target_token: Some(DeliveryAsset::Token(account("eth.near"))),
+            swap_msg: None,
+            target_call: None,
+            hook: None,
+            relayer_fee: None,
+            gas_drop: None,
+            direct_call: true,
+            min_total_out: Some(U128(300)),
+            on_failure: FailurePolicy::RefundToMos,
+            on_refund: RefundPolicy::LostAndFound,
+            reverted_amount_in: None,
+            is_gas_drop: false,
+            balance_before: None,
+        };
+
+        assert_eq!(
+            transfer_to_target_account_gas(config, &ctx),
+            reverse_gas(config)
+        );
+        assert_eq!(
+            transfer_to_target_account_gas(config, &ctx.reverse(U128(200))),
+            CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_REFUND_GAS
+        );
+
+        // the swap back goes through ref internal account in the internal mode
+        let internal = SwapConfig {
+            swap_mode: SwapMode::Internal,
+            ..config
+        };
+        assert_eq!(
+            transfer_to_target_account_gas(internal, &ctx),
+            Gas(10_000_000_000_000
+                + swap_gas(internal, CALLBACK_TRANSFER_TO_TARGET_ACCOUNT_REFUND_GAS).0)
+        );
+    }
+
+    #[test]
+    fn test_reverse_min_amount_out() {
+        let ctx = SwapContext {
+            order_id: 0,
+            actions: vec![
+                swap("usdc.near", "wrap.near"),
+                swap("wrap.near", "eth.near"),
+            ],
+            token_in: account("usdc.near"),
+            amount_in: U128(10_000),
+            token_out: account("eth.near"),
+            target_account: account("alice.near"),
+            target_token: Some(DeliveryAsset::Token(account("eth.near"))),
+            swap_msg: None,
+            target_call: None,
+            hook: None,
+            relayer_fee: None,
+            gas_drop: None,
+            direct_call: true,
+            min_total_out: Some(U128(300)),
+            on_failure: FailurePolicy::RefundToMos,
+            on_refund: RefundPolicy::LostAndFound,
+            reverted_amount_in: None,
+            is_gas_drop: false,
+            balance_before: None,
+        };
+
+        let reverse = ctx.reverse(U128(200));
+        let min_amounts_out: Vec<U128> = reverse
+            .actions
+            .iter()
+            .map(|action| {
+                let Action::Swap(action) = action;
+                action.min_amount_out
+            })
+            .collect();
+        // 1% per action of the input amount
+        assert_eq!(min_amounts_out, vec![U128(0), U128(9_800)]);
+        assert_eq!(reverse.reverted_amount_in, Some(U128(10_000)));
+    }
+
+    #[test]
+    fn test_execute_msg() {
+        let msg = execute_msg(&[swap("usdc.near", "eth.near")]);
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&msg).unwrap(),
+            serde_json::json!({
+                "referral_id": null,
+                "actions": [{
+                    "pool_id": 0,
+                    "token_in": "usdc.near",
+                    "amount_in": null,
+                    "token_out": "eth.near",
+                    "min_amount_out": "0",
+                }],
+            })
+        );
+    }
+}
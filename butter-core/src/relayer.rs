@@ -0,0 +1,153 @@
+use crate::types::{RelayerFee, SwapContext};
+use crate::*;
+use near_sdk::json_types::U64;
+
+/// Gas to call callback_withdraw_relayer_fee method.
+const CALLBACK_WITHDRAW_RELAYER_FEE_GAS: Gas = Gas(5_000_000_000_000);
+
+#[near_bindgen]
+impl ButterCore {
+    /// Max relayer fee of a swap whose output is `token`, relayer fees are not allowed if it is 0.
+    pub fn get_relayer_fee_cap(&self, token: AccountId) -> U128 {
+        U128(self.relayer_fee_caps.get(&token).unwrap_or(0))
+    }
+
+    pub fn set_relayer_fee_cap(&mut self, token: AccountId, cap: U128) {
+        self.assert_owner();
+        if cap.0 == 0 {
+            self.relayer_fee_caps.remove(&token);
+        } else {
+            self.relayer_fee_caps.insert(&token, &cap.0);
+        }
+    }
+
+    /// Balance of `relayer` in `token`, which is withdrawable by the relayer.
+    pub fn get_relayer_fee(&self, relayer: AccountId, token: AccountId) -> U128 {
+        U128(self.relayer_fees.get(&(relayer, token)).unwrap_or(0))
+    }
+
+    /// Withdraw `amount` of the relayer fees in `token` of the caller, all of them if `amount` is
+    /// none. Requires 1 yoctoNEAR.
+    #[payable]
+    pub fn withdraw_relayer_fee(&mut self, token: AccountId, amount: Option<U128>) -> Promise {
+        ensure!(
+            env::attached_deposit() >= 1,
+            ButterCoreError::NotEnoughDeposit
+        );
+        let relayer = env::predecessor_account_id();
+        let balance = self
+            .relayer_fees
+            .get(&(relayer.clone(), token.clone()))
+            .unwrap_or(0);
+        let amount = amount.map_or(balance, |amount| amount.0);
+        ensure!(
+            amount > 0 && amount <= balance,
+            ButterCoreError::NotEnoughBalance {
+                available: U128(balance)
+            }
+        );
+        self.debit_relayer_fee(&relayer, &token, amount);
+
+        ext_ft_core::ext(token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(1)
+            .ft_transfer(relayer.clone(), U128(amount), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_WITHDRAW_RELAYER_FEE_GAS)
+                    .callback_withdraw_relayer_fee(relayer, token, U128(amount)),
+            )
+    }
+
+    /// Give the fees back to the relayer balance if the withdrawal failed.
+    #[private]
+    pub fn callback_withdraw_relayer_fee(
+        &mut self,
+        relayer: AccountId,
+        token: AccountId,
+        amount: U128,
+    ) -> bool {
+        match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => true,
+            PromiseResult::Failed => {
+                log!(
+                    "withdraw {} {} to relayer {} failed",
+                    amount.0,
+                    token,
+                    relayer
+                );
+                self.credit_relayer_fee(&relayer, &token, amount.0);
+                false
+            }
+        }
+    }
+}
+
+impl ButterCore {
+    /// Check the relayer fee is within the cap of the output token.
+    pub(crate) fn check_relayer_fee(
+        &self,
+        token_out: &AccountId,
+        fee: &RelayerFee,
+    ) -> Result<(), ButterCoreError> {
+        let cap = self.relayer_fee_caps.get(token_out).unwrap_or(0);
+        if fee.amount.0 > cap {
+            return Err(ButterCoreError::RelayerFeeExceeded {
+                fee: fee.amount,
+                cap: U128(cap),
+            });
+        }
+        Ok(())
+    }
+
+    /// Deduct the relayer fee of the swap from `amount_out` and credit it to the relayer, the fee
+    /// is not charged if it is not less than `amount_out`. Returns the amount left to deliver.
+    pub(crate) fn charge_relayer_fee(&mut self, ctx: &SwapContext, amount_out: U128) -> U128 {
+        let fee = match &ctx.relayer_fee {
+            Some(fee) if fee.amount.0 > 0 => fee,
+            _ => return amount_out,
+        };
+        if fee.amount.0 >= amount_out.0 {
+            log!(
+                "relayer fee {} is not less than amount out {}, not charged",
+                fee.amount.0,
+                amount_out.0
+            );
+            return amount_out;
+        }
+        self.credit_relayer_fee(&fee.relayer, &ctx.token_out, fee.amount.0);
+        CoreEvent::RelayerFeeCharged {
+            order_id: U64(ctx.order_id),
+            relayer: &fee.relayer,
+            token: &ctx.token_out,
+            amount: fee.amount,
+        }
+        .emit();
+        U128(amount_out.0 - fee.amount.0)
+    }
+
+    /// The relayer fees are held by the core, they are reserved as the pending amounts so they
+    /// are neither taken as the swap output nor swept.
+    fn credit_relayer_fee(&mut self, relayer: &AccountId, token: &AccountId, amount: Balance) {
+        let key = (relayer.clone(), token.clone());
+        let balance = self.relayer_fees.get(&key).unwrap_or(0);
+        self.relayer_fees.insert(&key, &(balance + amount));
+        let pending_amount = self.pending_amounts.get(token).unwrap_or(0);
+        self.pending_amounts
+            .insert(token, &(pending_amount + amount));
+    }
+
+    fn debit_relayer_fee(&mut self, relayer: &AccountId, token: &AccountId, amount: Balance) {
+        let key = (relayer.clone(), token.clone());
+        let balance = self.relayer_fees.get(&key).unwrap_or(0) - amount;
+        if balance == 0 {
+            self.relayer_fees.remove(&key);
+        } else {
+            self.relayer_fees.insert(&key, &balance);
+        }
+        let pending_amount = self.pending_amounts.get(token).unwrap_or(0);
+        self.pending_amounts
+            .insert(token, &pending_amount.saturating_sub(amount));
+    }
+}
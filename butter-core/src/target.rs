@@ -0,0 +1,75 @@
+use crate::planner::TargetCheck;
+use crate::*;
+use near_sdk::json_types::U64;
+use near_sdk::serde_json::Value;
+
+#[near_bindgen]
+impl ButterCore {
+    /// Start the swap if the target account passed the check, otherwise refund the input.
+    /// `token` is the token the target account should be registered in, none if the target
+    /// account should exist.
+    #[private]
+    pub fn callback_check_target(
+        &self,
+        swap_mode: SwapMode,
+        token: Option<AccountId>,
+        ctx: SwapContext,
+    ) -> PromiseOrValue<(U128, U128)> {
+        // a token which doesn't implement storage_balance_of can't be checked, the swap goes on
+        let available = match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) => {
+                token.is_none()
+                    || serde_json::from_slice::<Value>(&x)
+                        .map_or(true, |balance| !balance.is_null())
+            }
+            PromiseResult::Failed => token.is_some(),
+        };
+        if available {
+            return self.do_swap_in_mode(swap_mode, ctx).into();
+        }
+
+        CoreEvent::OrderFailed {
+            order_id: Some(U64(ctx.order_id)),
+            error: &ButterCoreError::TargetAccountUnavailable {
+                account_id: ctx.target_account.clone(),
+                token,
+            },
+        }
+        .emit();
+        self.refund(ctx, U128(0))
+    }
+}
+
+impl ButterCore {
+    /// Check the target account before the swap, see `planner::target_check`.
+    pub(crate) fn check_target(
+        &self,
+        target_check: &TargetCheck,
+        swap_mode: SwapMode,
+        ctx: SwapContext,
+    ) -> Promise {
+        let (check, token) = match target_check {
+            TargetCheck::Registered(token) => (
+                ext_storage::ext(token.clone())
+                    .with_static_gas(CHECK_TARGET_GAS)
+                    .storage_balance_of(ctx.target_account.clone()),
+                Some(token.clone()),
+            ),
+            // a transfer to a named account which doesn't exist fails
+            TargetCheck::Exists => (Promise::new(ctx.target_account.clone()).transfer(0), None),
+        };
+        let swap_gas = planner::swap_gas(
+            SwapConfig {
+                swap_mode,
+                ..self.swap_config()
+            },
+            self.transfer_to_target_account_gas(&ctx),
+        );
+        check.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(Gas(CALLBACK_CHECK_TARGET_GAS.0 + swap_gas.0))
+                .callback_check_target(swap_mode, token, ctx),
+        )
+    }
+}
@@ -1,4 +1,6 @@
-use near_sdk::json_types::{U128, U64};
+use crate::errors::ButterCoreError;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{Base58CryptoHash, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::AccountId;
 
@@ -22,7 +24,413 @@ pub struct CoreSwapMessage {
     /// List of sequential actions.
     pub actions: Vec<Action>,
     pub target_account: AccountId,
-    pub target_token: Option<AccountId>,
+    /// What is delivered to target_account, a swap out if none.
+    pub target_token: Option<DeliveryAsset>,
+    /// Onward swap of a swap out, which is the msg of ft_transfer_call to MOS.
+    pub swap_msg: Option<SwapMsg>,
+    /// Deliver the output token of a swap in with ft_transfer_call instead of ft_transfer.
+    pub target_call: Option<TargetCall>,
+    /// Call an allowed contract with the output of a swap in instead of delivering it.
+    pub hook: Option<Hook>,
+    /// Fee of the relayer which pays for the swap, deducted from the output.
+    pub relayer_fee: Option<RelayerFee>,
+    /// Swap a part of the output of a swap in to NEAR for the gas of the target account.
+    pub gas_drop: Option<GasDrop>,
+    /// Check the target account of a swap in before the swap, the input is refunded if the
+    /// output can't be delivered to it.
+    pub check_target: Option<bool>,
+    /// Required minimum amount delivered to target_account, checked after all deductions.
+    pub min_total_out: Option<U128>,
+    /// Where the tokens go if the swap can't be completed, default to `FailurePolicy::RefundToMos`.
+    pub on_failure: Option<FailurePolicy>,
+    /// What is done with the part of a swap out refunded by MOS, default to
+    /// `RefundPolicy::LostAndFound`.
+    pub on_refund: Option<RefundPolicy>,
+}
+
+/// Legacy `target_token` of a swap in which delivers native NEAR.
+pub const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+/// Max loss of each action of the swap back to the input token after a `min_total_out` miss, in
+/// basis points of the input amount. It covers the pool fees of both swaps.
+pub const REVERSE_SWAP_LOSS_BPS: u128 = 100;
+
+/// Whether `account_id` is an implicit account, whose id is the 64 lowercase hex characters of its
+/// ed25519 public key, or `0x` and the 40 lowercase hex characters of an Ethereum address. An
+/// implicit account is created by a transfer of NEAR to it, a named account is not.
+pub fn is_implicit_account(account_id: &AccountId) -> bool {
+    let account_id = account_id.as_str();
+    let is_hex = |s: &str| s.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'));
+    match account_id.strip_prefix("0x") {
+        Some(address) => address.len() == 40 && is_hex(address),
+        None => account_id.len() == 64 && is_hex(account_id),
+    }
+}
+
+/// What is delivered to the target account of a swap.
+///
+/// It is `{"native": {}}`, `{"token": "<account id>"}` or
+/// `{"swap_out": {"chain": "<chain id>", "address": "<token address>"}}` in JSON. A plain account
+/// id is the legacy target token, which is kept as is for backward compatibility, even if it is
+/// `"native"`.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(from = "DeliveryAssetRepr", into = "DeliveryAssetRepr")]
+pub enum DeliveryAsset {
+    /// NEAR unwrapped from the output, which should be the wrapped token.
+    Native,
+    /// The output token, which should be the given token.
+    Token(AccountId),
+    /// The output token bridged by MOS to the token `address` on `chain`.
+    SwapOut { chain: U64, address: String },
+    /// Legacy target token, the output token is delivered whatever the account id is, or native
+    /// NEAR if it is `ZERO_ADDRESS` and the output is the wrapped token.
+    Legacy(AccountId),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+enum DeliveryAssetDef {
+    Native {},
+    Token(AccountId),
+    SwapOut { chain: U64, address: String },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(untagged)]
+enum DeliveryAssetRepr {
+    Legacy(AccountId),
+    Asset(DeliveryAssetDef),
+}
+
+impl From<DeliveryAssetRepr> for DeliveryAsset {
+    fn from(repr: DeliveryAssetRepr) -> Self {
+        match repr {
+            DeliveryAssetRepr::Asset(DeliveryAssetDef::Native {}) => DeliveryAsset::Native,
+            DeliveryAssetRepr::Asset(DeliveryAssetDef::Token(token)) => DeliveryAsset::Token(token),
+            DeliveryAssetRepr::Asset(DeliveryAssetDef::SwapOut { chain, address }) => {
+                DeliveryAsset::SwapOut { chain, address }
+            }
+            DeliveryAssetRepr::Legacy(token) => DeliveryAsset::Legacy(token),
+        }
+    }
+}
+
+impl From<DeliveryAsset> for DeliveryAssetRepr {
+    fn from(asset: DeliveryAsset) -> Self {
+        match asset {
+            DeliveryAsset::Native => DeliveryAssetRepr::Asset(DeliveryAssetDef::Native {}),
+            DeliveryAsset::Token(token) => DeliveryAssetRepr::Asset(DeliveryAssetDef::Token(token)),
+            DeliveryAsset::SwapOut { chain, address } => {
+                DeliveryAssetRepr::Asset(DeliveryAssetDef::SwapOut { chain, address })
+            }
+            DeliveryAsset::Legacy(token) => DeliveryAssetRepr::Legacy(token),
+        }
+    }
+}
+
+/// ft_transfer_call of the output token to the target account, e.g. to deposit it into a
+/// contract.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TargetCall {
+    pub msg: String,
+    /// Gas of ft_on_transfer of the target account.
+    pub gas: U64,
+}
+
+/// Call of an allowed contract method with the output of a swap in. For native NEAR, the method is
+/// called with the NEAR attached, for a token, the method should be `ft_on_transfer` and the
+/// token is sent to the contract by ft_transfer_call with `args` as the msg. The output not used
+/// by the hook is delivered to the target account.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Hook {
+    pub contract_id: AccountId,
+    pub method_name: String,
+    /// JSON arguments of the method.
+    pub args: String,
+    pub gas: U64,
+}
+
+/// Contract method allowed to be called by hooks.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HookConfig {
+    pub contract_id: AccountId,
+    pub method_name: String,
+    pub max_gas: U64,
+}
+
+/// Fee paid to the relayer in the output token, or in wrapped NEAR if the target is native. It is
+/// credited to the balance of the relayer in the core, which the relayer withdraws later.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RelayerFee {
+    pub relayer: AccountId,
+    pub amount: U128,
+}
+
+/// Swap `amount` of the output token to wrapped NEAR in ref pool `pool_id`, the NEAR unwrapped is
+/// sent to the target account along with the rest of the output.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GasDrop {
+    pub pool_id: u64,
+    pub amount: U128,
+    pub min_amount_out: U128,
+}
+
+/// Result of an item of `batch_swap`.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchSwapResult {
+    pub amount_in: U128,
+    pub amount_out: U128,
+    /// Set if the swap is not started or failed.
+    pub error: Option<String>,
+    /// Code of `error`, see `ButterCoreError`.
+    pub error_code: Option<String>,
+}
+
+impl BatchSwapResult {
+    pub fn new(amount_in: U128, amount_out: U128, error: Option<ButterCoreError>) -> Self {
+        Self {
+            amount_in,
+            amount_out,
+            error_code: error.as_ref().map(|e| e.code().to_string()),
+            error: error.map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Where ButterCore sends the tokens it can't deliver as requested, e.g. the input token left by a
+/// failed swap in ref exchange, or the output token when the delivery to target_account failed.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Give the tokens back to MOS.
+    RefundToMos,
+    /// Deliver the input token to target_account.
+    DeliverToTarget,
+    /// Deliver the tokens to the fallback account.
+    DeliverToFallback(AccountId),
+}
+
+/// What ButterCore does with the part of a swap out that MOS refunds to it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum RefundPolicy {
+    /// Give the tokens back to MOS, which sent them for the swap.
+    ReturnToSender,
+    /// Send the tokens to the fallback account or MOS lost and found.
+    #[default]
+    LostAndFound,
+    /// Queue the swap out to be sent to MOS again by `retry_delivery`, it is sent to the fallback
+    /// account or MOS lost and found after `max_delivery_attempts` attempts.
+    Retry,
+}
+
+/// How ButterCore drives the swap on ref exchange.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum SwapMode {
+    /// Swap with ref exchange's `ft_transfer_call` Execute flow, the output lands in the core
+    /// account and is measured with `ft_balance_of`.
+    Execute,
+    /// Keep funds in the core's ref exchange internal account: deposit, `swap`, then `withdraw`
+    /// the exact amount returned by the swap.
+    Internal,
+}
+
+/// What ButterCore does when the final output is less than `min_total_out`.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum MinOutPolicy {
+    /// Swap the output back to the input token and refund it to controller.
+    RefundInput,
+    /// Return the output token to controller as is.
+    RefundOutput,
+}
+
+/// A transfer from ButterCore to a user.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Delivery {
+    pub token: AccountId,
+    pub account_id: AccountId,
+    pub amount: U128,
+    pub is_native: bool,
+    /// Receives the tokens if the delivery can't be done, MOS lost and found if none.
+    pub fallback_account: Option<AccountId>,
+    /// Msg of the ft_transfer_call which delivers a swap out to MOS, none for ft_transfer.
+    #[serde(default)]
+    pub msg: Option<String>,
+}
+
+/// A failed delivery waiting to be retried.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingDelivery {
+    pub delivery: Delivery,
+    /// Number of failed attempts.
+    pub attempts: u32,
+    /// Block timestamp of the first failed attempt in nanoseconds.
+    pub created_at: U64,
+}
+
+/// Context of a single swap, passed along the callback chain.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapContext {
+    pub order_id: u64,
+    pub actions: Vec<Action>,
+    pub token_in: AccountId,
+    pub amount_in: U128,
+    pub token_out: AccountId,
+    pub target_account: AccountId,
+    pub target_token: Option<DeliveryAsset>,
+    pub swap_msg: Option<SwapMsg>,
+    pub target_call: Option<TargetCall>,
+    pub hook: Option<Hook>,
+    pub relayer_fee: Option<RelayerFee>,
+    pub gas_drop: Option<GasDrop>,
+    /// True if called by `swap`, false if called by `ft_on_transfer`.
+    pub direct_call: bool,
+    pub min_total_out: Option<U128>,
+    pub on_failure: FailurePolicy,
+    #[serde(default)]
+    pub on_refund: RefundPolicy,
+    /// Set if this swap reverts a swap whose output is less than its `min_total_out`,
+    /// the value is the amount in of the reverted swap. The output is refunded instead of delivered.
+    pub reverted_amount_in: Option<U128>,
+    /// True if this swap is the gas drop of another swap, see `SwapContext::gas_drop`.
+    #[serde(default)]
+    pub is_gas_drop: bool,
+    /// Balance of the output token held by the core before the swap, set for the gas drop whose
+    /// output is measured against it instead of the pending amount of the wrapped NEAR.
+    #[serde(default)]
+    pub balance_before: Option<U128>,
+}
+
+impl SwapContext {
+    /// The token received from MOS.
+    pub fn original_token_in(&self) -> &AccountId {
+        if self.reverted_amount_in.is_some() {
+            &self.token_out
+        } else {
+            &self.token_in
+        }
+    }
+
+    /// The account which receives the tokens that can't be delivered to target_account,
+    /// none if they should be given back to MOS.
+    pub fn fallback_account(&self) -> Option<AccountId> {
+        match &self.on_failure {
+            FailurePolicy::DeliverToFallback(account) => Some(account.clone()),
+            _ => None,
+        }
+    }
+
+    /// Context of the swap that turns the output of this swap back into its input token.
+    /// It should give back at least the input of this swap less `REVERSE_SWAP_LOSS_BPS` per
+    /// action, otherwise ref exchange rejects it and the output is given back instead.
+    pub fn reverse(&self, amount_out: U128) -> Self {
+        let last = self.actions.len() - 1;
+        let actions: Vec<Action> = self
+            .actions
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, action)| {
+                let Action::Swap(swap_action) = action;
+                Action::Swap(SwapAction {
+                    pool_id: swap_action.pool_id,
+                    token_in: swap_action.token_out.clone(),
+                    amount_in: if i == 0 { Some(amount_out) } else { None },
+                    token_out: swap_action.token_in.clone(),
+                    min_amount_out: if i == last {
+                        self.min_reverse_amount_out()
+                    } else {
+                        U128(0)
+                    },
+                })
+            })
+            .collect();
+
+        Self {
+            order_id: self.order_id,
+            actions,
+            token_in: self.token_out.clone(),
+            amount_in: amount_out,
+            token_out: self.token_in.clone(),
+            target_account: self.target_account.clone(),
+            target_token: None,
+            swap_msg: None,
+            target_call: None,
+            hook: None,
+            relayer_fee: None,
+            gas_drop: None,
+            direct_call: self.direct_call,
+            min_total_out: None,
+            on_failure: self.on_failure.clone(),
+            on_refund: self.on_refund,
+            reverted_amount_in: Some(self.amount_in),
+            is_gas_drop: false,
+            balance_before: None,
+        }
+    }
+
+    fn min_reverse_amount_out(&self) -> U128 {
+        let loss_bps = std::cmp::min(REVERSE_SWAP_LOSS_BPS * self.actions.len() as u128, 10_000);
+        let amount_in = self.amount_in.0;
+        // split the amount to avoid overflow
+        U128(
+            amount_in / 10_000 * (10_000 - loss_bps)
+                + amount_in % 10_000 * (10_000 - loss_bps) / 10_000,
+        )
+    }
+
+    /// Context of the gas drop of this swap, which swaps a part of its output to wrapped NEAR and
+    /// delivers it as NEAR to the target account, or the part of the output if it fails.
+    pub fn gas_drop(&self, gas_drop: &GasDrop, wrapped_token: &AccountId) -> Self {
+        Self {
+            order_id: self.order_id,
+            actions: vec![Action::Swap(SwapAction {
+                pool_id: gas_drop.pool_id,
+                token_in: self.token_out.clone(),
+                amount_in: Some(gas_drop.amount),
+                token_out: wrapped_token.clone(),
+                min_amount_out: gas_drop.min_amount_out,
+            })],
+            token_in: self.token_out.clone(),
+            amount_in: gas_drop.amount,
+            token_out: wrapped_token.clone(),
+            target_account: self.target_account.clone(),
+            target_token: Some(DeliveryAsset::Native),
+            swap_msg: None,
+            target_call: None,
+            hook: None,
+            relayer_fee: None,
+            gas_drop: None,
+            direct_call: true,
+            min_total_out: None,
+            on_failure: FailurePolicy::DeliverToTarget,
+            on_refund: RefundPolicy::LostAndFound,
+            reverted_amount_in: None,
+            is_gas_drop: true,
+            balance_before: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -82,3 +490,235 @@ pub struct SwapMsg {
     pub swap_data_0: SwapData,
     pub swap_data_1: SwapData,
 }
+
+/// Code staged by the owner to upgrade the core, the code itself is stored by its hash.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StagedUpgrade {
+    pub code_hash: Base58CryptoHash,
+    /// Block timestamp when the code is staged.
+    pub staged_at: U64,
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradeStatus {
+    Succeeded,
+    Failed,
+}
+
+/// The last code upgrade of the core, staged, from the factory or a rollback.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradeRecord {
+    pub code_hash: Base58CryptoHash,
+    /// Hash of the code replaced by the upgrade, if known.
+    pub previous_code_hash: Option<Base58CryptoHash>,
+    /// State version before the upgrade, a rollback requires the state version to be unchanged.
+    pub previous_state_version: u32,
+    pub status: UpgradeStatus,
+    /// Block timestamp when the status is updated.
+    pub updated_at: U64,
+}
+
+/// A sensitive change, which takes effect through a timelocked proposal.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigChange {
+    Owner(AccountId),
+    /// Hand the ownership to `threshold` of `admins`, see `setup_multisig`.
+    Multisig {
+        admins: Vec<AccountId>,
+        threshold: u32,
+    },
+    Controller(AccountId),
+    RefExchange(AccountId),
+    WrappedToken(AccountId),
+    /// The factory which can stage upgrades, e.g. after the migration of a core which is not a
+    /// sub account of its factory.
+    Factory(AccountId),
+    /// Set or remove the guardian, who can cancel proposals.
+    Guardian(Option<AccountId>),
+    /// Delay of the proposals in nanoseconds.
+    TimelockDelay(U64),
+    /// Delay between staging and applying an upgrade in nanoseconds.
+    UpgradeDelay(U64),
+    /// Transfer `amount` of `token`, or NEAR if `token` is none, which is not reserved for
+    /// pending deliveries or relayer fees.
+    Sweep {
+        token: Option<AccountId>,
+        receiver_id: AccountId,
+        amount: U128,
+    },
+    /// Withdraw `amount` of `token` from the internal account of the core in ref exchange to the
+    /// core, e.g. the tokens left there by a withdraw which failed, which can be swept then.
+    RefWithdraw {
+        token: AccountId,
+        amount: U128,
+    },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Proposal {
+    pub change: ConfigChange,
+    /// Block timestamp when the change is proposed.
+    pub proposed_at: U64,
+    /// Block timestamp from which the change can be executed.
+    pub executable_at: U64,
+}
+
+/// An action of the owner, executed by the core itself once approved by the multisig admins.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum AdminAction {
+    SetSwapMode(SwapMode),
+    SetMinOutPolicy(MinOutPolicy),
+    SetMaxDeliveryAttempts(u32),
+    SetDeliveryTimeout(U64),
+    /// Register in ref exchange with `deposit` from the core balance.
+    RegisterInRefExchange {
+        token_ids: Vec<AccountId>,
+        deposit: U128,
+    },
+    ProposeConfigChange(ConfigChange),
+    ExecuteConfigChange(U64),
+    CancelConfigChange(U64),
+    ApplyUpgrade(Base58CryptoHash),
+    CancelUpgrade,
+    RollbackUpgrade,
+    SetHook(HookConfig),
+    RemoveHook {
+        contract_id: AccountId,
+        method_name: String,
+    },
+    SetRelayerFeeCap {
+        token: AccountId,
+        cap: U128,
+    },
+    SetGasDropCap {
+        token: AccountId,
+        cap: U128,
+    },
+    SetMaxGasDrops(u32),
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AdminProposal {
+    pub action: AdminAction,
+    pub proposer: AccountId,
+    pub approvals: Vec<AccountId>,
+    /// Block timestamp when the action is proposed.
+    pub created_at: U64,
+    /// Block timestamp after which the proposal can no longer be approved or executed.
+    pub expires_at: U64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::serde_json::{self, json};
+
+    fn target_token(value: serde_json::Value) -> DeliveryAsset {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_delivery_asset_from_json() {
+        assert_eq!(target_token(json!({"native": {}})), DeliveryAsset::Native);
+        assert_eq!(
+            target_token(json!({"token": "eth.near"})),
+            DeliveryAsset::Token("eth.near".parse().unwrap())
+        );
+        assert_eq!(
+            target_token(json!({"swap_out": {"chain": "1", "address": "0xc02a"}})),
+            DeliveryAsset::SwapOut {
+                chain: U64(1),
+                address: "0xc02a".to_string()
+            }
+        );
+        // legacy target tokens
+        assert_eq!(
+            target_token(json!(ZERO_ADDRESS)),
+            DeliveryAsset::Legacy(ZERO_ADDRESS.parse().unwrap())
+        );
+        assert_eq!(
+            target_token(json!("eth.near")),
+            DeliveryAsset::Legacy("eth.near".parse().unwrap())
+        );
+        assert_eq!(
+            target_token(json!("native")),
+            DeliveryAsset::Legacy("native".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_delivery_asset_round_trip() {
+        for asset in [
+            DeliveryAsset::Native,
+            DeliveryAsset::Token("native".parse().unwrap()),
+            DeliveryAsset::Legacy("native".parse().unwrap()),
+            DeliveryAsset::Legacy(ZERO_ADDRESS.parse().unwrap()),
+        ] {
+            let value = serde_json::to_value(&asset).unwrap();
+            assert_eq!(target_token(value), asset);
+        }
+    }
+
+    #[test]
+    fn test_is_implicit_account() {
+        let account = |account_id: &str| account_id.parse::<AccountId>().unwrap();
+        assert!(is_implicit_account(&account(
+            "98793cd91a3f870fb126f66285808c7e094afcfc4eda8a970f6648cdf0dbd6de"
+        )));
+        assert!(!is_implicit_account(&account("alice.near")));
+        assert!(!is_implicit_account(&account(
+            "98793cd91a3f870fb126f66285808c7e094afcfc4eda8a970f6648cdf0dbd6d"
+        )));
+        assert!(!is_implicit_account(&account(
+            "98793cd91a3f870fb126f66285808c7e094afcfc4eda8a970f6648cdf0dbd6dg"
+        )));
+    }
+
+    #[test]
+    fn test_is_eth_implicit_account() {
+        let account = |account_id: &str| account_id.parse::<AccountId>().unwrap();
+        assert!(is_implicit_account(&account(
+            "0xb794f5ea0ba39494ce839613fffba74279579268"
+        )));
+        assert!(!is_implicit_account(&account(
+            "0xb794f5ea0ba39494ce839613fffba7427957926"
+        )));
+        assert!(!is_implicit_account(&account(
+            "0xb794f5ea0ba39494ce839613fffba742795792681"
+        )));
+        assert!(!is_implicit_account(&account(
+            "0xb794f5ea0ba39494ce839613fffba7427957926g"
+        )));
+        assert!(!is_implicit_account(&account(
+            "b794f5ea0ba39494ce839613fffba74279579268"
+        )));
+    }
+
+    #[test]
+    fn test_delivery_asset_to_json() {
+        assert_eq!(
+            serde_json::to_value(DeliveryAsset::Native).unwrap(),
+            json!({"native": {}})
+        );
+        assert_eq!(
+            serde_json::to_value(DeliveryAsset::Token("eth.near".parse().unwrap())).unwrap(),
+            json!({"token": "eth.near"})
+        );
+        assert_eq!(
+            serde_json::to_value(DeliveryAsset::Legacy("eth.near".parse().unwrap())).unwrap(),
+            json!("eth.near")
+        );
+    }
+}
@@ -0,0 +1,494 @@
+use crate::events::CoreEvent;
+use crate::migration::read_state_version;
+use crate::types::{StagedUpgrade, UpgradeRecord, UpgradeStatus};
+use crate::*;
+use near_sdk::json_types::U64;
+use near_sdk::serde_json::json;
+use near_sdk::CryptoHash;
+
+/// 1 day in nanoseconds.
+pub const DEFAULT_UPGRADE_DELAY: u64 = 24 * 3600 * 1_000_000_000;
+/// 1 hour in nanoseconds, the upgrade delay can't be set below it.
+pub const MIN_UPGRADE_DELAY: u64 = 3600 * 1_000_000_000;
+
+/// Gas to call get_code_by_hash on the factory.
+const FACTORY_GET_CODE_GAS: Gas = Gas(30_000_000_000_000);
+/// Gas to call callback_stage_code method.
+const CALLBACK_STAGE_CODE_GAS: Gas = Gas(30_000_000_000_000);
+/// Gas to call callback_deploy_code method, not include gas used by migrate.
+const CALLBACK_DEPLOY_CODE_GAS: Gas = Gas(40_000_000_000_000);
+/// Min gas of migrate in a rollback, whose callback_deploy_code gets the gas left by
+/// rollback_upgrade.
+const MIN_MIGRATE_GAS: Gas = Gas(20_000_000_000_000);
+/// Gas kept by the rollback to create the receipts, besides the gas attached to them.
+const ROLLBACK_RESERVED_GAS: Gas = Gas(20_000_000_000_000);
+/// Gas to call callback_apply_upgrade method.
+const CALLBACK_APPLY_UPGRADE_GAS: Gas = Gas(10_000_000_000_000);
+/// Gas to create the receipt of callback_apply_upgrade, besides the gas attached to it.
+const CALLBACK_APPLY_UPGRADE_RECEIPT_GAS: Gas = Gas(10_000_000_000_000);
+
+#[near_bindgen]
+impl ButterCore {
+    pub fn get_factory(&self) -> AccountId {
+        self.factory.clone()
+    }
+
+    /// Hash of the deployed code, given by the factory which created the core and recorded by each
+    /// upgrade.
+    pub fn get_code_hash(&self) -> Option<Base58CryptoHash> {
+        self.code_hash
+    }
+
+    pub fn get_staged_upgrade(&self) -> Option<StagedUpgrade> {
+        self.staged_upgrade.clone()
+    }
+
+    pub fn get_last_upgrade(&self) -> Option<UpgradeRecord> {
+        self.last_upgrade.clone()
+    }
+
+    pub fn get_upgrade_delay(&self) -> U64 {
+        U64(self.upgrade_delay)
+    }
+
+    /// Stage `code` to be applied by `apply_upgrade` after `upgrade_delay`, replacing the code
+    /// staged before. Returns the code hash. With the multisig, any admin can stage the code,
+    /// which is applied once the admins approve its hash.
+    pub fn stage_upgrade(&mut self, code: Base64VecU8) -> Base58CryptoHash {
+        let caller = env::predecessor_account_id();
+        ensure!(
+            caller == self.owner || self.is_admin(&caller),
+            ButterCoreError::Unauthorized(caller)
+        );
+        ensure!(
+            !code.0.is_empty(),
+            ButterCoreError::InvalidArgument("empty code".to_string())
+        );
+
+        self.stage_code(code.0)
+    }
+
+    /// Stage `code`, or apply it if it is the staged code and `upgrade_delay` has passed, so the
+    /// owner upgrades the core by calling it again after the delay.
+    pub fn upgrade_self(&mut self, code: Base64VecU8) {
+        self.assert_owner();
+        let code_hash: Base58CryptoHash = env::sha256_array(&code.0).into();
+        let executable = self.staged_upgrade.as_ref().is_some_and(|staged| {
+            staged.code_hash == code_hash
+                && env::block_timestamp() >= staged.staged_at.0 + self.upgrade_delay
+        });
+        if executable {
+            self.apply_upgrade(code_hash);
+        } else {
+            self.stage_upgrade(code);
+        }
+    }
+
+    /// Cancel the staged upgrade, callable by the owner or the guardian.
+    pub fn cancel_upgrade(&mut self) {
+        let caller = env::predecessor_account_id();
+        ensure!(
+            caller == self.owner || Some(&caller) == self.guardian.as_ref(),
+            ButterCoreError::Unauthorized(caller)
+        );
+        let staged = self
+            .staged_upgrade
+            .clone()
+            .unwrap_or_else(|| ButterCoreError::NoStagedUpgrade.panic());
+        self.remove_staged_code();
+        self.staged_upgrade = None;
+
+        CoreEvent::UpgradeCancelled {
+            code_hash: staged.code_hash,
+            cancelled_by: &caller,
+        }
+        .emit();
+    }
+
+    /// Deploy the staged code of `code_hash` and migrate the state. The deployment is reverted
+    /// if migrate fails, the result is recorded in `get_last_upgrade`.
+    pub fn apply_upgrade(&mut self, code_hash: Base58CryptoHash) -> Promise {
+        self.assert_owner();
+        let staged = self
+            .staged_upgrade
+            .clone()
+            .unwrap_or_else(|| ButterCoreError::NoStagedUpgrade.panic());
+        ensure!(
+            staged.code_hash == code_hash,
+            ButterCoreError::CodeHashMismatch
+        );
+        let executable_at = staged.staged_at.0 + self.upgrade_delay;
+        ensure!(
+            env::block_timestamp() >= executable_at,
+            ButterCoreError::TimeLocked {
+                until: U64(executable_at)
+            }
+        );
+
+        let code = self.codes.get(&code_hash.into()).unwrap();
+        self.deploy_code(code, code_hash)
+    }
+
+    /// Roll back to the code replaced by the last upgrade, only if the upgrade did not change
+    /// the state version. The code is pulled from the factory, the core only stores the staged
+    /// code.
+    pub fn rollback_upgrade(&mut self) -> Promise {
+        self.assert_owner();
+        let last_upgrade = self.last_upgrade.clone().unwrap_or_else(|| {
+            ButterCoreError::CannotRollBack("no upgrade to roll back".to_string()).panic()
+        });
+        ensure!(
+            last_upgrade.status == UpgradeStatus::Succeeded,
+            ButterCoreError::CannotRollBack("last upgrade failed".to_string())
+        );
+        let previous_code_hash = last_upgrade.previous_code_hash.unwrap_or_else(|| {
+            ButterCoreError::CannotRollBack("previous code is unknown".to_string()).panic()
+        });
+        ensure!(
+            last_upgrade.previous_state_version == read_state_version(),
+            ButterCoreError::CannotRollBack("state version changed by the upgrade".to_string())
+        );
+
+        self.pull_code_from_factory(previous_code_hash)
+    }
+
+    /// Stage the code of `code_hash` stored in the factory, only callable by the factory. The
+    /// owner applies it by `apply_upgrade` after `upgrade_delay` like the code staged by itself.
+    pub fn upgrade_from_factory(&mut self, code_hash: Base58CryptoHash) -> Promise {
+        ensure!(
+            self.factory == env::predecessor_account_id(),
+            ButterCoreError::Unauthorized(env::predecessor_account_id())
+        );
+
+        ext_factory::ext(self.factory.clone())
+            .with_static_gas(FACTORY_GET_CODE_GAS)
+            .get_code_by_hash(code_hash)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_STAGE_CODE_GAS)
+                    .callback_stage_code(code_hash),
+            )
+    }
+
+    /// Stage the code pulled from the factory if its hash matches. Returns the code hash.
+    #[private]
+    pub fn callback_stage_code(&mut self, code_hash: Base58CryptoHash) -> Base58CryptoHash {
+        let code = Self::factory_code(code_hash);
+        self.stage_code(code)
+    }
+
+    /// Deploy the code pulled from the factory if its hash matches, then call migrate.
+    #[private]
+    pub fn callback_deploy_code(&mut self, code_hash: Base58CryptoHash) -> Promise {
+        let code = Self::factory_code(code_hash);
+        self.deploy_code(code, code_hash)
+    }
+
+    /// Record the result of deploying `code_hash`. It runs on the new code if the upgrade
+    /// succeeded, on the previous code otherwise.
+    #[private]
+    pub fn callback_apply_upgrade(
+        &mut self,
+        code_hash: Base58CryptoHash,
+        previous_code_hash: Option<Base58CryptoHash>,
+        previous_state_version: u32,
+    ) -> bool {
+        let succeeded = match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(_) => true,
+            PromiseResult::Failed => false,
+        };
+        let status = if succeeded {
+            if self
+                .staged_upgrade
+                .as_ref()
+                .is_some_and(|staged| staged.code_hash == code_hash)
+            {
+                self.remove_staged_code();
+                self.staged_upgrade = None;
+            }
+            self.code_hash = Some(code_hash);
+            UpgradeStatus::Succeeded
+        } else {
+            log!("upgrade to {} failed", String::from(&code_hash));
+            UpgradeStatus::Failed
+        };
+
+        self.last_upgrade = Some(UpgradeRecord {
+            code_hash,
+            previous_code_hash,
+            previous_state_version,
+            status,
+            updated_at: U64(env::block_timestamp()),
+        });
+
+        CoreEvent::UpgradeApplied { code_hash, status }.emit();
+        succeeded
+    }
+}
+
+impl ButterCore {
+    /// Stage `code`, replacing the code staged before. Returns the code hash.
+    fn stage_code(&mut self, code: Vec<u8>) -> Base58CryptoHash {
+        self.remove_staged_code();
+        let code_hash: CryptoHash = env::sha256_array(&code);
+        self.codes.insert(&code_hash, &code);
+        let staged_at = env::block_timestamp();
+        self.staged_upgrade = Some(StagedUpgrade {
+            code_hash: code_hash.into(),
+            staged_at: U64(staged_at),
+        });
+
+        CoreEvent::UpgradeStaged {
+            code_hash: code_hash.into(),
+            executable_at: U64(staged_at + self.upgrade_delay),
+        }
+        .emit();
+        code_hash.into()
+    }
+
+    /// The code of `code_hash` returned by get_code_by_hash of the factory.
+    fn factory_code(code_hash: Base58CryptoHash) -> Vec<u8> {
+        let code = match promise_result() {
+            PromiseResult::NotReady => env::abort(),
+            PromiseResult::Successful(x) => serde_json::from_slice::<Base64VecU8>(&x).ok(),
+            PromiseResult::Failed => None,
+        }
+        .unwrap_or_else(|| {
+            ButterCoreError::FactoryCallFailed("get code from factory".to_string()).panic()
+        })
+        .0;
+        let expected_hash: CryptoHash = code_hash.into();
+        ensure!(
+            env::sha256_array(&code) == expected_hash,
+            ButterCoreError::CodeHashMismatch
+        );
+        code
+    }
+
+    /// Pull the code of `code_hash` from the factory and deploy it, callback_deploy_code gets all
+    /// the gas left, so migrate gets the gas left by callback_deploy_code as in `apply_upgrade`.
+    fn pull_code_from_factory(&self, code_hash: Base58CryptoHash) -> Promise {
+        let deploy_gas = (env::prepaid_gas() - env::used_gas())
+            .0
+            .saturating_sub(FACTORY_GET_CODE_GAS.0 + ROLLBACK_RESERVED_GAS.0);
+        ensure!(
+            deploy_gas >= CALLBACK_DEPLOY_CODE_GAS.0 + MIN_MIGRATE_GAS.0,
+            ButterCoreError::NotEnoughGas
+        );
+        ext_factory::ext(self.factory.clone())
+            .with_static_gas(FACTORY_GET_CODE_GAS)
+            .with_unused_gas_weight(0)
+            .get_code_by_hash(code_hash)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(deploy_gas))
+                    .callback_deploy_code(code_hash),
+            )
+    }
+
+    /// Deploy `code` and call migrate in one receipt, so a failed migrate reverts the
+    /// deployment, then record the result.
+    fn deploy_code(&self, code: Vec<u8>, code_hash: Base58CryptoHash) -> Promise {
+        let migrate_gas = env::prepaid_gas()
+            - env::used_gas()
+            - GAS_FOR_UPGRADE_SELF_DEPLOY
+            - CALLBACK_APPLY_UPGRADE_GAS
+            - CALLBACK_APPLY_UPGRADE_RECEIPT_GAS;
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(
+                "migrate".to_string(),
+                json!({ "factory": self.factory }).to_string().into_bytes(),
+                0,
+                migrate_gas,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_APPLY_UPGRADE_GAS)
+                    .callback_apply_upgrade(code_hash, self.code_hash, read_state_version()),
+            )
+    }
+
+    fn remove_staged_code(&mut self) {
+        if let Some(staged) = &self.staged_upgrade {
+            self.codes.remove(&staged.code_hash.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migration::STATE_VERSION;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig, VMContext};
+
+    const CORE: &str = "core0.corefac.near";
+    const FACTORY: &str = "corefac.near";
+    const OWNER: &str = "owner.near";
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    fn context(predecessor: &str, block_timestamp: u64) -> VMContext {
+        VMContextBuilder::new()
+            .current_account_id(account(CORE))
+            .predecessor_account_id(account(predecessor))
+            .block_timestamp(block_timestamp)
+            .prepaid_gas(Gas(300_000_000_000_000))
+            .build()
+    }
+
+    fn setup() -> ButterCore {
+        testing_env!(context(FACTORY, 0));
+        ButterCore::new(
+            account("mos.near"),
+            account("v2.ref-finance.near"),
+            account("wrap.near"),
+            account(OWNER),
+            None,
+        )
+    }
+
+    /// Stage `code` by upgrade_from_factory and its callback with the code returned by the
+    /// factory.
+    fn stage_from_factory(core: &mut ButterCore, code: &[u8]) -> Base58CryptoHash {
+        let code_hash: Base58CryptoHash = env::sha256_array(code).into();
+        testing_env!(context(FACTORY, 0));
+        core.upgrade_from_factory(code_hash);
+        testing_env!(
+            context(CORE, 0),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&Base64VecU8(code.to_vec())).unwrap()
+            )],
+        );
+        core.callback_stage_code(code_hash)
+    }
+
+    #[test]
+    #[should_panic(expected = "E051")]
+    fn test_apply_factory_upgrade_before_delay() {
+        let mut core = setup();
+        let code_hash = stage_from_factory(&mut core, b"new code");
+        assert_eq!(core.get_staged_upgrade().unwrap().code_hash, code_hash);
+
+        testing_env!(context(OWNER, DEFAULT_UPGRADE_DELAY - 1));
+        core.apply_upgrade(code_hash);
+    }
+
+    #[test]
+    fn test_apply_factory_upgrade_after_delay() {
+        let mut core = setup();
+        let code_hash = stage_from_factory(&mut core, b"new code");
+
+        testing_env!(context(OWNER, DEFAULT_UPGRADE_DELAY));
+        core.apply_upgrade(code_hash);
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == account(CORE)));
+    }
+
+    /// Context of a callback called with `result`.
+    fn callback_context(result: PromiseResult) {
+        testing_env!(
+            context(CORE, DEFAULT_UPGRADE_DELAY),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
+    #[test]
+    fn test_apply_upgrade_failed() {
+        let mut core = setup();
+        let code_hash = stage_from_factory(&mut core, b"new code");
+
+        callback_context(PromiseResult::Failed);
+        assert!(!core.callback_apply_upgrade(code_hash, None, STATE_VERSION));
+        let last_upgrade = core.get_last_upgrade().unwrap();
+        assert_eq!(last_upgrade.status, UpgradeStatus::Failed);
+        assert_eq!(last_upgrade.code_hash, code_hash);
+        // the staged code is kept to be applied again
+        assert_eq!(core.get_staged_upgrade().unwrap().code_hash, code_hash);
+        assert!(core.codes.get(&code_hash.into()).is_some());
+        assert_eq!(core.get_code_hash(), None);
+    }
+
+    #[test]
+    fn test_apply_upgrade_succeeded() {
+        let mut core = setup();
+        let previous_code_hash: Base58CryptoHash = env::sha256_array(b"old code").into();
+        core.code_hash = Some(previous_code_hash);
+        let code_hash = stage_from_factory(&mut core, b"new code");
+
+        callback_context(PromiseResult::Successful(vec![]));
+        assert!(core.callback_apply_upgrade(code_hash, core.code_hash, STATE_VERSION));
+        let last_upgrade = core.get_last_upgrade().unwrap();
+        assert_eq!(last_upgrade.status, UpgradeStatus::Succeeded);
+        assert_eq!(last_upgrade.previous_code_hash, Some(previous_code_hash));
+        assert_eq!(last_upgrade.previous_state_version, STATE_VERSION);
+        assert!(core.get_staged_upgrade().is_none());
+        assert!(core.codes.get(&code_hash.into()).is_none());
+        assert_eq!(core.get_code_hash(), Some(code_hash));
+    }
+
+    /// A core whose last upgrade replaced the code of "old code" with the state of
+    /// `previous_state_version`.
+    fn upgraded(previous_state_version: u32) -> ButterCore {
+        let mut core = setup();
+        core.last_upgrade = Some(UpgradeRecord {
+            code_hash: env::sha256_array(b"new code").into(),
+            previous_code_hash: Some(env::sha256_array(b"old code").into()),
+            previous_state_version,
+            status: UpgradeStatus::Succeeded,
+            updated_at: U64(0),
+        });
+        core
+    }
+
+    #[test]
+    fn test_rollback_upgrade() {
+        let mut core = upgraded(STATE_VERSION);
+
+        testing_env!(context(OWNER, 0));
+        core.rollback_upgrade();
+        // the gas left goes to the deployment of the code pulled from the factory and migrate
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, account(FACTORY));
+        let deploy_gas = receipts[1]
+            .actions
+            .iter()
+            .find_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name, gas, ..
+                } if function_name == "callback_deploy_code" => Some(*gas),
+                _ => None,
+            })
+            .unwrap();
+        assert!(deploy_gas.0 >= 240_000_000_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "E042: cannot roll back, state version changed by the upgrade")]
+    fn test_rollback_upgrade_changed_state_version() {
+        let mut core = upgraded(STATE_VERSION - 1);
+
+        testing_env!(context(OWNER, 0));
+        core.rollback_upgrade();
+    }
+
+    #[test]
+    #[should_panic(expected = "E001")]
+    fn test_upgrade_from_factory_by_other() {
+        let mut core = setup();
+        testing_env!(context(OWNER, 0));
+        core.upgrade_from_factory(env::sha256_array(b"new code").into());
+    }
+}
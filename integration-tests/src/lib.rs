@@ -0,0 +1 @@
+//! Integration tests of butter core, see the tests directory.
@@ -0,0 +1,241 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use near_workspaces::types::NearToken;
+use near_workspaces::{Account, Contract};
+use serde_json::{json, Value};
+
+const RELEASE_DIR: &str = "../target/wasm32-unknown-unknown/release";
+
+fn wasm(name: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(std::fs::read(format!("{}/{}.wasm", RELEASE_DIR, name))?)
+}
+
+/// The core wasm with a custom section appended, which is another valid version of the core.
+fn core_wasm_with_section(name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut code = wasm("butter_core")?;
+    code.extend_from_slice(&[0, name.len() as u8 + 1, name.len() as u8]);
+    code.extend_from_slice(name.as_bytes());
+    Ok(code)
+}
+
+/// Upload `code` as core `version` in two chunks and add the version.
+async fn add_core_version(
+    owner: &Account,
+    factory: &Contract,
+    version: &str,
+    code: &[u8],
+) -> anyhow::Result<()> {
+    let (first, second) = code.split_at(code.len() / 2);
+    for chunk in [first, second] {
+        owner
+            .call(factory.id(), "upload_core_code")
+            .args_json(json!({ "version": version, "chunk": STANDARD.encode(chunk) }))
+            .max_gas()
+            .transact()
+            .await?
+            .into_result()?;
+    }
+    owner
+        .call(factory.id(), "add_core_version")
+        .args_json(json!({ "version": version }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// The factory with core version 1.0.0 and its owner.
+async fn setup() -> anyhow::Result<(Contract, Account, Account)> {
+    let worker = near_workspaces::sandbox().await?;
+    let root = worker.root_account()?;
+    let factory = root
+        .create_subaccount("factory")
+        .initial_balance(NearToken::from_near(50))
+        .transact()
+        .await?
+        .into_result()?
+        .deploy(&wasm("butter_core_factory")?)
+        .await?
+        .into_result()?;
+    let owner = root
+        .create_subaccount("owner")
+        .initial_balance(NearToken::from_near(50))
+        .transact()
+        .await?
+        .into_result()?;
+    factory
+        .call("new")
+        .args_json(json!({ "owner": owner.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    add_core_version(&owner, &factory, "1.0.0", &wasm("butter_core")?).await?;
+    Ok((factory, owner, root))
+}
+
+async fn create_core(
+    caller: &Account,
+    factory: &Contract,
+    name: &str,
+) -> anyhow::Result<near_workspaces::result::ExecutionFinalResult> {
+    let min_deposit: String = factory
+        .view("get_min_deposit")
+        .args_json(json!({ "version": null }))
+        .await?
+        .json()?;
+    Ok(caller
+        .call(factory.id(), "create_butter_core")
+        .args_json(json!({
+            "name": name,
+            "controller": "mos.test.near",
+            "ref_exchange": "ref.test.near",
+            "wrapped_token": "wnear.test.near",
+            "owner": caller.id(),
+            "version": null,
+        }))
+        .deposit(NearToken::from_yoctonear(min_deposit.parse()?))
+        .max_gas()
+        .transact()
+        .await?)
+}
+
+#[tokio::test]
+async fn test_create_butter_core() -> anyhow::Result<()> {
+    let (factory, owner, root) = setup().await?;
+
+    let result = create_core(&owner, &factory, "core0").await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    let created: bool = result.json()?;
+    assert!(created);
+
+    let core_info: Value = factory
+        .view("get_core")
+        .args_json(json!({ "name": "core0" }))
+        .await?
+        .json()?;
+    let core_id = format!("core0.{}", factory.id());
+    assert_eq!(core_info["account_id"], json!(core_id));
+    assert_eq!(core_info["version"], json!("1.0.0"));
+    let cores: Vec<Value> = factory
+        .view("get_cores_by_controller")
+        .args_json(json!({ "controller": "mos.test.near", "from_index": 0, "limit": 10 }))
+        .await?
+        .json()?;
+    assert_eq!(cores, vec![core_info.clone()]);
+
+    // the core is initialized with the factory and the hash of its code
+    let core_owner: String = owner.view(&core_id.parse()?, "get_owner").await?.json()?;
+    assert_eq!(core_owner, owner.id().to_string());
+    let core_factory: String = owner.view(&core_id.parse()?, "get_factory").await?.json()?;
+    assert_eq!(core_factory, factory.id().to_string());
+    let code_hash: Value = owner
+        .view(&core_id.parse()?, "get_code_hash")
+        .await?
+        .json()?;
+    assert_eq!(code_hash, core_info["code_hash"]);
+
+    // a core name can't be reused
+    let result = create_core(&owner, &factory, "core0").await?;
+    assert!(format!("{:?}", result.into_result().unwrap_err()).contains("already exists"));
+
+    // only the owner and the deployers can create cores
+    let alice = root
+        .create_subaccount("alice")
+        .initial_balance(NearToken::from_near(20))
+        .transact()
+        .await?
+        .into_result()?;
+    let result = create_core(&alice, &factory, "core1").await?;
+    assert!(format!("{:?}", result.into_result().unwrap_err()).contains("unexpected caller"));
+    owner
+        .call(factory.id(), "add_deployer")
+        .args_json(json!({ "deployer": alice.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+    let result = create_core(&alice, &factory, "core1").await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    let count: u64 = factory.view("get_cores_count").await?.json()?;
+    assert_eq!(count, 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upgrade_cores() -> anyhow::Result<()> {
+    let (factory, owner, _root) = setup().await?;
+    for name in ["core0", "core1"] {
+        let result = create_core(&owner, &factory, name).await?;
+        assert!(result.is_success(), "{:?}", result.failures());
+    }
+    add_core_version(
+        &owner,
+        &factory,
+        "1.1.0",
+        &core_wasm_with_section("v1.1.0")?,
+    )
+    .await?;
+    let version: Value = factory
+        .view("get_core_version")
+        .args_json(json!({ "version": "1.1.0" }))
+        .await?
+        .json()?;
+
+    // the cores are upgraded in stages
+    let result = owner
+        .call(factory.id(), "upgrade_cores")
+        .args_json(json!({ "version": "1.1.0", "names": ["core0"] }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    let upgrade: Value = factory
+        .view("get_core_upgrade")
+        .args_json(json!({ "name": "core0" }))
+        .await?
+        .json()?;
+    assert_eq!(upgrade["status"], json!("staged"));
+    assert_eq!(upgrade["code_hash"], version["code_hash"]);
+    let core0 = format!("core0.{}", factory.id()).parse()?;
+    let staged: Value = owner.view(&core0, "get_staged_upgrade").await?.json()?;
+    assert_eq!(staged["code_hash"], version["code_hash"]);
+
+    let to_upgrade: Vec<Value> = factory
+        .view("get_cores_to_upgrade")
+        .args_json(json!({ "version": "1.1.0", "from_index": 0, "limit": 10 }))
+        .await?
+        .json()?;
+    // core0 is staged but not applied yet
+    assert_eq!(to_upgrade.len(), 2);
+
+    let result = owner
+        .call(factory.id(), "upgrade_cores")
+        .args_json(json!({ "version": "1.1.0", "names": ["core1"] }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    let upgrade: Value = factory
+        .view("get_core_upgrade")
+        .args_json(json!({ "name": "core1" }))
+        .await?
+        .json()?;
+    assert_eq!(upgrade["status"], json!("staged"));
+
+    // unknown cores and versions are rejected
+    let result = owner
+        .call(factory.id(), "upgrade_cores")
+        .args_json(json!({ "version": "1.1.0", "names": ["core2"] }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(format!("{:?}", result.into_result().unwrap_err()).contains("unknown core core2"));
+    let result = owner
+        .call(factory.id(), "upgrade_cores")
+        .args_json(json!({ "version": "2.0.0", "names": ["core0"] }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(format!("{:?}", result.into_result().unwrap_err()).contains("unknown version"));
+    Ok(())
+}
@@ -0,0 +1,1036 @@
+use near_workspaces::network::Sandbox;
+use near_workspaces::result::ExecutionFinalResult;
+use near_workspaces::types::NearToken;
+use near_workspaces::{Account, AccountId, Contract, Worker};
+use serde_json::{json, Value};
+
+const RELEASE_DIR: &str = "../target/wasm32-unknown-unknown/release";
+const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+/// 1 USDC is worth 2 ETH in pool 0 and 3 wNEAR in pool 1.
+const USDC_ETH_POOL: u64 = 0;
+const USDC_WNEAR_POOL: u64 = 1;
+const ETH_USDC_POOL: u64 = 2;
+const USDC_DAI_POOL: u64 = 3;
+const ONE_YOCTO: NearToken = NearToken::from_yoctonear(1);
+
+struct Env {
+    /// Keeps the sandbox running.
+    worker: Worker<Sandbox>,
+    core: Contract,
+    mos: Contract,
+    owner: Account,
+    ref_exchange: Contract,
+    usdc: Contract,
+    eth: Contract,
+    wnear: Contract,
+    alice: Account,
+}
+
+fn wasm(name: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(std::fs::read(format!("{}/{}.wasm", RELEASE_DIR, name))?)
+}
+
+async fn deploy(root: &Account, name: &str, wasm_name: &str) -> anyhow::Result<Contract> {
+    let account = root
+        .create_subaccount(name)
+        .initial_balance(NearToken::from_near(50))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(account.deploy(&wasm(wasm_name)?).await?.into_result()?)
+}
+
+async fn mint(token: &Contract, account_id: &AccountId, amount: u128) -> anyhow::Result<()> {
+    token
+        .call("mint")
+        .args_json(json!({ "account_id": account_id, "amount": amount.to_string() }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn balance_of(token: &Contract, account_id: &AccountId) -> anyhow::Result<u128> {
+    let balance: String = token
+        .view("ft_balance_of")
+        .args_json(json!({ "account_id": account_id }))
+        .await?
+        .json()?;
+    Ok(balance.parse()?)
+}
+
+fn swap_action(pool_id: u64, token_in: &Contract, amount_in: u128, token_out: &Contract) -> Value {
+    json!({
+        "pool_id": pool_id,
+        "token_in": token_in.id(),
+        "amount_in": amount_in.to_string(),
+        "token_out": token_out.id(),
+        "min_amount_out": "0",
+    })
+}
+
+impl Env {
+    async fn new() -> anyhow::Result<Self> {
+        let worker = near_workspaces::sandbox().await?;
+        let root = worker.root_account()?;
+
+        let usdc = deploy(&root, "usdc", "mock_ft").await?;
+        let eth = deploy(&root, "eth", "mock_ft").await?;
+        let wnear = deploy(&root, "wnear", "mock_wnear").await?;
+        let ref_exchange = deploy(&root, "ref", "mock_ref").await?;
+        let mos = deploy(&root, "mos", "mock_mos").await?;
+        let core = deploy(&root, "core", "butter_core").await?;
+        for contract in [&usdc, &eth, &wnear, &ref_exchange, &mos] {
+            contract.call("new").transact().await?.into_result()?;
+        }
+        let owner = root
+            .create_subaccount("owner")
+            .initial_balance(NearToken::from_near(10))
+            .transact()
+            .await?
+            .into_result()?;
+        let alice = root
+            .create_subaccount("alice")
+            .initial_balance(NearToken::from_near(10))
+            .transact()
+            .await?
+            .into_result()?;
+        core.call("new")
+            .args_json(json!({
+                "controller": mos.id(),
+                "ref_exchange": ref_exchange.id(),
+                "wrapped_token": wnear.id(),
+                "owner": owner.id(),
+            }))
+            .transact()
+            .await?
+            .into_result()?;
+
+        for pool_id in [USDC_ETH_POOL, USDC_WNEAR_POOL] {
+            let (token_out, rate) = if pool_id == USDC_ETH_POOL {
+                (&eth, 2)
+            } else {
+                (&wnear, 3)
+            };
+            ref_exchange
+                .call("set_pool")
+                .args_json(json!({
+                    "pool_id": pool_id,
+                    "pool": {
+                        "token_in": usdc.id(),
+                        "token_out": token_out.id(),
+                        "rate_numerator": rate.to_string(),
+                        "rate_denominator": "1",
+                        "max_amount_in": null,
+                    },
+                }))
+                .transact()
+                .await?
+                .into_result()?;
+        }
+        for token in [&usdc, &eth, &wnear] {
+            for account_id in [core.id(), mos.id(), ref_exchange.id()] {
+                mint(token, account_id, 0).await?;
+            }
+        }
+        mint(&usdc, mos.id(), 1_000_000).await?;
+        mint(&eth, ref_exchange.id(), 1_000_000).await?;
+        mint(&wnear, ref_exchange.id(), 1_000_000).await?;
+
+        Ok(Self {
+            worker,
+            core,
+            mos,
+            owner,
+            ref_exchange,
+            usdc,
+            eth,
+            wnear,
+            alice,
+        })
+    }
+
+    /// MOS transfers `amount` of USDC to the core with the core swap message, as a swap in.
+    async fn swap_in(
+        &self,
+        amount: u128,
+        core_swap_msg: Value,
+    ) -> anyhow::Result<ExecutionFinalResult> {
+        Ok(self
+            .mos
+            .as_account()
+            .call(self.usdc.id(), "ft_transfer_call")
+            .args_json(json!({
+                "receiver_id": self.core.id(),
+                "amount": amount.to_string(),
+                "msg": core_swap_msg.to_string(),
+            }))
+            .deposit(ONE_YOCTO)
+            .max_gas()
+            .transact()
+            .await?)
+    }
+
+    /// Register the core and its tokens in ref exchange and switch to the internal mode.
+    async fn use_internal_mode(&self) -> anyhow::Result<()> {
+        self.owner
+            .call(self.core.id(), "register_in_ref_exchange")
+            .args_json(json!({ "token_ids": [self.usdc.id(), self.eth.id(), self.wnear.id()] }))
+            .deposit(NearToken::from_millinear(100))
+            .max_gas()
+            .transact()
+            .await?
+            .into_result()?;
+        self.owner
+            .call(self.core.id(), "set_swap_mode")
+            .args_json(json!({ "swap_mode": "internal" }))
+            .transact()
+            .await?
+            .into_result()?;
+        Ok(())
+    }
+
+    async fn ref_deposit(&self, token: &Contract) -> anyhow::Result<u128> {
+        let deposit: String = self
+            .ref_exchange
+            .view("get_deposit")
+            .args_json(json!({ "account_id": self.core.id(), "token_id": token.id() }))
+            .await?
+            .json()?;
+        Ok(deposit.parse()?)
+    }
+
+    async fn mos_received(&self) -> anyhow::Result<Vec<Value>> {
+        Ok(self.mos.view("get_received").await?.json()?)
+    }
+}
+
+#[tokio::test]
+async fn test_swap_in() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    mint(&env.eth, env.alice.id(), 0).await?;
+
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": env.eth.id(),
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 200);
+    assert_eq!(balance_of(&env.usdc, env.mos.id()).await?, 1_000_000 - 100);
+    assert_eq!(balance_of(&env.usdc, env.ref_exchange.id()).await?, 100);
+    assert_eq!(balance_of(&env.eth, env.core.id()).await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_in_internal() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    env.use_internal_mode().await?;
+    mint(&env.eth, env.alice.id(), 0).await?;
+
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": env.eth.id()},
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(result.json::<String>()?, "100");
+
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 200);
+    assert_eq!(balance_of(&env.usdc, env.ref_exchange.id()).await?, 100);
+    assert_eq!(env.ref_deposit(&env.usdc).await?, 0);
+    assert_eq!(env.ref_deposit(&env.eth).await?, 0);
+    assert_eq!(balance_of(&env.eth, env.core.id()).await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_in_internal_withdraw_failed() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    env.use_internal_mode().await?;
+    // the core is not registered in DAI, ref exchange keeps the output in its internal account
+    let dai = deploy(&env.worker.root_account()?, "dai", "mock_ft").await?;
+    dai.call("new").transact().await?.into_result()?;
+    mint(&dai, env.ref_exchange.id(), 1_000_000).await?;
+    mint(&dai, env.alice.id(), 0).await?;
+    env.ref_exchange
+        .call("set_pool")
+        .args_json(json!({
+            "pool_id": USDC_DAI_POOL,
+            "pool": {
+                "token_in": env.usdc.id(),
+                "token_out": dai.id(),
+                "rate_numerator": "2",
+                "rate_denominator": "1",
+                "max_amount_in": null,
+            },
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_DAI_POOL, &env.usdc, 100, &dai)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": dai.id()},
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+
+    assert_eq!(balance_of(&dai, env.alice.id()).await?, 0);
+    assert_eq!(env.ref_deposit(&dai).await?, 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_out() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+
+    env.mos
+        .as_account()
+        .call(env.usdc.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": env.core.id(), "amount": "100" }))
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?
+        .into_result()?;
+    let result = env
+        .mos
+        .as_account()
+        .call(env.core.id(), "swap")
+        .args_json(json!({
+            "amount": "100",
+            "core_swap_msg": {
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.mos.id(),
+                "target_token": null,
+                "min_total_out": null,
+                "on_failure": null,
+            },
+        }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(
+        result.json::<(String, String)>()?,
+        ("100".into(), "200".into())
+    );
+
+    let received = env.mos_received().await?;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["token"], json!(env.eth.id()));
+    assert_eq!(received[0]["sender_id"], json!(env.core.id()));
+    assert_eq!(received[0]["amount"], json!("200"));
+    assert_eq!(balance_of(&env.eth, env.mos.id()).await?, 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_swap() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    mint(&env.eth, env.alice.id(), 0).await?;
+    env.mos
+        .as_account()
+        .call(env.usdc.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": env.core.id(), "amount": "100" }))
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?
+        .into_result()?;
+    let core_swap_msg = json!({
+        "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+        "target_account": env.alice.id(),
+        "target_token": {"token": env.eth.id()},
+        "min_total_out": null,
+        "on_failure": null,
+    });
+
+    // the second item doesn't match the amount of its action and is not swapped
+    let result = env
+        .mos
+        .as_account()
+        .call(env.core.id(), "batch_swap")
+        .args_json(json!({
+            "swaps": [["100", core_swap_msg], ["50", core_swap_msg]],
+        }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    let results: Vec<Value> = result.json()?;
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["amount_in"], json!("100"));
+    assert_eq!(results[0]["amount_out"], json!("200"));
+    assert_eq!(results[0]["error_code"], Value::Null);
+    assert_eq!(results[1]["amount_out"], json!("0"));
+    assert_eq!(results[1]["error_code"], json!("E012"));
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 200);
+
+    // the prepaid gas doesn't cover 3 swaps, the batch is rejected
+    let result = env
+        .mos
+        .as_account()
+        .call(env.core.id(), "batch_swap")
+        .args_json(json!({
+            "swaps": [["100", core_swap_msg], ["100", core_swap_msg], ["100", core_swap_msg]],
+        }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(format!("{:?}", result.into_result().unwrap_err()).contains("E014"));
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_out_refunded() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+
+    env.mos
+        .as_account()
+        .call(env.usdc.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": env.core.id(), "amount": "100" }))
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?
+        .into_result()?;
+    env.mos
+        .call("set_refund")
+        .args_json(json!({ "refund": "50" }))
+        .transact()
+        .await?
+        .into_result()?;
+    let result = env
+        .mos
+        .as_account()
+        .call(env.core.id(), "swap")
+        .args_json(json!({
+            "amount": "100",
+            "core_swap_msg": {
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.mos.id(),
+                "target_token": null,
+                "min_total_out": null,
+                "on_failure": null,
+            },
+        }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    // only the amount used by MOS is reported as delivered
+    assert_eq!(
+        result.json::<(String, String)>()?,
+        ("100".into(), "150".into())
+    );
+
+    // the refunded amount goes to MOS lost and found
+    let received = env.mos_received().await?;
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0]["amount"], json!("200"));
+    assert_eq!(received[1]["amount"], json!("50"));
+    let msg: Value = serde_json::from_str(received[1]["msg"].as_str().unwrap())?;
+    assert_eq!(msg, json!({ "account": env.mos.id(), "is_native": false }));
+    assert_eq!(balance_of(&env.eth, env.mos.id()).await?, 200);
+    assert_eq!(balance_of(&env.eth, env.core.id()).await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_in_target_call() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    mint(&env.eth, env.alice.id(), 0).await?;
+    env.mos
+        .call("set_refund")
+        .args_json(json!({ "refund": "50" }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    // the mock MOS is the target contract, which uses 150 of the output
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.mos.id(),
+                "target_token": {"token": env.eth.id()},
+                "target_call": {"msg": "deposit", "gas": "20000000000000"},
+                "min_total_out": null,
+                "on_failure": {"deliver_to_fallback": env.alice.id()},
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+
+    let received = env.mos_received().await?;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["amount"], json!("200"));
+    assert_eq!(received[0]["msg"], json!("deposit"));
+    // the refunded amount is delivered to the target account, not to the fallback account
+    assert_eq!(balance_of(&env.eth, env.mos.id()).await?, 200);
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 0);
+    assert_eq!(balance_of(&env.eth, env.core.id()).await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_in_hook() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    mint(&env.eth, env.alice.id(), 0).await?;
+    env.owner
+        .call(env.core.id(), "set_hook")
+        .args_json(json!({
+            "contract_id": env.mos.id(),
+            "method_name": "ft_on_transfer",
+            "max_gas": "30000000000000",
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    env.mos
+        .call("set_refund")
+        .args_json(json!({ "refund": "50" }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    // the mock MOS is the hook contract, which uses 150 of the output
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": env.eth.id()},
+                "hook": {
+                    "contract_id": env.mos.id(),
+                    "method_name": "ft_on_transfer",
+                    "args": "stake",
+                    "gas": "20000000000000",
+                },
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+
+    let received = env.mos_received().await?;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["amount"], json!("200"));
+    assert_eq!(received[0]["msg"], json!("stake"));
+    assert_eq!(balance_of(&env.eth, env.mos.id()).await?, 150);
+    // the amount not used by the hook goes to the target account
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 50);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relayer_fee() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let relayer = env
+        .alice
+        .create_subaccount("relayer")
+        .initial_balance(NearToken::from_near(1))
+        .transact()
+        .await?
+        .into_result()?;
+    mint(&env.eth, env.alice.id(), 0).await?;
+    mint(&env.eth, relayer.id(), 0).await?;
+    env.owner
+        .call(env.core.id(), "set_relayer_fee_cap")
+        .args_json(json!({ "token": env.eth.id(), "cap": "20" }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": env.eth.id()},
+                "relayer_fee": {"relayer": relayer.id(), "amount": "10"},
+                "min_total_out": "190",
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 190);
+    let fee: String = env
+        .core
+        .view("get_relayer_fee")
+        .args_json(json!({ "relayer": relayer.id(), "token": env.eth.id() }))
+        .await?
+        .json()?;
+    assert_eq!(fee, "10");
+
+    // the fee is kept out of the output of the next swap
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": env.eth.id()},
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 390);
+
+    relayer
+        .call(env.core.id(), "withdraw_relayer_fee")
+        .args_json(json!({ "token": env.eth.id() }))
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    assert_eq!(balance_of(&env.eth, relayer.id()).await?, 10);
+    assert_eq!(balance_of(&env.eth, env.core.id()).await?, 0);
+
+    // a fee over the cap is rejected and the input is refunded to MOS
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": env.eth.id()},
+                "relayer_fee": {"relayer": relayer.id(), "amount": "30"},
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 390);
+    assert_eq!(balance_of(&env.usdc, env.mos.id()).await?, 1_000_000 - 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relayer_fee_kept_on_swap_in_of_fee_token() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let relayer = env
+        .alice
+        .create_subaccount("relayer")
+        .initial_balance(NearToken::from_near(1))
+        .transact()
+        .await?
+        .into_result()?;
+    mint(&env.eth, env.alice.id(), 0).await?;
+    mint(&env.eth, relayer.id(), 0).await?;
+    mint(&env.usdc, env.alice.id(), 0).await?;
+    env.owner
+        .call(env.core.id(), "set_relayer_fee_cap")
+        .args_json(json!({ "token": env.eth.id(), "cap": "20" }))
+        .transact()
+        .await?
+        .into_result()?;
+    env.ref_exchange
+        .call("set_pool")
+        .args_json(json!({
+            "pool_id": ETH_USDC_POOL,
+            "pool": {
+                "token_in": env.eth.id(),
+                "token_out": env.usdc.id(),
+                "rate_numerator": "1",
+                "rate_denominator": "2",
+                "max_amount_in": null,
+            },
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    mint(&env.usdc, env.ref_exchange.id(), 1_000_000).await?;
+    mint(&env.eth, env.mos.id(), 100).await?;
+
+    // a fee of 10 ETH is credited to the relayer
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": env.eth.id()},
+                "relayer_fee": {"relayer": relayer.id(), "amount": "10"},
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(balance_of(&env.eth, env.core.id()).await?, 10);
+
+    // a swap in of ETH uses the whole input, the fee is not refunded to MOS
+    let result = env
+        .mos
+        .as_account()
+        .call(env.eth.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": env.core.id(),
+            "amount": "100",
+            "msg": json!({
+                "actions": [swap_action(ETH_USDC_POOL, &env.eth, 100, &env.usdc)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": env.usdc.id()},
+                "min_total_out": null,
+                "on_failure": null,
+            })
+            .to_string(),
+        }))
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(result.json::<String>()?, "100");
+    assert_eq!(balance_of(&env.usdc, env.alice.id()).await?, 50);
+    assert_eq!(balance_of(&env.eth, env.mos.id()).await?, 0);
+    assert_eq!(balance_of(&env.eth, env.core.id()).await?, 10);
+
+    relayer
+        .call(env.core.id(), "withdraw_relayer_fee")
+        .args_json(json!({ "token": env.eth.id() }))
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    assert_eq!(balance_of(&env.eth, relayer.id()).await?, 10);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_out_with_swap_msg() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let swap_data = json!({
+        "swap_param": [],
+        "target_token": vec![1; 20],
+        "to_address": vec![2; 20],
+    });
+    let swap_msg = json!({
+        "map_target_token": vec![0; 20],
+        "to_chain": "56",
+        "swap_data_0": swap_data,
+        "swap_data_1": swap_data,
+    });
+
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.mos.id(),
+                "target_token": {
+                    "swap_out": {"chain": "56", "address": "0x0101010101010101010101010101010101010101"}
+                },
+                "swap_msg": swap_msg,
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+
+    // the onward swap is forwarded to MOS as the msg of ft_transfer_call
+    let received = env.mos_received().await?;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["token"], json!(env.eth.id()));
+    assert_eq!(received[0]["amount"], json!("200"));
+    let msg: Value = serde_json::from_str(received[0]["msg"].as_str().unwrap())?;
+    assert_eq!(msg, swap_msg);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_in_native() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    let balance_before = env.alice.view_account().await?.balance;
+
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_WNEAR_POOL, &env.usdc, 100, &env.wnear)],
+                "target_account": env.alice.id(),
+                "target_token": ZERO_ADDRESS,
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+
+    let balance_after = env.alice.view_account().await?.balance;
+    assert_eq!(
+        balance_after.as_yoctonear() - balance_before.as_yoctonear(),
+        300
+    );
+    assert_eq!(balance_of(&env.wnear, env.core.id()).await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gas_drop() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    mint(&env.eth, env.alice.id(), 0).await?;
+    // 1 ETH is worth 3 wNEAR in pool 2
+    env.ref_exchange
+        .call("set_pool")
+        .args_json(json!({
+            "pool_id": 2,
+            "pool": {
+                "token_in": env.eth.id(),
+                "token_out": env.wnear.id(),
+                "rate_numerator": "3",
+                "rate_denominator": "1",
+                "max_amount_in": null,
+            },
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    env.owner
+        .call(env.core.id(), "set_gas_drop_cap")
+        .args_json(json!({ "token": env.eth.id(), "cap": "20" }))
+        .transact()
+        .await?
+        .into_result()?;
+    let core_swap_msg = json!({
+        "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+        "target_account": env.alice.id(),
+        "target_token": {"token": env.eth.id()},
+        "gas_drop": {"pool_id": 2, "amount": "10", "min_amount_out": "30"},
+        "min_total_out": null,
+        "on_failure": null,
+    });
+    let balance_before = env.alice.view_account().await?.balance;
+
+    let result = env.swap_in(100, core_swap_msg.clone()).await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+
+    let balance_after = env.alice.view_account().await?.balance;
+    assert_eq!(
+        balance_after.as_yoctonear() - balance_before.as_yoctonear(),
+        30
+    );
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 190);
+    assert_eq!(balance_of(&env.wnear, env.core.id()).await?, 0);
+    let gas_drops: u32 = env
+        .core
+        .view("get_gas_drops")
+        .args_json(json!({ "account_id": env.alice.id() }))
+        .await?
+        .json()?;
+    assert_eq!(gas_drops, 1);
+
+    // an account gets one gas drop by default
+    let result = env.swap_in(100, core_swap_msg).await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 390);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_target() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+
+    // alice is not registered in ETH, the input is refunded before the swap
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": env.eth.id()},
+                "check_target": true,
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(balance_of(&env.usdc, env.mos.id()).await?, 1_000_000);
+    assert_eq!(balance_of(&env.usdc, env.ref_exchange.id()).await?, 0);
+
+    // a named account which doesn't exist can't receive NEAR
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_WNEAR_POOL, &env.usdc, 100, &env.wnear)],
+                "target_account": format!("nobody.{}", env.alice.id()),
+                "target_token": {"native": {}},
+                "check_target": true,
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(balance_of(&env.usdc, env.mos.id()).await?, 1_000_000);
+
+    // the swap goes on once alice is registered
+    mint(&env.eth, env.alice.id(), 0).await?;
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": {"token": env.eth.id()},
+                "check_target": true,
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_partial_fill_returns_unused_input_to_mos() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+    mint(&env.eth, env.alice.id(), 0).await?;
+    env.ref_exchange
+        .call("set_pool")
+        .args_json(json!({
+            "pool_id": USDC_ETH_POOL,
+            "pool": {
+                "token_in": env.usdc.id(),
+                "token_out": env.eth.id(),
+                "rate_numerator": "2",
+                "rate_denominator": "1",
+                "max_amount_in": "60",
+            },
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": env.eth.id(),
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+
+    // the unused input goes back to MOS, nothing is delivered to the target account and the
+    // output of the used input is left in the core
+    assert_eq!(balance_of(&env.usdc, env.mos.id()).await?, 1_000_000 - 60);
+    assert_eq!(balance_of(&env.usdc, env.ref_exchange.id()).await?, 60);
+    assert_eq!(balance_of(&env.eth, env.alice.id()).await?, 0);
+    assert_eq!(balance_of(&env.eth, env.core.id()).await?, 120);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_delivery_queued_then_sent_to_lost_and_found() -> anyhow::Result<()> {
+    let env = Env::new().await?;
+
+    // alice is not registered in ETH, the delivery fails and is queued
+    let result = env
+        .swap_in(
+            100,
+            json!({
+                "actions": [swap_action(USDC_ETH_POOL, &env.usdc, 100, &env.eth)],
+                "target_account": env.alice.id(),
+                "target_token": env.eth.id(),
+                "min_total_out": null,
+                "on_failure": null,
+            }),
+        )
+        .await?;
+    assert!(result.is_success(), "{:?}", result.failures());
+
+    let pending: Value = env
+        .core
+        .view("get_pending_delivery")
+        .args_json(json!({ "order_id": "0" }))
+        .await?
+        .json()?;
+    assert_eq!(pending["attempts"], json!(1));
+    assert_eq!(pending["delivery"]["account_id"], json!(env.alice.id()));
+    assert_eq!(pending["delivery"]["amount"], json!("200"));
+    let pending_amount: String = env
+        .core
+        .view("get_pending_amount")
+        .args_json(json!({ "token": env.eth.id() }))
+        .await?
+        .json()?;
+    assert_eq!(pending_amount, "200");
+
+    // the delivery is redirected to MOS lost and found on the next retry
+    env.owner
+        .call(env.core.id(), "set_max_delivery_attempts")
+        .args_json(json!({ "max_delivery_attempts": 1 }))
+        .transact()
+        .await?
+        .into_result()?;
+    env.alice
+        .call(env.core.id(), "retry_delivery")
+        .args_json(json!({ "order_id": "0" }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    let received = env.mos_received().await?;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["token"], json!(env.eth.id()));
+    assert_eq!(received[0]["amount"], json!("200"));
+    let msg: Value = serde_json::from_str(received[0]["msg"].as_str().unwrap())?;
+    assert_eq!(
+        msg,
+        json!({ "account": env.alice.id(), "is_native": false })
+    );
+    let pending: Option<Value> = env
+        .core
+        .view("get_pending_delivery")
+        .args_json(json!({ "order_id": "0" }))
+        .await?
+        .json()?;
+    assert!(pending.is_none());
+    assert_eq!(balance_of(&env.eth, env.core.id()).await?, 0);
+    Ok(())
+}
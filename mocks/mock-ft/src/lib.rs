@@ -0,0 +1,33 @@
+//! Fungible token for the integration tests, anyone can mint.
+
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{near_bindgen, AccountId, PanicOnDefault, PromiseOrValue};
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockFt {
+    token: FungibleToken,
+}
+
+#[near_bindgen]
+impl MockFt {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            token: FungibleToken::new(b"t".to_vec()),
+        }
+    }
+
+    /// Register `account_id` if needed and mint `amount` to it, mint 0 to only register.
+    pub fn mint(&mut self, account_id: AccountId, amount: U128) {
+        if !self.token.accounts.contains_key(&account_id) {
+            self.token.internal_register_account(&account_id);
+        }
+        self.token.internal_deposit(&account_id, amount.0);
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(MockFt, token);
+near_contract_standards::impl_fungible_token_storage!(MockFt, token);
@@ -0,0 +1,60 @@
+//! MOS controller for the integration tests, it accepts and records all the tokens transferred
+//! to it by `ft_transfer_call`, except the amount set by `set_refund` for the next transfer.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::Vector;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault, PromiseOrValue};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Received {
+    pub token: AccountId,
+    pub sender_id: AccountId,
+    pub amount: U128,
+    pub msg: String,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockMos {
+    received: Vector<Received>,
+    refund: U128,
+}
+
+#[near_bindgen]
+impl MockMos {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            received: Vector::new(b"r".to_vec()),
+            refund: U128(0),
+        }
+    }
+
+    pub fn get_received(&self) -> Vec<Received> {
+        self.received.to_vec()
+    }
+
+    /// Refund `refund` of the next transfer.
+    pub fn set_refund(&mut self, refund: U128) {
+        self.refund = refund;
+    }
+
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.received.push(&Received {
+            token: env::predecessor_account_id(),
+            sender_id,
+            amount,
+            msg,
+        });
+        let refund = std::mem::replace(&mut self.refund, U128(0));
+        PromiseOrValue::Value(refund)
+    }
+}
@@ -0,0 +1,237 @@
+//! Ref exchange for the integration tests, it implements the Execute flow of `ft_transfer_call`
+//! and the internal account flow of deposit, `swap` and `withdraw` with fixed rate pools. A pool
+//! can cap its input amount to simulate a partial fill, the unused input is returned to the
+//! sender by the token. As in ref exchange, a withdraw whose transfer fails is credited back to
+//! the internal account.
+
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, LookupSet};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, near_bindgen, serde_json, AccountId, Gas, PanicOnDefault, Promise,
+    PromiseOrValue, PromiseResult,
+};
+
+const FT_TRANSFER_GAS: Gas = Gas(5_000_000_000_000);
+const CALLBACK_RETURN_UNUSED_GAS: Gas = Gas(5_000_000_000_000);
+const CALLBACK_POST_WITHDRAW_GAS: Gas = Gas(5_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Pool {
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    /// Amount out is `amount_in * rate_numerator / rate_denominator`.
+    pub rate_numerator: U128,
+    pub rate_denominator: U128,
+    /// Maximum input amount of the first action using the pool.
+    pub max_amount_in: Option<U128>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct ExecuteMessage {
+    actions: Vec<SwapAction>,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockRef {
+    pools: LookupMap<u64, Pool>,
+    accounts: LookupSet<AccountId>,
+    /// Internal account balances by account and token.
+    deposits: LookupMap<(AccountId, AccountId), U128>,
+}
+
+#[near_bindgen]
+impl MockRef {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            pools: LookupMap::new(b"p".to_vec()),
+            accounts: LookupSet::new(b"a".to_vec()),
+            deposits: LookupMap::new(b"d".to_vec()),
+        }
+    }
+
+    pub fn set_pool(&mut self, pool_id: u64, pool: Pool) {
+        self.pools.insert(&pool_id, &pool);
+    }
+
+    /// Register the internal account of `account_id` or the caller, the deposit is kept.
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) {
+        let _ = registration_only;
+        self.accounts
+            .insert(&account_id.unwrap_or_else(env::predecessor_account_id));
+    }
+
+    #[payable]
+    pub fn register_tokens(&mut self, token_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        assert!(
+            self.accounts.contains(&env::predecessor_account_id()),
+            "ERR_NOT_REGISTERED"
+        );
+        let _ = token_ids;
+    }
+
+    pub fn get_deposit(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        self.deposits
+            .get(&(account_id, token_id))
+            .unwrap_or(U128(0))
+    }
+
+    /// Swap in the internal account of the caller, returns the amount out.
+    #[payable]
+    pub fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128 {
+        assert_one_yocto();
+        let _ = referral_id;
+        let account_id = env::predecessor_account_id();
+        let first_action = actions.first().expect("ERR_NO_ACTIONS");
+        let amount_in = first_action.amount_in.expect("ERR_NO_AMOUNT_IN").0;
+        let token_in = first_action.token_in.clone();
+        self.withdraw_deposit(&account_id, &token_in, amount_in);
+        let (token_out, used_amount, amount_out) = self.do_actions(&token_in, amount_in, &actions);
+        self.deposit(&account_id, &token_in, amount_in - used_amount);
+        self.deposit(&account_id, &token_out, amount_out);
+        U128(amount_out)
+    }
+
+    #[payable]
+    pub fn withdraw(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        unregister: Option<bool>,
+    ) -> Promise {
+        assert_one_yocto();
+        let _ = unregister;
+        let account_id = env::predecessor_account_id();
+        self.withdraw_deposit(&account_id, &token_id, amount.0);
+        ext_ft_core::ext(token_id.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(1)
+            .ft_transfer(account_id.clone(), amount, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_POST_WITHDRAW_GAS)
+                    .callback_post_withdraw(token_id, account_id, amount),
+            )
+    }
+
+    /// Credit the amount back to the internal account if the transfer failed, without failing.
+    #[private]
+    pub fn callback_post_withdraw(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        amount: U128,
+    ) {
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            env::log_str("withdraw failed, deposit it back");
+            self.deposit(&account_id, &token_id, amount.0);
+        }
+    }
+
+    /// Do the swap actions of `msg` and transfer the output to `sender_id`, the output token
+    /// should be deposited to the ref account before.
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token = env::predecessor_account_id();
+        if msg.is_empty() {
+            assert!(self.accounts.contains(&sender_id), "ERR_NOT_REGISTERED");
+            self.deposit(&sender_id, &token, amount.0);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let message = serde_json::from_str::<ExecuteMessage>(&msg).expect("ERR_MSG_WRONG_FORMAT");
+        let (token, used_amount, amount_out) = self.do_actions(&token, amount.0, &message.actions);
+
+        ext_ft_core::ext(token)
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(1)
+            .ft_transfer(sender_id, U128(amount_out), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_RETURN_UNUSED_GAS)
+                    .callback_return_unused(U128(amount.0 - used_amount)),
+            )
+            .into()
+    }
+
+    #[private]
+    pub fn callback_return_unused(&self, unused_amount: U128) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => unused_amount,
+            _ => env::panic_str("ERR_TRANSFER_OUT"),
+        }
+    }
+}
+
+impl MockRef {
+    /// Do the swap actions with the input of `amount` of `token`, returns the output token, the
+    /// used input amount and the output amount.
+    fn do_actions(
+        &self,
+        token: &AccountId,
+        amount: u128,
+        actions: &[SwapAction],
+    ) -> (AccountId, u128, u128) {
+        let mut token = token.clone();
+        let mut used_amount = amount;
+        let mut amount_out = amount;
+        for (i, action) in actions.iter().enumerate() {
+            assert_eq!(action.token_in, token, "ERR_TOKEN_IN");
+            let pool = self.pools.get(&action.pool_id).expect("ERR_NO_POOL");
+            assert_eq!(pool.token_in, action.token_in, "ERR_POOL_TOKEN_IN");
+            assert_eq!(pool.token_out, action.token_out, "ERR_POOL_TOKEN_OUT");
+
+            let mut amount_in = action.amount_in.map(|x| x.0).unwrap_or(amount_out);
+            if i == 0 {
+                if let Some(max_amount_in) = pool.max_amount_in {
+                    amount_in = amount_in.min(max_amount_in.0);
+                }
+                assert!(amount_in <= amount, "ERR_NOT_ENOUGH_DEPOSIT");
+                used_amount = amount_in;
+            }
+            amount_out = amount_in * pool.rate_numerator.0 / pool.rate_denominator.0;
+            assert!(amount_out >= action.min_amount_out.0, "ERR_MIN_AMOUNT");
+            token = action.token_out.clone();
+        }
+        (token, used_amount, amount_out)
+    }
+
+    fn deposit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: u128) {
+        let key = (account_id.clone(), token_id.clone());
+        let balance = self.deposits.get(&key).unwrap_or(U128(0)).0;
+        self.deposits.insert(&key, &U128(balance + amount));
+    }
+
+    fn withdraw_deposit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: u128) {
+        let key = (account_id.clone(), token_id.clone());
+        let balance = self.deposits.get(&key).unwrap_or(U128(0)).0;
+        assert!(balance >= amount, "ERR_NOT_ENOUGH_BALANCE");
+        self.deposits.insert(&key, &U128(balance - amount));
+    }
+}
@@ -0,0 +1,49 @@
+//! Wrapped NEAR for the integration tests, anyone can mint besides depositing NEAR.
+//! The account should hold enough NEAR for the withdrawals of the minted tokens.
+
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{
+    assert_one_yocto, env, near_bindgen, AccountId, PanicOnDefault, Promise, PromiseOrValue,
+};
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockWnear {
+    token: FungibleToken,
+}
+
+#[near_bindgen]
+impl MockWnear {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            token: FungibleToken::new(b"t".to_vec()),
+        }
+    }
+
+    /// Register `account_id` if needed and mint `amount` to it, mint 0 to only register.
+    pub fn mint(&mut self, account_id: AccountId, amount: U128) {
+        if !self.token.accounts.contains_key(&account_id) {
+            self.token.internal_register_account(&account_id);
+        }
+        self.token.internal_deposit(&account_id, amount.0);
+    }
+
+    #[payable]
+    pub fn near_deposit(&mut self) {
+        self.mint(env::predecessor_account_id(), U128(env::attached_deposit()));
+    }
+
+    #[payable]
+    pub fn near_withdraw(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.token.internal_withdraw(&account_id, amount.0);
+        Promise::new(account_id).transfer(amount.0)
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(MockWnear, token);
+near_contract_standards::impl_fungible_token_storage!(MockWnear, token);
@@ -24,4 +24,4 @@ RUSTFLAGS=$RFLAGS cargo build --target wasm32-unknown-unknown --release
 
 
 mkdir $RES_DIR
-cp $RELEASE_DIR/*.wasm $RES_DIR
\ No newline at end of file
+cp $RELEASE_DIR/butter_core.wasm $RELEASE_DIR/butter_core_factory.wasm $RES_DIR
\ No newline at end of file
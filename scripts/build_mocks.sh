@@ -0,0 +1,13 @@
+#!/usr/bin/env bash
+
+# build the mock contracts used by the integration tests
+set -e
+RFLAGS='-C link-arg=-s'
+SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
+
+for MOCK in mock-ft mock-wnear mock-ref mock-mos
+do
+  cd $SCRIPT_DIR/../mocks/$MOCK
+  echo "start to build $MOCK"
+  RUSTFLAGS=$RFLAGS cargo build --target wasm32-unknown-unknown --release
+done
@@ -3,4 +3,5 @@ CONTROLLER=mos2.mfac.map009.testnet    # mos account id
 REF_EXCHANGE=ref-finance-101.testnet   # v2.ref-finance.near for mainnet
 WRAPPED_TOKEN=wrap.testnet             # wrap.near for mainnet
 OWNER=multisig.mfac.map009.testnet     # multisig contract, used for upgrade
-FACTORY_NAME=corefac
\ No newline at end of file
+FACTORY_NAME=corefac
+FACTORY_OWNER=$MASTER_ACCOUNT      # factory owner, who can create cores and manage deployers
\ No newline at end of file
@@ -18,6 +18,7 @@ function printHelp() {
   echo "  $FILE_NAME <command>"
   echo "Commands:"
   echo "  deploy_factory                                    transfer out ft token"
+  echo "  upload_core <version>                             upload butter core code as a new version"
   echo "  deploy_core <core name>                           deposit out ft token"
   echo "  help                                              show help"
 }
@@ -27,7 +28,20 @@ function deploy_factory() {
   near create-account "$FACTORY_ACCOUNT" --masterAccount $MASTER_ACCOUNT --initialBalance 15
 
   echo "deploying butter core factory contract"
-  near deploy --accountId "$FACTORY_ACCOUNT" --wasmFile $RES_DIR/butter_core_factory.wasm
+  near deploy --accountId "$FACTORY_ACCOUNT" --wasmFile $RES_DIR/butter_core_factory.wasm \
+    --initFunction new --initArgs '{"owner": "'$FACTORY_OWNER'"}'
+}
+
+function upload_core() {
+  echo "uploading butter core code version $1"
+  CHUNK_DIR=$(mktemp -d)
+  split -b 60000 $RES_DIR/butter_core.wasm $CHUNK_DIR/chunk_
+  for CHUNK in $CHUNK_DIR/chunk_*; do
+    near call "$FACTORY_ACCOUNT" upload_core_code '{"version": "'$1'", "chunk": "'$(base64 < $CHUNK | tr -d '\n')'"}' --accountId $FACTORY_OWNER --gas 300000000000000
+  done
+  rm -rf $CHUNK_DIR
+
+  near call "$FACTORY_ACCOUNT" add_core_version '{"version": "'$1'"}' --accountId $FACTORY_OWNER --gas 300000000000000
 }
 
 function deploy_core() {
@@ -52,6 +66,15 @@ if [[ $# -gt 0 ]]; then
         exit 1
       fi
       ;;
+    upload_core)
+      if [[ $# == 2 ]]; then
+        shift
+        upload_core $@
+      else
+        printHelp
+        exit 1
+      fi
+      ;;
     deploy_core)
       if [[ $# == 2 ]]; then
         shift